@@ -0,0 +1,76 @@
+//! Which client addresses may query the server.
+
+use ipnet::IpNet;
+use lru::LruCache;
+use serde::{de::Error, Deserialize, Deserializer};
+use std::{net::IpAddr, num::NonZeroUsize, sync::Mutex};
+
+/// Clients whose rejected queries are counted one by one. Anyone on the
+/// internet can add to these counts, so only the most recent are kept.
+const TRACKED_CLIENTS: NonZeroUsize = NonZeroUsize::new(1024).unwrap();
+
+/// Parses an address block such as "192.168.0.0/16". A bare address stands
+/// for itself alone.
+pub(crate) fn parse_net(s: &str) -> Result<IpNet, String> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid address block {:?}", s))
+}
+
+/// Reads address blocks written as `parse_net` takes them.
+pub(crate) fn nets<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<IpNet>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|net| parse_net(net).map_err(D::Error::custom))
+        .collect()
+}
+
+/// Checks `ip` against the blocks, reading IPv4-mapped IPv6 addresses
+/// (::ffff:a.b.c.d) as the IPv4 address they carry.
+pub(crate) fn covers(nets: &[IpNet], ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    nets.iter().any(|net| net.contains(&ip))
+}
+
+pub struct AccessList {
+    allowed: Vec<IpNet>,
+    rejected: Mutex<LruCache<IpAddr, u64>>,
+}
+
+impl AccessList {
+    pub fn new(allowed: Vec<IpNet>) -> Self {
+        Self {
+            allowed,
+            rejected: Mutex::new(LruCache::new(TRACKED_CLIENTS)),
+        }
+    }
+
+    /// Whether `client` may query the server, counting it if not.
+    pub fn check(&self, client: IpAddr) -> bool {
+        if covers(&self.allowed, client) {
+            return true;
+        }
+        let client = client.to_canonical();
+        let mut rejected = self.rejected.lock().unwrap();
+        match rejected.get_mut(&client) {
+            Some(count) => *count += 1,
+            None => {
+                rejected.put(client, 1);
+            }
+        }
+        false
+    }
+
+    /// Lists the recently rejected clients with how often each was turned
+    /// away.
+    pub fn rejected_clients(&self) -> Vec<(IpAddr, u64)> {
+        self.rejected
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(client, count)| (*client, *count))
+            .collect()
+    }
+}
@@ -0,0 +1,446 @@
+//! The command line, which a config file can fill in.
+
+use crate::{acl::parse_net, dns::parse_qtype_arg};
+use clap::{ArgAction, Parser, ValueEnum};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    num::NonZeroU32,
+    path::PathBuf,
+    time::Duration,
+};
+use tracing::Level;
+
+#[derive(Parser)]
+#[clap(author, version, about)]
+pub struct Args {
+    /// TOML file of settings named like the long flags with underscores
+    /// (e.g., cache_size = 1000). Flags given here override it
+    #[clap(long)]
+    pub config: Option<PathBuf>,
+
+    /// Print the settings in effect, defaults included, as a config file and
+    /// exit
+    #[clap(long)]
+    pub dump_config: bool,
+
+    /// Path or http(s) URL of a denylist. Repeat the flag to combine several
+    /// lists
+    #[clap(short, long, default_value = "denylist.txt")]
+    pub(crate) list: Vec<String>,
+
+    /// Path or http(s) URL of an allowlist whose entries override broader
+    /// denylist entries. Repeat the flag to combine several lists
+    #[clap(short, long)]
+    pub(crate) allowlist: Vec<String>,
+
+    /// How to read the denylists: "auto" tells plain domains, hosts-file
+    /// lines and adblock rules apart line by line, while the other formats
+    /// read every line one way, for lists whose lines would be mistaken for
+    /// another format. Allowlists are always told apart line by line
+    #[clap(long, value_enum, default_value_t = ListFormat::Auto)]
+    pub(crate) denylist_format: ListFormat,
+
+    /// File of regular expressions, one per line, blocking the names they
+    /// match for every client. They are only checked when no list entry
+    /// decides a name, but then each one is tried in turn, so a long file
+    /// slows lookups down; prefer list entries where a domain will do.
+    /// Reloaded along with the lists
+    #[clap(long)]
+    pub(crate) regex_denylist: Option<PathBuf>,
+
+    /// Directory to keep copies of downloaded lists in, used when a later
+    /// download fails
+    #[clap(long)]
+    pub(crate) list_cache_dir: Option<PathBuf>,
+
+    /// Skip lists that can't be downloaded and have no cached copy instead
+    /// of failing
+    #[clap(long)]
+    pub(crate) fail_open: bool,
+
+    /// How often to check list URLs for updates (e.g., "24h" or "30m")
+    #[clap(long, value_parser = humantime::parse_duration)]
+    pub(crate) refresh_interval: Option<Duration>,
+
+    /// File of records to answer with instead of asking the upstreams, one
+    /// per line, such as "A nas.home 192.168.1.40". A, AAAA, CNAME and TXT
+    /// records are supported, and "*.home" covers the names below home.
+    /// Reloaded along with the lists
+    #[clap(long)]
+    pub(crate) local_records: Option<PathBuf>,
+
+    /// TTL in seconds of the answers given from --local-records
+    #[clap(long, default_value_t = 300)]
+    pub(crate) local_ttl: u32,
+
+    /// Upstream DNS server: an address (e.g., "1.1.1.1:53"), a DNS-over-TLS
+    /// or DNS-over-QUIC server (e.g., "tls://1.1.1.1:853#one.one.one.one",
+    /// "quic://94.140.14.140:853#dns.adguard-dns.com") or a DNS-over-HTTPS
+    /// URL (e.g., "https://cloudflare-dns.com/dns-query"). Repeat the flag
+    /// or separate servers with commas to use several. Without this flag or
+    /// --upstream-doh, queries go to 1.1.1.1:53
+    #[clap(short, long, alias = "upstream-dns", value_delimiter = ',')]
+    pub(crate) dns: Vec<String>,
+
+    /// URL of a DNS-over-HTTPS upstream server, such as
+    /// "https://dns.quad9.net/dns-query", tried after those of --dns. An
+    /// http:// URL sends queries unencrypted, for a server on the same host
+    /// or behind a TLS proxy. Repeat the flag to use several
+    #[clap(long)]
+    pub(crate) upstream_doh: Vec<String>,
+
+    /// How to choose between several upstream servers
+    #[clap(long, value_enum, default_value_t = UpstreamStrategy::Failover)]
+    pub(crate) upstream_strategy: UpstreamStrategy,
+
+    /// How long to wait for a UDP or DNS-over-QUIC upstream before moving on
+    /// to the next one (e.g., "2s"; a plain number is milliseconds)
+    #[clap(
+        long,
+        alias = "upstream-timeout-ms",
+        value_parser = parse_timeout,
+        default_value = "300ms"
+    )]
+    pub(crate) upstream_timeout: Duration,
+
+    /// How long a TCP, DNS-over-TLS or DNS-over-HTTPS exchange with an
+    /// upstream may take, connecting included (e.g., "5s"; a plain number is
+    /// milliseconds)
+    #[clap(long, value_parser = parse_timeout, default_value = "2s")]
+    pub(crate) upstream_stream_timeout: Duration,
+
+    /// Times to resend an unanswered UDP query within the upstream timeout
+    #[clap(long, default_value_t = 2)]
+    pub(crate) upstream_retries: u32,
+
+    /// Most upstream lookups in flight at once. Queries beyond that wait for
+    /// one to finish, and fail if none does within --upstream-timeout
+    #[clap(long, default_value = "1024")]
+    pub(crate) max_concurrent: NonZeroU32,
+
+    /// Never ask the upstreams: answer from the lists and the cache only,
+    /// and everything else with --no-forward-rcode. For isolated networks
+    /// and for trying out the lists on their own
+    #[clap(long)]
+    pub(crate) no_forward: bool,
+
+    /// How to answer the queries --no-forward keeps from the upstreams
+    #[clap(long, value_enum, default_value_t = NoForwardRcode::Servfail)]
+    pub(crate) no_forward_rcode: NoForwardRcode,
+
+    /// Address to listen on for UDP and TCP queries (e.g., "127.0.0.1:5353").
+    /// IPv6 addresses only accept IPv6 clients, so repeat the flag with
+    /// "0.0.0.0:53" and "[::]:53" to serve both
+    #[clap(long, default_value = "0.0.0.0:53")]
+    pub(crate) listen: Vec<String>,
+
+    /// Address to listen on for DNS-over-TLS queries (e.g., "0.0.0.0:853")
+    #[clap(long)]
+    pub(crate) listen_tls: Option<String>,
+
+    /// Address to listen on for DNS-over-HTTPS queries (e.g., "0.0.0.0:443")
+    #[clap(long)]
+    pub(crate) listen_doh: Option<String>,
+
+    /// Address to serve Prometheus metrics on at /metrics (e.g.,
+    /// "127.0.0.1:9090")
+    #[clap(long, alias = "metrics-listen")]
+    pub(crate) metrics_addr: Option<String>,
+
+    /// PEM certificate chain for the DNS-over-TLS and DNS-over-HTTPS
+    /// listeners
+    #[clap(long)]
+    pub(crate) tls_cert: Option<String>,
+
+    /// PEM private key for the DNS-over-TLS and DNS-over-HTTPS listeners
+    #[clap(long)]
+    pub(crate) tls_key: Option<String>,
+
+    /// How to answer queries for blocked domains
+    #[clap(long, value_enum, default_value_t = BlockMode::Nxdomain)]
+    pub(crate) block_mode: BlockMode,
+
+    /// Address given for blocked domains' A records in zeroip mode, such as
+    /// a local web server that logs what was blocked
+    #[clap(long, default_value = "0.0.0.0")]
+    pub(crate) sinkhole_ip: Ipv4Addr,
+
+    /// Address given for blocked domains' AAAA records in zeroip mode
+    #[clap(long, default_value = "::")]
+    pub(crate) sinkhole_ip6: Ipv6Addr,
+
+    /// TTL in seconds of the addresses given for blocked domains
+    #[clap(long, default_value_t = 300)]
+    pub(crate) block_ttl: u32,
+
+    /// Log queries the lists would block but answer them normally, to try
+    /// out a list before enforcing it
+    #[clap(long)]
+    pub(crate) dry_run: bool,
+
+    /// Also block answers whose CNAMEs lead to a listed domain, which
+    /// catches trackers hidden behind a first-party name. Costs parsing
+    /// every answer
+    #[clap(long)]
+    pub(crate) block_cnames: bool,
+
+    /// Tell EDNS clients why a query was blocked, with an Extended DNS Error
+    /// (RFC 8914) of code 17, "Filtered", and the list entry that matched
+    #[clap(long)]
+    pub(crate) extended_errors: bool,
+
+    /// Record type to answer with no records for every name, such as AAAA
+    /// on networks with broken IPv6 or HTTPS. Repeat the flag or separate
+    /// types with commas to give several
+    #[clap(long, value_delimiter = ',', value_parser = parse_qtype_arg)]
+    pub(crate) block_qtype: Vec<u16>,
+
+    /// How to answer queries of type ANY, which are mostly sent to abuse
+    /// resolvers for amplification
+    #[clap(long, value_enum, default_value = "hinfo")]
+    pub(crate) any_answer: AnyAnswer,
+
+    /// Address block allowed to query the server, such as "192.168.0.0/16".
+    /// Repeat the flag or separate blocks with commas to give several; the
+    /// default covers private, loopback and link-local addresses. Give
+    /// "0.0.0.0/0" and "::/0" to serve everyone
+    #[clap(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_net,
+        default_values = [
+            "127.0.0.0/8",
+            "10.0.0.0/8",
+            "172.16.0.0/12",
+            "192.168.0.0/16",
+            "169.254.0.0/16",
+            "::1/128",
+            "fc00::/7",
+            "fe80::/10",
+        ]
+    )]
+    pub(crate) allow_from: Vec<IpNet>,
+
+    /// Answer UDP queries from addresses not allowed with REFUSED rather
+    /// than dropping them. TCP, TLS and HTTPS connections from them are
+    /// closed either way
+    #[clap(long)]
+    pub(crate) refuse_unauthorized: bool,
+
+    /// Queries per second to answer from each client IP (e.g., "200" or
+    /// "200/s"); queries beyond it are refused
+    #[clap(long, alias = "client-rate-limit", value_parser = parse_rate)]
+    pub(crate) rate_limit: Option<u32>,
+
+    /// Queries a client may send at once before the rate limit applies
+    /// [default: the rate limit]
+    #[clap(long)]
+    pub(crate) rate_limit_burst: Option<u32>,
+
+    /// Drop queries over the rate limit without answering, rather than
+    /// answering them with REFUSED
+    #[clap(long)]
+    pub(crate) rate_limit_drop: bool,
+
+    /// Client address exempt from the rate limit, such as a monitoring host.
+    /// Repeat the flag or separate addresses with commas to give several
+    #[clap(long, value_delimiter = ',')]
+    pub(crate) rate_limit_exempt: Vec<IpAddr>,
+
+    /// Maximum number of upstream responses to cache (0 disables caching)
+    #[clap(long, default_value_t = 4096)]
+    pub(crate) cache_size: usize,
+
+    /// Longest time to cache answers saying a name or record type doesn't
+    /// exist (e.g., "5m"), however long their SOA record allows
+    #[clap(
+        long,
+        value_parser = humantime::parse_duration,
+        default_value = "1h"
+    )]
+    pub(crate) max_negative_ttl: Duration,
+
+    /// How long to keep expired cache entries to answer with when no
+    /// upstream can be reached (e.g., "1h"; "0s" disables this)
+    #[clap(
+        long,
+        value_parser = humantime::parse_duration,
+        default_value = "1h"
+    )]
+    pub(crate) stale_window: Duration,
+
+    /// Skip certificate verification for DNS-over-TLS, DNS-over-HTTPS and
+    /// DNS-over-QUIC upstreams
+    #[clap(long)]
+    pub(crate) tls_insecure: bool,
+
+    /// Let plain list entries match subdomains too, as if every entry were
+    /// written as ".example.com". Set to false to have them match only the
+    /// name itself, leaving subdomains to ".example.com" and "*.example.com"
+    /// entries. Single-label entries such as "zip" always cover their
+    /// subdomains
+    #[clap(
+        long,
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        default_value_t = true,
+        default_missing_value = "true"
+    )]
+    pub(crate) implicit_wildcards: bool,
+
+    /// Domain whose names and subdomains are always forwarded, whatever the
+    /// lists say, so that an entry like "arpa" can't break reverse lookups.
+    /// Repeat the flag or separate domains with commas to give several
+    #[clap(
+        long,
+        value_delimiter = ',',
+        default_values = ["in-addr.arpa", "ip6.arpa", "localhost"]
+    )]
+    pub(crate) protected_suffix: Vec<String>,
+
+    /// Reload the denylist and allowlist whenever their files change
+    #[clap(long)]
+    pub(crate) watch: bool,
+
+    /// Name answered with a TXT record saying "ok" and the uptime, for
+    /// monitoring to check that queries get answered
+    #[clap(long, default_value = "health.dnsfilter.local")]
+    pub(crate) health_domain: String,
+
+    /// Most verbose level to log: error, warn, info, debug or trace
+    #[clap(long, default_value = "info")]
+    pub log_level: Level,
+
+    /// Append a line for every query to this file, reopening it on SIGHUP or
+    /// SIGUSR2 so it can be rotated
+    #[clap(long)]
+    pub(crate) query_log: Option<PathBuf>,
+
+    /// How often to log a summary of the queries answered (e.g., "5m"), and
+    /// a final one on shutdown. 0 turns the summaries off
+    #[clap(
+        long,
+        value_parser = humantime::parse_duration,
+        default_value = "0"
+    )]
+    pub(crate) stats_interval: Duration,
+
+    /// Send dnstap messages for every query, answer and upstream exchange to
+    /// the Frame Streams collector listening on this unix socket
+    #[clap(long)]
+    pub(crate) dnstap_socket: Option<PathBuf>,
+
+    /// Accept commands on this unix socket, such as "block example.com" or
+    /// "check example.com", to change and inspect the running server
+    #[clap(long)]
+    pub(crate) control_socket: Option<PathBuf>,
+
+    /// Keep the rules added over the control socket in this file, so they
+    /// survive restarts
+    #[clap(long)]
+    pub(crate) persist_runtime_rules: Option<PathBuf>,
+
+    /// TOML file of policies giving the clients in some address ranges
+    /// their own lists in place of --list and --allowlist
+    #[clap(long)]
+    pub(crate) client_policy: Option<PathBuf>,
+}
+
+/// How the lines of a list are written.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ListFormat {
+    /// Tell each line's format from how it looks
+    Auto,
+    /// Domains, each optionally followed by the record types it is limited
+    /// to and the addresses to answer with, and `/regex/` rules
+    Domains,
+    /// Hosts-file lines: an address followed by one or more host names
+    Hosts,
+    /// Adblock rules such as "||example.com^", of which only the ones
+    /// naming a whole domain are followed
+    Abp,
+}
+
+/// How queries are spread across the upstream servers.
+#[derive(Clone, Copy, PartialEq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum UpstreamStrategy {
+    /// Try the servers in the order given, moving on when one fails
+    Failover,
+    /// Start each query at the next server in turn
+    RoundRobin,
+    /// Prefer the server that has been answering quickest, now and then
+    /// trying the others in case they got faster
+    Fastest,
+    /// Ask the quickest server first and, if it hasn't answered within
+    /// 30ms, the next quickest too, relaying whichever answers first. Costs
+    /// extra upstream queries
+    Race,
+}
+
+/// Response code for a query that would have gone upstream.
+#[derive(Clone, Copy, PartialEq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum NoForwardRcode {
+    /// Report a server failure
+    Servfail,
+    /// Claim the domain does not exist
+    Nxdomain,
+    /// Refuse to answer
+    Refused,
+}
+
+/// How to answer a query for a blocked domain.
+#[derive(Clone, Copy, PartialEq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum BlockMode {
+    /// Claim the domain does not exist
+    Nxdomain,
+    /// Claim the domain has no records of the type asked for
+    Nodata,
+    /// Answer A and AAAA queries with 0.0.0.0 and ::, or the sinkhole
+    /// addresses if set
+    #[value(alias = "null-ip")]
+    #[serde(alias = "null-ip")]
+    Zeroip,
+    /// Refuse to answer
+    Refused,
+}
+
+/// How to answer a query of type ANY.
+#[derive(Clone, Copy, PartialEq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum AnyAnswer {
+    /// Give a single HINFO record instead of everything (RFC 8482)
+    Hinfo,
+    /// Reply that ANY queries aren't implemented
+    Notimp,
+    /// Forward them like any other query
+    Forward,
+}
+
+/// Parses a timeout given as a duration such as "2s" or as a plain number of
+/// milliseconds. Zero would fail every query, so it is rejected.
+pub(crate) fn parse_timeout(arg: &str) -> Result<Duration, String> {
+    let timeout = match arg.parse() {
+        Ok(ms) => Duration::from_millis(ms),
+        Err(_) => humantime::parse_duration(arg).map_err(|e| e.to_string())?,
+    };
+    if timeout.is_zero() {
+        return Err("the timeout must be longer than zero".to_owned());
+    }
+    Ok(timeout)
+}
+
+/// Parses a query rate, given as a number of queries per second with or
+/// without a "/s" suffix.
+fn parse_rate(arg: &str) -> Result<u32, String> {
+    let qps = arg.strip_suffix("/s").unwrap_or(arg);
+    match qps.parse() {
+        Ok(qps) if qps > 0 => Ok(qps),
+        _ => Err(format!("invalid rate {:?}, expected e.g. \"200/s\"", arg)),
+    }
+}
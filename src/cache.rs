@@ -0,0 +1,435 @@
+//! Cache of upstream responses, keyed by lowercased name, query type and
+//! class, and the EDNS flags of the query, since a response with DNSSEC
+//! records or an OPT record can't be replayed to a client that didn't ask
+//! for them.
+
+use crate::dns::{
+    skip_name, skip_record, EdnsFlags, RCODE_NXDOMAIN, TYPE_OPT, TYPE_SOA,
+};
+use lru::LruCache;
+use std::{
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub type CacheKey = (String, u16, u16, EdnsFlags);
+
+struct Entry {
+    response: Vec<u8>,
+    stored: Instant,
+    expires: Instant,
+}
+
+impl Entry {
+    /// The response with its TTLs lowered by the time it has been cached,
+    /// so clients don't keep it past when the upstream said it expires.
+    fn aged_response(&self, now: Instant) -> Vec<u8> {
+        let age = now.saturating_duration_since(self.stored).as_secs();
+        let age = u32::try_from(age).unwrap_or(u32::MAX);
+        let mut response = self.response.clone();
+        set_ttls(&mut response, |ttl| ttl.saturating_sub(age));
+        response
+    }
+}
+
+/// TTL given to records in stale answers, as RFC 8767 suggests, so clients
+/// ask again soon rather than holding on to old data.
+const STALE_TTL: u32 = 30;
+
+pub struct Cache {
+    entries: Mutex<LruCache<CacheKey, Entry>>,
+    /// Cap on how long NXDOMAIN and NODATA answers are kept, in seconds.
+    max_negative_ttl: u32,
+    /// How long expired entries are kept for use when the upstreams fail.
+    stale_window: Duration,
+}
+
+impl Cache {
+    pub fn new(
+        capacity: NonZeroUsize,
+        max_negative_ttl: Duration,
+        stale_window: Duration,
+    ) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            max_negative_ttl: max_negative_ttl
+                .as_secs()
+                .try_into()
+                .unwrap_or(u32::MAX),
+            stale_window,
+        }
+    }
+
+    /// Returns the cached response for `key` if it hasn't expired, with its
+    /// TTLs counting down from when it was stored. Expired entries are kept
+    /// for `get_stale` until the stale window ends.
+    pub fn get(&self, key: &CacheKey) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(key)?;
+        let now = Instant::now();
+        if entry.expires > now {
+            return Some(entry.aged_response(now));
+        }
+        if entry.expires + self.stale_window <= now {
+            entries.pop(key);
+        }
+        None
+    }
+
+    /// Returns an expired response for `key` that is still within the stale
+    /// window, with its TTLs lowered to `STALE_TTL` (RFC 8767). Only meant
+    /// for when the upstreams can't be reached.
+    pub fn get_stale(&self, key: &CacheKey) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(key)?;
+        if entry.expires + self.stale_window <= Instant::now() {
+            entries.pop(key);
+            return None;
+        }
+        let mut response = entry.response.clone();
+        set_ttls(&mut response, |_| STALE_TTL)?;
+        Some(response)
+    }
+
+    /// Stores a response for as long as its shortest-lived answer record,
+    /// or for a negative answer, as long as its SOA record allows up to the
+    /// configured cap. Responses with a zero TTL are not cached.
+    pub fn insert(&self, key: CacheKey, response: &[u8]) {
+        let ttl = min_answer_ttl(response).or_else(|| {
+            negative_ttl(response).map(|ttl| ttl.min(self.max_negative_ttl))
+        });
+        let Some(ttl) = ttl.filter(|&ttl| ttl > 0) else {
+            return;
+        };
+        let now = Instant::now();
+        let entry = Entry {
+            response: response.to_vec(),
+            stored: now,
+            expires: now + Duration::from_secs(ttl.into()),
+        };
+        self.entries.lock().unwrap().put(key, entry);
+    }
+
+    /// Forgets every response, returning how many there were.
+    pub fn clear(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.len();
+        entries.clear();
+        count
+    }
+}
+
+/// Returns the smallest TTL in the answer section of a complete NOERROR
+/// response, or `None` if there is nothing worth caching.
+pub fn min_answer_ttl(response: &[u8]) -> Option<u32> {
+    if response.len() < 12 || response[2] & 0x02 != 0 || response[3] & 0x0F != 0
+    {
+        return None;
+    }
+    let qdcount = u16::from_be_bytes([response[4], response[5]]);
+    let ancount = u16::from_be_bytes([response[6], response[7]]);
+
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = skip_name(response, pos).ok()? + 4;
+    }
+
+    let mut min_ttl: Option<u32> = None;
+    for _ in 0..ancount {
+        pos = skip_name(response, pos).ok()?;
+        let record = response.get(pos..pos + 10)?;
+        let ttl =
+            u32::from_be_bytes([record[4], record[5], record[6], record[7]]);
+        let rdlength = u16::from_be_bytes([record[8], record[9]]) as usize;
+        pos += 10 + rdlength;
+        if pos > response.len() {
+            return None;
+        }
+        min_ttl = Some(min_ttl.map_or(ttl, |min| min.min(ttl)));
+    }
+    min_ttl
+}
+
+/// Rewrites the TTL of every record in `response` to what `ttl` makes of
+/// it, leaving the OPT record alone since its TTL field holds EDNS flags
+/// instead.
+fn set_ttls(response: &mut [u8], ttl: impl Fn(u32) -> u32) -> Option<()> {
+    let count = |i: usize| u16::from_be_bytes([response[i], response[i + 1]]);
+    if response.len() < 12 {
+        return None;
+    }
+    let qdcount = count(4);
+    let records = count(6) as usize + count(8) as usize + count(10) as usize;
+
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = skip_name(response, pos).ok()? + 4;
+    }
+    for _ in 0..records {
+        let fields = skip_name(response, pos).ok()?;
+        pos = skip_record(response, pos).ok()?;
+        if u16::from_be_bytes([response[fields], response[fields + 1]])
+            != TYPE_OPT
+        {
+            let field = &mut response[fields + 4..fields + 8];
+            let old =
+                u32::from_be_bytes([field[0], field[1], field[2], field[3]]);
+            field.copy_from_slice(&ttl(old).to_be_bytes());
+        }
+    }
+    Some(())
+}
+
+/// Returns how long an NXDOMAIN or NODATA response may be cached: the
+/// smaller of the SOA record's TTL and its MINIMUM field (RFC 2308 section
+/// 5). Without an SOA in the authority section the answer isn't cacheable.
+pub fn negative_ttl(response: &[u8]) -> Option<u32> {
+    if response.len() < 12 || response[2] & 0x02 != 0 {
+        return None;
+    }
+    let rcode = response[3] & 0x0F;
+    let qdcount = u16::from_be_bytes([response[4], response[5]]);
+    let ancount = u16::from_be_bytes([response[6], response[7]]);
+    let nscount = u16::from_be_bytes([response[8], response[9]]);
+    let nodata = rcode == 0 && ancount == 0;
+    if rcode != RCODE_NXDOMAIN && !nodata {
+        return None;
+    }
+
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = skip_name(response, pos).ok()? + 4;
+    }
+    for _ in 0..ancount {
+        pos = skip_record(response, pos).ok()?;
+    }
+    for _ in 0..nscount {
+        let fields = skip_name(response, pos).ok()?;
+        let end = skip_record(response, pos).ok()?;
+        let record = &response[fields..end];
+        let rtype = u16::from_be_bytes([record[0], record[1]]);
+        // The MINIMUM field ends the SOA data, after two names and four
+        // other 32-bit fields.
+        if rtype == TYPE_SOA && record.len() >= 10 + 22 {
+            let ttl = u32::from_be_bytes([
+                record[4], record[5], record[6], record[7],
+            ]);
+            let minimum = &record[record.len() - 4..];
+            let minimum = u32::from_be_bytes([
+                minimum[0], minimum[1], minimum[2], minimum[3],
+            ]);
+            return Some(ttl.min(minimum));
+        }
+        pos = end;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{
+        create_answer_response, encode_name, negative_soa, Record, CLASS_IN,
+        RCODE_SERVFAIL, TYPE_A,
+    };
+
+    fn cache() -> Cache {
+        Cache::new(
+            NonZeroUsize::new(16).unwrap(),
+            Duration::from_secs(300),
+            Duration::from_secs(3600),
+        )
+    }
+
+    fn key(flags: EdnsFlags) -> CacheKey {
+        ("example.com".to_owned(), TYPE_A, CLASS_IN, flags)
+    }
+
+    /// A response for example.com with `answers` and `authority`, and an
+    /// OPT record with the DO bit set if `edns`.
+    fn response(
+        rcode: u8,
+        answers: &[Record],
+        authority: &[Record],
+        edns: bool,
+    ) -> Vec<u8> {
+        let mut request = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        request.extend_from_slice(&encode_name("example.com"));
+        request.extend_from_slice(&TYPE_A.to_be_bytes());
+        request.extend_from_slice(&CLASS_IN.to_be_bytes());
+        if edns {
+            request[11] = 1;
+            request.extend_from_slice(&[0, 0, 41, 0x04, 0xD0, 0, 0, 0x80, 0]);
+            request.extend_from_slice(&[0, 0]);
+        }
+        create_answer_response(&request, rcode, answers, authority).unwrap()
+    }
+
+    fn a_record(ttl: u32) -> Record {
+        Record {
+            owner: None,
+            rtype: TYPE_A,
+            class: CLASS_IN,
+            ttl,
+            data: vec![10, 0, 0, 1],
+        }
+    }
+
+    /// How long the cached entry for `key` has left.
+    fn time_left(cache: &Cache, key: &CacheKey) -> Option<Duration> {
+        let mut entries = cache.entries.lock().unwrap();
+        let entry = entries.get(key)?;
+        Some(entry.expires.saturating_duration_since(Instant::now()))
+    }
+
+    /// Makes the entry for `key` look as if it was stored `by` earlier.
+    fn age(cache: &Cache, key: &CacheKey, by: Duration) {
+        let mut entries = cache.entries.lock().unwrap();
+        let entry = entries.get_mut(key).unwrap();
+        entry.stored -= by;
+        entry.expires -= by;
+    }
+
+    /// The TTL of each record in a message with one question.
+    fn ttls(message: &[u8]) -> Vec<u32> {
+        let records = [6, 8, 10]
+            .iter()
+            .map(|&i| u16::from_be_bytes([message[i], message[i + 1]]))
+            .sum::<u16>();
+        let mut pos = skip_name(message, 12).unwrap() + 4;
+        (0..records)
+            .map(|_| {
+                let fields = skip_name(message, pos).unwrap();
+                pos = skip_record(message, pos).unwrap();
+                let ttl = &message[fields + 4..fields + 8];
+                u32::from_be_bytes([ttl[0], ttl[1], ttl[2], ttl[3]])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keeps_answers_for_their_shortest_ttl() {
+        let cache = cache();
+        let key = key(EdnsFlags::default());
+        let response = response(0, &[a_record(300), a_record(60)], &[], false);
+        assert_eq!(min_answer_ttl(&response), Some(60));
+        cache.insert(key.clone(), &response);
+        assert_eq!(cache.get(&key), Some(response));
+        let left = time_left(&cache, &key).unwrap();
+        assert!(left > Duration::from_secs(58));
+        assert!(left <= Duration::from_secs(60));
+
+        age(&cache, &key, Duration::from_secs(60));
+        assert_eq!(cache.get(&key), None);
+    }
+
+    #[test]
+    fn counts_ttls_down_while_cached() {
+        let cache = cache();
+        let key = key(EdnsFlags {
+            edns: true,
+            dnssec_ok: true,
+            checking_disabled: false,
+        });
+        let response = response(0, &[a_record(300), a_record(60)], &[], true);
+        cache.insert(key.clone(), &response);
+
+        age(&cache, &key, Duration::from_secs(10));
+        let aged = cache.get(&key).unwrap();
+        // The OPT record's TTL field holds the DO bit and stays as it was.
+        assert_eq!(ttls(&aged), [290, 50, 0x8000]);
+        assert_eq!(aged.len(), response.len());
+
+        age(&cache, &key, Duration::from_millis(49_500));
+        assert_eq!(ttls(&cache.get(&key).unwrap()), [241, 1, 0x8000]);
+    }
+
+    #[test]
+    fn skips_uncacheable_responses() {
+        let cache = cache();
+        let key = key(EdnsFlags::default());
+        let mut truncated = response(0, &[a_record(60)], &[], false);
+        truncated[2] |= 0x02;
+        for response in [
+            response(0, &[a_record(0)], &[], false),
+            response(RCODE_SERVFAIL, &[], &[], false),
+            // Negative answers need an SOA to say how long they hold.
+            response(RCODE_NXDOMAIN, &[], &[], false),
+            truncated,
+        ] {
+            cache.insert(key.clone(), &response);
+            assert_eq!(cache.get(&key), None);
+        }
+    }
+
+    #[test]
+    fn answers_are_kept_apart_by_edns_flags() {
+        let cache = cache();
+        let plain = key(EdnsFlags::default());
+        let dnssec = key(EdnsFlags {
+            edns: true,
+            dnssec_ok: true,
+            checking_disabled: false,
+        });
+        cache.insert(dnssec.clone(), &response(0, &[a_record(60)], &[], true));
+        assert_eq!(cache.get(&plain), None);
+        assert!(cache.get(&dnssec).is_some());
+        assert_eq!(cache.clear(), 1);
+        assert_eq!(cache.get(&dnssec), None);
+    }
+
+    #[test]
+    fn keeps_negative_answers_as_the_soa_says_up_to_the_cap() {
+        let cache = cache();
+        let key = key(EdnsFlags::default());
+        let nodata = response(0, &[], &[negative_soa(CLASS_IN, 60)], false);
+        assert_eq!(negative_ttl(&nodata), Some(60));
+        cache.insert(key.clone(), &nodata);
+        assert!(time_left(&cache, &key).unwrap() <= Duration::from_secs(60));
+
+        let nxdomain = response(
+            RCODE_NXDOMAIN,
+            &[],
+            &[negative_soa(CLASS_IN, 86400)],
+            false,
+        );
+        cache.insert(key.clone(), &nxdomain);
+        assert!(cache.get(&key).is_some());
+        let left = time_left(&cache, &key).unwrap();
+        assert!(left > Duration::from_secs(290));
+        assert!(left <= Duration::from_secs(300));
+    }
+
+    #[test]
+    fn negative_ttl_is_the_lesser_of_soa_ttl_and_minimum() {
+        let mut soa = negative_soa(CLASS_IN, 30);
+        soa.ttl = 600;
+        let response = response(RCODE_NXDOMAIN, &[], &[soa], false);
+        assert_eq!(negative_ttl(&response), Some(30));
+    }
+
+    #[test]
+    fn serves_expired_answers_only_as_stale() {
+        let cache = cache();
+        let key = key(EdnsFlags {
+            edns: true,
+            dnssec_ok: true,
+            checking_disabled: false,
+        });
+        let response = response(0, &[a_record(600)], &[], true);
+        cache.insert(key.clone(), &response);
+        assert_eq!(cache.get(&key).as_ref(), Some(&response));
+
+        age(&cache, &key, Duration::from_secs(660));
+        assert_eq!(cache.get(&key), None);
+        let stale = cache.get_stale(&key).unwrap();
+        // The OPT record's TTL field holds the DO bit and stays as it was.
+        assert_eq!(ttls(&stale), [STALE_TTL, 0x8000]);
+        assert_eq!(stale.len(), response.len());
+
+        age(&cache, &key, Duration::from_secs(3600));
+        assert_eq!(cache.get_stale(&key), None);
+        assert_eq!(cache.clear(), 0);
+    }
+}
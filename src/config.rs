@@ -0,0 +1,500 @@
+//! Settings read from the TOML file given with --config. Keys are the long
+//! flag names with underscores, as in `cache_size = 1000`, and flags given on
+//! the command line win over the file.
+
+use crate::{
+    acl::nets,
+    args::{
+        parse_timeout, AnyAnswer, Args, BlockMode, ListFormat, NoForwardRcode,
+        UpstreamStrategy,
+    },
+    dns::parse_qtype_arg,
+    filter::ListSources,
+    querylog::qtype_name,
+};
+use clap::{parser::ValueSource, ArgMatches, FromArgMatches};
+use ipnet::IpNet;
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+use tracing::{warn, Level};
+
+/// Invokes the macro named with the name of every setting the file holds.
+macro_rules! with_settings {
+    ($macro:ident) => {
+        $macro!(
+            list,
+            allowlist,
+            denylist_format,
+            regex_denylist,
+            list_cache_dir,
+            fail_open,
+            refresh_interval,
+            local_records,
+            local_ttl,
+            dns,
+            upstream_doh,
+            upstream_strategy,
+            upstream_timeout,
+            upstream_stream_timeout,
+            upstream_retries,
+            max_concurrent,
+            no_forward,
+            no_forward_rcode,
+            listen,
+            listen_tls,
+            listen_doh,
+            metrics_addr,
+            tls_cert,
+            tls_key,
+            block_mode,
+            sinkhole_ip,
+            sinkhole_ip6,
+            block_ttl,
+            dry_run,
+            block_cnames,
+            extended_errors,
+            block_qtype,
+            any_answer,
+            allow_from,
+            refuse_unauthorized,
+            rate_limit,
+            rate_limit_burst,
+            rate_limit_drop,
+            rate_limit_exempt,
+            cache_size,
+            max_negative_ttl,
+            stale_window,
+            tls_insecure,
+            implicit_wildcards,
+            protected_suffix,
+            watch,
+            health_domain,
+            log_level,
+            query_log,
+            stats_interval,
+            dnstap_socket,
+            control_socket,
+            persist_runtime_rules,
+            client_policy,
+        )
+    };
+}
+
+/// Settings that rereading the file applies; changes to the rest need a
+/// restart.
+const RELOADABLE: &[&str] = &["list", "allowlist"];
+
+#[derive(Default, Deserialize, Serialize)]
+pub struct Config {
+    list: Option<Vec<String>>,
+    allowlist: Option<Vec<String>>,
+    denylist_format: Option<ListFormat>,
+    regex_denylist: Option<PathBuf>,
+    list_cache_dir: Option<PathBuf>,
+    fail_open: Option<bool>,
+    #[serde(
+        default,
+        deserialize_with = "duration",
+        serialize_with = "format_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    refresh_interval: Option<Duration>,
+    local_records: Option<PathBuf>,
+    local_ttl: Option<u32>,
+    dns: Option<Vec<String>>,
+    upstream_doh: Option<Vec<String>>,
+    upstream_strategy: Option<UpstreamStrategy>,
+    #[serde(
+        default,
+        alias = "upstream_timeout_ms",
+        deserialize_with = "timeout",
+        serialize_with = "format_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    upstream_timeout: Option<Duration>,
+    #[serde(
+        default,
+        deserialize_with = "timeout",
+        serialize_with = "format_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    upstream_stream_timeout: Option<Duration>,
+    upstream_retries: Option<u32>,
+    max_concurrent: Option<NonZeroU32>,
+    no_forward: Option<bool>,
+    no_forward_rcode: Option<NoForwardRcode>,
+    listen: Option<Vec<String>>,
+    listen_tls: Option<String>,
+    listen_doh: Option<String>,
+    metrics_addr: Option<String>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    block_mode: Option<BlockMode>,
+    sinkhole_ip: Option<Ipv4Addr>,
+    sinkhole_ip6: Option<Ipv6Addr>,
+    block_ttl: Option<u32>,
+    dry_run: Option<bool>,
+    block_cnames: Option<bool>,
+    extended_errors: Option<bool>,
+    #[serde(
+        default,
+        deserialize_with = "qtypes",
+        serialize_with = "format_qtypes",
+        skip_serializing_if = "Option::is_none"
+    )]
+    block_qtype: Option<Vec<u16>>,
+    any_answer: Option<AnyAnswer>,
+    #[serde(
+        default,
+        deserialize_with = "optional_nets",
+        serialize_with = "format_nets",
+        skip_serializing_if = "Option::is_none"
+    )]
+    allow_from: Option<Vec<IpNet>>,
+    refuse_unauthorized: Option<bool>,
+    rate_limit: Option<u32>,
+    rate_limit_burst: Option<u32>,
+    rate_limit_drop: Option<bool>,
+    rate_limit_exempt: Option<Vec<IpAddr>>,
+    cache_size: Option<usize>,
+    #[serde(
+        default,
+        deserialize_with = "duration",
+        serialize_with = "format_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    max_negative_ttl: Option<Duration>,
+    #[serde(
+        default,
+        deserialize_with = "duration",
+        serialize_with = "format_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    stale_window: Option<Duration>,
+    tls_insecure: Option<bool>,
+    implicit_wildcards: Option<bool>,
+    protected_suffix: Option<Vec<String>>,
+    watch: Option<bool>,
+    health_domain: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "level",
+        serialize_with = "format_level",
+        skip_serializing_if = "Option::is_none"
+    )]
+    log_level: Option<Level>,
+    query_log: Option<PathBuf>,
+    #[serde(
+        default,
+        deserialize_with = "duration",
+        serialize_with = "format_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    stats_interval: Option<Duration>,
+    dnstap_socket: Option<PathBuf>,
+    control_socket: Option<PathBuf>,
+    persist_runtime_rules: Option<PathBuf>,
+    client_policy: Option<PathBuf>,
+    /// Keys that aren't settings, most likely misspelt ones.
+    #[serde(flatten, skip_serializing)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        toml::from_str(&text)
+            .map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+
+    /// The settings in effect, defaults included.
+    pub fn from_args(args: &Args) -> Self {
+        let mut config = Self::default();
+        macro_rules! set {
+            ($($field:ident),* $(,)?) => {$(
+                config.$field = args.$field.clone().into();
+            )*};
+        }
+        with_settings!(set);
+        config
+    }
+
+    /// Warns about the keys that aren't settings, which are ignored.
+    pub fn warn_unknown(&self) {
+        for key in self.unknown.keys() {
+            warn!(key, "ignoring unknown setting in config");
+        }
+    }
+
+    /// Fills in every setting that wasn't given on the command line.
+    pub fn apply(&self, args: &mut Args, matches: &ArgMatches) {
+        let on_command_line = |id: &str| {
+            matches.value_source(id) == Some(ValueSource::CommandLine)
+        };
+        macro_rules! apply {
+            ($($field:ident),* $(,)?) => {$(
+                if let Some(value) = &self.$field {
+                    if !on_command_line(stringify!($field)) {
+                        args.$field = value.clone().into();
+                    }
+                }
+            )*};
+        }
+        with_settings!(apply);
+    }
+}
+
+/// Rereads the config file on SIGHUP, keeping what was on the command line.
+pub struct Reread {
+    path: PathBuf,
+    matches: ArgMatches,
+    /// The settings in effect, to tell what a reread changed.
+    effective: Mutex<toml::Table>,
+}
+
+impl Reread {
+    pub fn new(path: PathBuf, matches: ArgMatches, args: &Args) -> Self {
+        Self {
+            path,
+            matches,
+            effective: Mutex::new(table(&Config::from_args(args))),
+        }
+    }
+
+    /// Rereads the file and returns the lists it now gives. Other settings
+    /// that changed only take effect on restart, which is logged. Returns
+    /// nothing if the file can't be read, so the current settings stay.
+    pub(crate) fn reread(&self) -> Option<ListSources> {
+        let config = match Config::load(&self.path) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!(error = %e, "failed to reread config, keeping settings");
+                return None;
+            }
+        };
+        config.warn_unknown();
+        let mut args = Args::from_arg_matches(&self.matches).ok()?;
+        config.apply(&mut args, &self.matches);
+        let new = table(&Config::from_args(&args));
+        let mut effective = self.effective.lock().unwrap();
+        let keys: BTreeSet<String> =
+            effective.keys().chain(new.keys()).cloned().collect();
+        for key in keys {
+            if effective.get(&key) == new.get(&key) {
+                continue;
+            }
+            if RELOADABLE.contains(&key.as_str()) {
+                match new.get(&key) {
+                    Some(value) => effective.insert(key, value.clone()),
+                    None => effective.remove(&key),
+                };
+            } else {
+                warn!(key, "setting changed, restart to apply it");
+            }
+        }
+        Some(ListSources {
+            denylists: args.list,
+            allowlists: args.allowlist,
+        })
+    }
+}
+
+fn table(config: &Config) -> toml::Table {
+    toml::Table::try_from(config).unwrap_or_default()
+}
+
+/// Reads durations written like the flags take them, such as "30m".
+fn duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    humantime::parse_duration(&text)
+        .map(Some)
+        .map_err(D::Error::custom)
+}
+
+/// Reads timeouts like the flags take them, as durations or as numbers of
+/// milliseconds.
+fn timeout<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Timeout {
+        Millis(u64),
+        Text(String),
+    }
+    let text = match Timeout::deserialize(deserializer)? {
+        Timeout::Millis(ms) => ms.to_string(),
+        Timeout::Text(text) => text,
+    };
+    parse_timeout(&text).map(Some).map_err(D::Error::custom)
+}
+
+fn format_duration<S: Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match duration {
+        Some(duration) => {
+            serializer.collect_str(&humantime::format_duration(*duration))
+        }
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Reads record types by name, such as "AAAA", or as "TYPE65".
+fn qtypes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<u16>>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|qtype| parse_qtype_arg(qtype).map_err(D::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn format_qtypes<S: Serializer>(
+    qtypes: &Option<Vec<u16>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match qtypes {
+        Some(qtypes) => serializer
+            .collect_seq(qtypes.iter().map(|&qtype| qtype_name(qtype))),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn optional_nets<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<IpNet>>, D::Error> {
+    nets(deserializer).map(Some)
+}
+
+fn format_nets<S: Serializer>(
+    nets: &Option<Vec<IpNet>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match nets {
+        Some(nets) => serializer.collect_seq(nets.iter().map(IpNet::to_string)),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn level<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Level>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    text.parse().map(Some).map_err(D::Error::custom)
+}
+
+fn format_level<S: Serializer>(
+    level: &Option<Level>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match level {
+        Some(level) => serializer.serialize_str(&level.as_str().to_lowercase()),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    const SAMPLE: &str = r#"
+        list = ["ads.txt", "https://example.com/list.txt"]
+        allowlist = ["allow.txt"]
+        dns = ["9.9.9.9:53"]
+        upstream_strategy = "round-robin"
+        upstream_timeout = 500
+        block_mode = "null-ip"
+        block_qtype = ["AAAA", "TYPE65"]
+        allow_from = ["192.168.0.0/16"]
+        cache_size = 1000
+        stale_window = "30m"
+        log_level = "debug"
+    "#;
+
+    /// The settings `flags` and then `file` give.
+    fn settings(file: &str, flags: &[&str]) -> Args {
+        let config: Config = toml::from_str(file).unwrap();
+        let matches =
+            Args::command().get_matches_from(["dnsfilter"].iter().chain(flags));
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        config.apply(&mut args, &matches);
+        args
+    }
+
+    #[test]
+    fn reads_every_kind_of_setting() {
+        let args = settings(SAMPLE, &[]);
+        assert_eq!(args.list, ["ads.txt", "https://example.com/list.txt"]);
+        assert_eq!(args.allowlist, ["allow.txt"]);
+        assert_eq!(args.dns, ["9.9.9.9:53"]);
+        assert!(args.upstream_strategy == UpstreamStrategy::RoundRobin);
+        assert_eq!(args.upstream_timeout, Duration::from_millis(500));
+        assert!(args.block_mode == BlockMode::Zeroip);
+        assert_eq!(args.block_qtype, [28, 65]);
+        assert_eq!(args.allow_from.len(), 1);
+        assert_eq!(args.cache_size, 1000);
+        assert_eq!(args.stale_window, Duration::from_secs(1800));
+        assert_eq!(args.log_level, Level::DEBUG);
+    }
+
+    #[test]
+    fn flags_win_over_the_file() {
+        let flags = ["--cache-size", "5", "--list", "flag.txt"];
+        let args = settings(SAMPLE, &flags);
+        assert_eq!(args.cache_size, 5);
+        assert_eq!(args.list, ["flag.txt"]);
+        assert_eq!(args.dns, ["9.9.9.9:53"]);
+    }
+
+    /// Collects what is logged, to check the warnings.
+    #[derive(Clone, Default)]
+    struct Logged(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Logged {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn warns_about_unknown_keys() {
+        let config: Config =
+            toml::from_str("cache_sise = 1000\ncache_size = 10").unwrap();
+        let logged = Logged::default();
+        let writer = logged.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            config.warn_unknown();
+        });
+        let logged = String::from_utf8(logged.0.lock().unwrap().clone());
+        let logged = logged.unwrap();
+        assert!(logged.contains("ignoring unknown setting"));
+        assert!(logged.contains("cache_sise"));
+        assert!(!logged.contains("cache_size"));
+    }
+}
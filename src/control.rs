@@ -0,0 +1,410 @@
+//! A unix socket for managing the running server, one command per line:
+//!
+//! ```text
+//! block <rule>           add a denylist rule, written as in a list
+//! allow <rule>           add an allowlist rule
+//! remove <rule>          take back a rule added with block or allow
+//! check <domain> [type] [client]
+//!                        tell whether a query would be blocked, and why
+//! reload                 reread the lists
+//! stats                  show the query counters
+//! flush-cache            forget every cached answer
+//! ```
+//!
+//! Each command gets one line back, starting with "ok" or "error". Anyone
+//! who can open the socket can run them, so it is only accessible to the
+//! user running the server.
+
+use crate::{
+    args::ListFormat,
+    dns::{parse_qtype_arg, TYPE_A},
+    filter::{parse_list, Blocklists, ListConfig, ListEntries, Verdict},
+    server::{accept, reload_lists, Service},
+};
+use std::{
+    fmt::Write as _,
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc, Mutex},
+};
+#[cfg(unix)]
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+#[cfg(unix)]
+use tracing::debug;
+
+/// Longest command accepted, newline included. Longer lines end the
+/// connection.
+#[cfg(unix)]
+const MAX_LINE: usize = 1024;
+
+/// Names `parse_list` gives the runtime rules in warnings.
+const SOURCE: &str = "runtime rules";
+
+/// Rules added while the server runs, kept apart from the lists so that
+/// reloading those leaves them in place. They are also saved to a file, if
+/// given one, so they outlive a restart.
+pub struct RuntimeRules {
+    rules: Mutex<Vec<Rule>>,
+    persist: Option<PathBuf>,
+    implicit_wildcards: bool,
+}
+
+#[derive(Clone, PartialEq)]
+struct Rule {
+    allow: bool,
+    /// The rule as written in a list, such as ".example.com".
+    text: String,
+}
+
+impl RuntimeRules {
+    /// Starts with the rules saved in `persist`, if it exists yet.
+    pub fn load(
+        persist: Option<PathBuf>,
+        implicit_wildcards: bool,
+    ) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        if let Some(path) = &persist {
+            let text = match std::fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+                Err(e) => {
+                    return Err(format!(
+                        "Failed to read {}: {}",
+                        path.display(),
+                        e
+                    ))
+                }
+            };
+            for (number, line) in text.lines().enumerate() {
+                let rule = match line.split_once(' ') {
+                    Some(("block", text)) => Rule {
+                        allow: false,
+                        text: text.to_owned(),
+                    },
+                    Some(("allow", text)) => Rule {
+                        allow: true,
+                        text: text.to_owned(),
+                    },
+                    _ => {
+                        return Err(format!(
+                            "Invalid rule on line {} of {}",
+                            number + 1,
+                            path.display()
+                        ))
+                    }
+                };
+                rules.push(rule);
+            }
+        }
+        Ok(Self {
+            rules: Mutex::new(rules),
+            persist,
+            implicit_wildcards,
+        })
+    }
+
+    /// Builds the lists the rules make up.
+    pub fn lists(&self) -> io::Result<Blocklists> {
+        self.build(&self.rules.lock().unwrap())
+    }
+
+    /// Adds a rule, returning the lists with it. Adding a rule twice has no
+    /// further effect.
+    fn add(&self, allow: bool, text: &str) -> Result<Blocklists, String> {
+        let entries = parse_list(
+            text.as_bytes(),
+            SOURCE,
+            self.implicit_wildcards,
+            ListFormat::Auto,
+        )
+        .map_err(|e| e.to_string())?;
+        if entries.is_empty()
+            || entries.stats.malformed > 0
+            || entries.stats.unsupported > 0
+        {
+            return Err(format!("invalid rule {:?}", text));
+        }
+        let rule = Rule {
+            allow,
+            text: text.to_owned(),
+        };
+        self.change(|rules| {
+            if !rules.contains(&rule) {
+                rules.push(rule);
+            }
+            true
+        })
+    }
+
+    /// Removes a rule in either list, returning the lists without it.
+    fn remove(&self, text: &str) -> Result<Blocklists, String> {
+        self.change(|rules| {
+            let before = rules.len();
+            rules.retain(|rule| rule.text != text);
+            rules.len() < before
+        })
+        .map_err(|_| format!("no rule {:?} was added", text))
+    }
+
+    /// Applies `edit` and saves the result. The rules stay as they were if
+    /// `edit` returns false or saving fails.
+    fn change(
+        &self,
+        edit: impl FnOnce(&mut Vec<Rule>) -> bool,
+    ) -> Result<Blocklists, String> {
+        let mut rules = self.rules.lock().unwrap();
+        let mut changed = rules.clone();
+        if !edit(&mut changed) {
+            return Err("nothing to change".to_owned());
+        }
+        let lists = self.build(&changed).map_err(|e| e.to_string())?;
+        self.save(&changed)
+            .map_err(|e| format!("failed to save the rules: {}", e))?;
+        *rules = changed;
+        Ok(lists)
+    }
+
+    fn build(&self, rules: &[Rule]) -> io::Result<Blocklists> {
+        let parse = |allow: bool| {
+            let text = rules
+                .iter()
+                .filter(|rule| rule.allow == allow)
+                .map(|rule| rule.text.as_str())
+                .collect::<Vec<_>>()
+                .join("\n");
+            parse_list(
+                text.as_bytes(),
+                SOURCE,
+                self.implicit_wildcards,
+                ListFormat::Auto,
+            )
+        };
+        let (denied, allowed): (ListEntries, ListEntries) =
+            (parse(false)?, parse(true)?);
+        Blocklists::build(denied, allowed)
+    }
+
+    /// Writes the rules to a new file renamed over the old one, so a crash
+    /// midway leaves the previous rules intact.
+    fn save(&self, rules: &[Rule]) -> io::Result<()> {
+        let Some(path) = &self.persist else {
+            return Ok(());
+        };
+        let mut text = String::new();
+        for rule in rules {
+            let command = if rule.allow { "allow" } else { "block" };
+            let _ = writeln!(text, "{} {}", command, rule.text);
+        }
+        let mut partial = path.clone().into_os_string();
+        partial.push(".tmp");
+        std::fs::write(&partial, text)?;
+        std::fs::rename(&partial, path)
+    }
+}
+
+/// Opens the socket, replacing one left behind by an earlier run, and
+/// limits it to the current user. The socket is bound inside a directory
+/// only the user can enter and moved into place once restricted, so it is
+/// never reachable by others, not even between binding and restricting it.
+#[cfg(unix)]
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            std::fs::remove_file(path)?;
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let mut private = path.as_os_str().to_owned();
+    private.push(format!(".{}.tmp", std::process::id()));
+    let private = PathBuf::from(private);
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = private.join("socket");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(
+            &staged,
+            std::fs::Permissions::from_mode(0o600),
+        )?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&staged);
+    std::fs::remove_dir(&private)?;
+    bound
+}
+
+#[cfg(unix)]
+pub async fn serve_control(
+    listener: UnixListener,
+    rules: Arc<RuntimeRules>,
+    config: Arc<ListConfig>,
+    service: Arc<Service>,
+) {
+    loop {
+        let (stream, _) = accept(|| listener.accept()).await;
+        let rules = Arc::clone(&rules);
+        let config = Arc::clone(&config);
+        let service = Arc::clone(&service);
+        tokio::spawn(async move {
+            if let Err(e) =
+                handle_connection(stream, &rules, &config, &service).await
+            {
+                debug!(error = %e, "control connection failed");
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+async fn handle_connection(
+    stream: UnixStream,
+    rules: &RuntimeRules,
+    config: &Arc<ListConfig>,
+    service: &Service,
+) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = (&mut reader)
+            .take(MAX_LINE as u64)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if read == 0 {
+            return Ok(());
+        }
+        if !line.ends_with(b"\n") && line.len() == MAX_LINE {
+            writer.write_all(b"error line too long\n").await?;
+            return Ok(());
+        }
+        let reply = match std::str::from_utf8(&line) {
+            Ok(command) => run(command.trim(), rules, config, service).await,
+            Err(_) => Err("commands must be UTF-8".to_owned()),
+        };
+        let reply = match reply {
+            Ok(text) if text.is_empty() => "ok\n".to_owned(),
+            Ok(text) => format!("ok {}\n", text),
+            Err(e) => format!("error {}\n", e),
+        };
+        writer.write_all(reply.as_bytes()).await?;
+    }
+}
+
+async fn run(
+    command: &str,
+    rules: &RuntimeRules,
+    config: &Arc<ListConfig>,
+    service: &Service,
+) -> Result<String, String> {
+    let (verb, argument) = match command.split_once(char::is_whitespace) {
+        Some((verb, argument)) => (verb, argument.trim()),
+        None => (command, ""),
+    };
+    match verb {
+        "block" | "allow" => {
+            let lists = rules.add(verb == "allow", argument)?;
+            service.runtime.store(Arc::new(lists));
+            Ok(String::new())
+        }
+        "remove" => {
+            let lists = rules.remove(argument)?;
+            service.runtime.store(Arc::new(lists));
+            Ok(String::new())
+        }
+        "check" => check(argument, service),
+        "reload" => {
+            reload_lists(config, service).await;
+            Ok(String::new())
+        }
+        "stats" => Ok(stats(service)),
+        "flush-cache" => {
+            let flushed =
+                service.cache.as_ref().map_or(0, |cache| cache.clear());
+            Ok(format!("removed {} answers", flushed))
+        }
+        "" => Err("empty command".to_owned()),
+        _ => Err(format!("unknown command {:?}", verb)),
+    }
+}
+
+/// Tells how the lists decide a query, for record type A unless another is
+/// given, and from a client no policy covers unless one is given.
+fn check(argument: &str, service: &Service) -> Result<String, String> {
+    let mut words = argument.split_whitespace();
+    let Some(domain) = words.next() else {
+        return Err("expected a domain to check".to_owned());
+    };
+    let qtype = match words.next() {
+        Some(qtype) => parse_qtype_arg(qtype)?,
+        None => TYPE_A,
+    };
+    let lists = match words.next() {
+        Some(client) => {
+            let client: IpAddr = client
+                .parse()
+                .map_err(|_| format!("invalid client address {:?}", client))?;
+            service.lists_for(client)
+        }
+        None => &service.lists,
+    };
+    let domain = domain.to_lowercase();
+    let domain = domain.strip_suffix('.').unwrap_or(&domain);
+    if service.local.load().contains(domain) {
+        return Ok("answered from the local records".to_owned());
+    }
+    if service.is_protected(domain) {
+        return Ok("protected, always forwarded".to_owned());
+    }
+    let (lists, runtime) = (lists.load(), service.runtime.load());
+    Ok(match lists.decide(&runtime, domain, qtype) {
+        Verdict::Allowed(reason) => format!("allowed by {}", reason),
+        Verdict::Blocked(reason, _) => format!("blocked by {}", reason),
+        Verdict::Unlisted => "not listed".to_owned(),
+    })
+}
+
+fn stats(service: &Service) -> String {
+    let metrics = &service.metrics;
+    let lists = service.lists.load();
+    let runtime = service.runtime.load();
+    let counters = [
+        ("queries", &metrics.queries),
+        ("blocked", &metrics.blocked),
+        ("forwarded", &metrics.forwarded),
+        ("cache_hits", &metrics.cache_hits),
+        ("cache_misses", &metrics.cache_misses),
+        ("upstream_retries", &metrics.upstream_retries),
+        (
+            "upstream_retry_successes",
+            &metrics.upstream_retry_successes,
+        ),
+    ];
+    let mut out = String::new();
+    for (name, value) in counters {
+        let _ = write!(out, "{}={} ", name, value.load(Ordering::Relaxed));
+    }
+    let _ = write!(
+        out,
+        "denylist={} allowlist={} runtime_denylist={} runtime_allowlist={} \
+         in_flight={}",
+        lists.denylist.len(),
+        lists.allowlist.len(),
+        runtime.denylist.len(),
+        runtime.allowlist.len(),
+        *service.in_flight.borrow()
+    );
+    out
+}
@@ -0,0 +1,962 @@
+//! Reading DNS messages and putting together the responses sent back.
+
+use thiserror::Error;
+
+/// Why a DNS message couldn't be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum Error {
+    #[error("Invalid DNS request")]
+    TooShort,
+    #[error("Invalid question in DNS request")]
+    InvalidQuestion,
+    #[error("Invalid domain name in DNS request")]
+    InvalidName,
+    #[error("Invalid compression pointer in DNS request")]
+    InvalidPointer,
+    #[error("Too many compression pointers in DNS request")]
+    TooManyPointers,
+    /// Also what a label longer than 63 octets reads as.
+    #[error("Invalid label type in DNS request")]
+    InvalidLabelType,
+    #[error("Domain name longer than 255 octets in DNS request")]
+    NameTooLong,
+    #[error("Invalid UTF-8 in domain name")]
+    InvalidUtf8,
+    #[error("Missing QTYPE or QCLASS in DNS request")]
+    MissingQtype,
+    #[error("Truncated resource record")]
+    TruncatedRecord,
+}
+
+/// Media type of wire-format DNS messages carried over HTTPS (RFC 8484).
+pub(crate) const DNS_MESSAGE: &str = "application/dns-message";
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_CNAME: u16 = 5;
+/// Type of the SOA record carried by negative answers.
+pub(crate) const TYPE_SOA: u16 = 6;
+pub(crate) const TYPE_HINFO: u16 = 13;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub(crate) const TYPE_OPT: u16 = 41;
+pub const TYPE_ANY: u16 = 255;
+pub const CLASS_IN: u16 = 1;
+pub const CLASS_ANY: u16 = 255;
+pub const RCODE_FORMERR: u8 = 1;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_NOTIMP: u8 = 4;
+pub const RCODE_REFUSED: u8 = 5;
+/// EDNS option code of an Extended DNS Error (RFC 8914 2).
+const OPTION_EDE: u16 = 15;
+/// Extended DNS Error for answers a filter changed (RFC 8914 4.18).
+pub const EDE_FILTERED: u16 = 17;
+
+/// Record types a list entry can be limited to, as in "example.com AAAA".
+/// Others can be given as "TYPE65" and so on (RFC 3597).
+pub(crate) const QTYPE_NAMES: &[(&str, u16)] = &[
+    ("a", TYPE_A),
+    ("ns", 2),
+    ("cname", TYPE_CNAME),
+    ("soa", TYPE_SOA),
+    ("ptr", 12),
+    ("hinfo", TYPE_HINFO),
+    ("mx", 15),
+    ("txt", TYPE_TXT),
+    ("aaaa", TYPE_AAAA),
+    ("srv", 33),
+    ("svcb", 64),
+    ("https", 65),
+    ("any", TYPE_ANY),
+    ("caa", 257),
+];
+
+/// Compression pointers followed before a name is rejected as a loop.
+const MAX_POINTER_JUMPS: usize = 16;
+
+/// Longest name in wire format, length octets included (RFC 1035 2.3.4).
+const MAX_NAME_LENGTH: usize = 255;
+
+/// What a client can receive over UDP without EDNS0 (RFC 1035 4.2.1).
+const MIN_UDP_PAYLOAD: usize = 512;
+
+/// Largest datagram read from clients or the upstream, and the cap on the
+/// EDNS0 payload size a client may advertise.
+pub(crate) const MAX_UDP_PAYLOAD: usize = 4096;
+
+/// Parses a record type given on the command line, in any case.
+pub fn parse_qtype_arg(arg: &str) -> Result<u16, String> {
+    parse_qtype(&arg.to_ascii_lowercase())
+        .ok_or_else(|| format!("unknown record type {:?}", arg))
+}
+
+/// Reads a record type qualifier such as "aaaa" or "type65".
+pub(crate) fn parse_qtype(token: &str) -> Option<u16> {
+    if let Some((_, qtype)) =
+        QTYPE_NAMES.iter().find(|(name, _)| *name == token)
+    {
+        return Some(*qtype);
+    }
+    token.strip_prefix("type")?.parse().ok()
+}
+
+/// Gives a cached response the transaction ID and question name of the
+/// request it answers. The name may differ in case, and clients using 0x20
+/// encoding expect to get back exactly the case they sent.
+pub fn match_request(response: &mut [u8], request: &[u8]) {
+    response[0..2].copy_from_slice(&request[0..2]);
+    let Ok(end) = skip_name(request, 12) else {
+        return;
+    };
+    if end <= response.len()
+        && response[12..end].eq_ignore_ascii_case(&request[12..end])
+    {
+        response[12..end].copy_from_slice(&request[12..end]);
+    }
+}
+
+/// Builds a response with `rcode` that echoes the request's header and
+/// question and has no records, ready for any to be appended.
+pub fn create_question_response(
+    request: &[u8],
+    rcode: u8,
+) -> Result<Vec<u8>, Error> {
+    if request.len() < 12 {
+        return Err(Error::TooShort);
+    }
+    // Echo only the header and question; anything after it in the request
+    // (e.g. an OPT record) would be garbage once ARCOUNT is zeroed.
+    let question_end = skip_name(request, 12)? + 4;
+    if question_end > request.len() {
+        return Err(Error::InvalidQuestion);
+    }
+    let mut response = request[..question_end].to_vec();
+    set_response_flags(&mut response, rcode);
+    response[4..6].copy_from_slice(&1u16.to_be_bytes());
+    response[6..12].fill(0);
+    Ok(response)
+}
+
+/// Answers a query that can't be handled with just an error code. Only the
+/// header is echoed, since the question section may be what was wrong.
+pub fn create_error_response(request: &[u8], rcode: u8) -> Vec<u8> {
+    let mut response = request[..12].to_vec();
+    set_response_flags(&mut response, rcode);
+    response[4..12].fill(0);
+    response
+}
+
+/// Turns the flags of a copied query header into those of a response made
+/// up here: QR and RA set, the opcode and the RD and CD bits kept, and AA,
+/// TC, AD and Z cleared, since the answer is neither authoritative nor
+/// validated.
+fn set_response_flags(header: &mut [u8], rcode: u8) {
+    header[2] = 0x80 | (header[2] & 0x79);
+    header[3] = 0x80 | (header[3] & 0x10) | rcode;
+}
+
+/// An SOA record for the authority section of a made-up negative answer.
+/// Resolvers cache the answer for the lesser of its TTL and MINIMUM field
+/// (RFC 2308 5), which are both `ttl`. Its MNAME and RNAME are both
+/// blocked.dnsfilter., so the answer doesn't pass itself off as coming from
+/// the zone's own servers.
+pub fn negative_soa(class: u16, ttl: u32) -> Record {
+    let name = encode_name("blocked.dnsfilter.");
+    let mut data = [name.as_slice(), &name].concat();
+    // SERIAL, REFRESH, RETRY, EXPIRE and MINIMUM.
+    for value in [1, 3600, 600, 86400, ttl] {
+        data.extend_from_slice(&value.to_be_bytes());
+    }
+    Record {
+        owner: None,
+        rtype: TYPE_SOA,
+        class,
+        ttl,
+        data,
+    }
+}
+
+/// A resource record made up by the filter rather than an upstream.
+pub struct Record {
+    /// The name owning the record, if not the one in the question.
+    pub owner: Option<String>,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: Vec<u8>,
+}
+
+/// Writes a name in wire format, uncompressed. The name must be valid.
+pub fn encode_name(name: &str) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(name.len() + 2);
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        encoded.push(label.len() as u8);
+        encoded.extend_from_slice(label.as_bytes());
+    }
+    encoded.push(0);
+    encoded
+}
+
+/// Builds a response with `rcode` that echoes the request's question and
+/// holds `answers` and, in the authority section, `authority`.
+pub fn create_answer_response(
+    request: &[u8],
+    rcode: u8,
+    answers: &[Record],
+    authority: &[Record],
+) -> Result<Vec<u8>, Error> {
+    let mut response = create_question_response(request, rcode)?;
+
+    response[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+    response[8..10].copy_from_slice(&(authority.len() as u16).to_be_bytes());
+    for record in answers.iter().chain(authority) {
+        match &record.owner {
+            Some(owner) => response.extend_from_slice(&encode_name(owner)),
+            None => response.extend_from_slice(&[0xC0, 0x0C]),
+        }
+        response.extend_from_slice(&record.rtype.to_be_bytes());
+        response.extend_from_slice(&record.class.to_be_bytes());
+        response.extend_from_slice(&record.ttl.to_be_bytes());
+        response.extend_from_slice(&(record.data.len() as u16).to_be_bytes());
+        response.extend_from_slice(&record.data);
+    }
+
+    if let Ok(Some(opt)) = find_opt(request) {
+        // EDNS queries get an OPT record back (RFC 6891 7), with the DO bit
+        // copied from the query (RFC 3225 3).
+        let dnssec_ok = request[opt + 6] & 0x80;
+        response[11] = 1;
+        response.push(0);
+        response.extend_from_slice(&TYPE_OPT.to_be_bytes());
+        response.extend_from_slice(&(MAX_UDP_PAYLOAD as u16).to_be_bytes());
+        response.extend_from_slice(&[0, 0, dnssec_ok, 0]);
+        response.extend_from_slice(&[0, 0]);
+    }
+    Ok(response)
+}
+
+/// Adds an Extended DNS Error (RFC 8914) with `code` and `text` to a
+/// response from `create_answer_response`. Only responses to EDNS queries
+/// have the OPT record to carry it, so others are left as they are.
+pub fn add_extended_error(response: &mut Vec<u8>, code: u16, text: &str) {
+    let Ok(Some(opt)) = find_opt(response) else {
+        return;
+    };
+    // The OPT record comes last, so its data can grow in place.
+    let length = opt + 8;
+    let data = u16::from_be_bytes([response[length], response[length + 1]]);
+    if length + 2 + usize::from(data) != response.len() {
+        return;
+    }
+    let option = 2 + text.len() as u16;
+    response.extend_from_slice(&OPTION_EDE.to_be_bytes());
+    response.extend_from_slice(&option.to_be_bytes());
+    response.extend_from_slice(&code.to_be_bytes());
+    response.extend_from_slice(text.as_bytes());
+    let data = data + 4 + option;
+    response[length..length + 2].copy_from_slice(&data.to_be_bytes());
+}
+
+/// Returns how large a UDP response the client accepts: the payload size in
+/// its OPT record (RFC 6891 6.2.5), or the classic 512 bytes without one.
+pub fn udp_payload_size(request: &[u8]) -> usize {
+    match find_opt(request) {
+        Ok(Some(opt)) => {
+            let size = u16::from_be_bytes([request[opt + 2], request[opt + 3]]);
+            usize::from(size).clamp(MIN_UDP_PAYLOAD, MAX_UDP_PAYLOAD)
+        }
+        _ => MIN_UDP_PAYLOAD,
+    }
+}
+
+/// The parts of a query besides its question that change what upstreams
+/// answer: whether it has an OPT record, its DO bit asking for DNSSEC records
+/// (RFC 3225) and the CD bit turning off validation (RFC 4035 3.2.2).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EdnsFlags {
+    pub edns: bool,
+    pub dnssec_ok: bool,
+    pub checking_disabled: bool,
+}
+
+impl EdnsFlags {
+    pub fn of(request: &[u8]) -> Self {
+        let opt = find_opt(request).ok().flatten();
+        Self {
+            edns: opt.is_some(),
+            // DO is the top bit of the flags in the OPT record's TTL field.
+            dnssec_ok: opt.is_some_and(|opt| request[opt + 6] & 0x80 != 0),
+            checking_disabled: request.len() > 3 && request[3] & 0x10 != 0,
+        }
+    }
+}
+
+/// Finds the OPT record in a message's additional section and returns the
+/// offset of its fixed fields, just past the (root) owner name.
+fn find_opt(message: &[u8]) -> Result<Option<usize>, Error> {
+    if message.len() < 12 {
+        return Err(Error::TooShort);
+    }
+    let count = |i: usize| u16::from_be_bytes([message[i], message[i + 1]]);
+    let mut pos = 12;
+    for _ in 0..count(4) {
+        pos = skip_name(message, pos)? + 4;
+    }
+    for _ in 0..usize::from(count(6)) + usize::from(count(8)) {
+        pos = skip_record(message, pos)?;
+    }
+    for _ in 0..count(10) {
+        let fields = skip_name(message, pos)?;
+        pos = skip_record(message, pos)?;
+        if u16::from_be_bytes([message[fields], message[fields + 1]])
+            == TYPE_OPT
+        {
+            return Ok(Some(fields));
+        }
+    }
+    Ok(None)
+}
+
+/// Cuts a response that doesn't fit in `limit` bytes down to its header and
+/// question with TC set, so the client knows to retry over TCP.
+pub fn truncate_response(response: Vec<u8>, limit: usize) -> Vec<u8> {
+    if response.len() <= limit || response.len() < 12 {
+        return response;
+    }
+    let qdcount = u16::from_be_bytes([response[4], response[5]]);
+    let mut end = 12;
+    for _ in 0..qdcount {
+        match skip_name(&response, end) {
+            Ok(pos) if pos + 4 <= response.len() => end = pos + 4,
+            _ => {
+                end = 12;
+                break;
+            }
+        }
+    }
+    let mut truncated = response[..end].to_vec();
+    if end == 12 {
+        truncated[4..6].copy_from_slice(&[0, 0]);
+    }
+    truncated[2] |= 0x02;
+    truncated[6..12].copy_from_slice(&[0; 6]);
+    truncated
+}
+
+/// The question section of a DNS query.
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+pub fn parse_dns_question(request: &[u8]) -> Result<Question, Error> {
+    if request.len() < 12 {
+        return Err(Error::TooShort);
+    }
+    let (domain, pos) = read_name(request, 12)?;
+    if pos + 4 > request.len() {
+        return Err(Error::MissingQtype);
+    }
+
+    Ok(Question {
+        name: domain,
+        qtype: u16::from_be_bytes([request[pos], request[pos + 1]]),
+        qclass: u16::from_be_bytes([request[pos + 2], request[pos + 3]]),
+    })
+}
+
+/// Reads the name starting at `pos`, lowercased, and returns it with the
+/// offset just past it.
+fn read_name(message: &[u8], mut pos: usize) -> Result<(String, usize), Error> {
+    let mut jumps = 0;
+    // Where the message continues once the name has been read, which is
+    // right after the first compression pointer if the name uses one.
+    let mut name_end = None;
+    let mut domain = String::new();
+    // Octets of the name in wire format, counting the root label.
+    let mut length = 1;
+
+    loop {
+        if pos >= message.len() {
+            return Err(Error::InvalidName);
+        }
+        if message[pos] == 0 {
+            break;
+        }
+        if message[pos] & 0xC0 == 0xC0 {
+            if pos + 1 >= message.len() {
+                return Err(Error::InvalidPointer);
+            }
+            jumps += 1;
+            if jumps > MAX_POINTER_JUMPS {
+                return Err(Error::TooManyPointers);
+            }
+            name_end.get_or_insert(pos + 2);
+            let target = (u16::from_be_bytes([message[pos], message[pos + 1]])
+                & 0x3FFF) as usize;
+            // Pointers may only refer to earlier data, which also rules out
+            // a pointer to itself.
+            if target >= pos {
+                return Err(Error::InvalidPointer);
+            }
+            pos = target;
+            continue;
+        }
+        // 0x40 and 0x80 prefix the obsolete extended label types.
+        if message[pos] & 0xC0 != 0 {
+            return Err(Error::InvalidLabelType);
+        }
+
+        let len = message[pos] as usize;
+        pos += 1;
+        length += 1 + len;
+        if length > MAX_NAME_LENGTH {
+            return Err(Error::NameTooLong);
+        }
+
+        if pos + len > message.len() {
+            return Err(Error::InvalidName);
+        }
+
+        domain.push_str(
+            std::str::from_utf8(&message[pos..pos + len])
+                .map_err(|_| Error::InvalidUtf8)?,
+        );
+        domain.push('.');
+        pos += len;
+    }
+
+    if domain.ends_with('.') {
+        domain.pop();
+    }
+    if domain.is_empty() {
+        // The root, which no list entry can name, is written as in zone
+        // files so it doesn't show up as an empty name in logs.
+        domain.push('.');
+    }
+    // Lists are lowercase, while clients may randomize the case of the name
+    // (0x20 encoding). Responses copy the name from the request, so the
+    // client still gets back the case it sent.
+    domain.make_ascii_lowercase();
+
+    Ok((domain, name_end.unwrap_or(pos + 1)))
+}
+
+/// Follows the CNAMEs in a response's answer section from `name`, returning
+/// the names they lead to in order.
+pub fn cname_chain(response: &[u8], name: &str) -> Result<Vec<String>, Error> {
+    if response.len() < 12 {
+        return Err(Error::TooShort);
+    }
+    let count = |i: usize| u16::from_be_bytes([response[i], response[i + 1]]);
+    let mut pos = 12;
+    for _ in 0..count(4) {
+        pos = skip_name(response, pos)? + 4;
+    }
+    let mut aliases = Vec::new();
+    for _ in 0..count(6) {
+        let (owner, fields) = read_name(response, pos)?;
+        let end = skip_record(response, pos)?;
+        let rtype =
+            u16::from_be_bytes([response[fields], response[fields + 1]]);
+        if rtype == TYPE_CNAME {
+            aliases.push((owner, read_name(response, fields + 10)?.0));
+        }
+        pos = end;
+    }
+    let mut chain = Vec::new();
+    let mut current = name;
+    // Each alias is used once, so a loop ends the chain.
+    while let Some(i) = aliases.iter().position(|(owner, _)| owner == current) {
+        chain.push(aliases.swap_remove(i).1);
+        current = chain.last().unwrap();
+    }
+    Ok(chain)
+}
+
+/// Returns the offset just past the name starting at `pos`.
+pub(crate) fn skip_name(
+    message: &[u8],
+    mut pos: usize,
+) -> Result<usize, Error> {
+    while pos < message.len() {
+        match message[pos] {
+            0 => return Ok(pos + 1),
+            len if len & 0xC0 == 0xC0 => return Ok(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+    Err(Error::InvalidName)
+}
+
+/// Returns the offset just past the resource record starting at `pos`.
+pub(crate) fn skip_record(message: &[u8], pos: usize) -> Result<usize, Error> {
+    let pos = skip_name(message, pos)?;
+    let fields = message.get(pos..pos + 10).ok_or(Error::TruncatedRecord)?;
+    let end = pos + 10 + u16::from_be_bytes([fields[8], fields[9]]) as usize;
+    if end > message.len() {
+        return Err(Error::TruncatedRecord);
+    }
+    Ok(end)
+}
+
+/// Checks that `response` has the QR bit set and echoes the transaction ID
+/// and question of `request`. A response may leave out the question only to
+/// report an error, as some servers do for queries they can't parse.
+pub(crate) fn is_reply_to(response: &[u8], request: &[u8]) -> bool {
+    if response.len() < 12 || response[0..2] != request[0..2] {
+        return false;
+    }
+    if response[2] & 0x80 == 0 {
+        return false;
+    }
+    if response[4..6] == [0, 0] {
+        return response[3] & 0x0F != 0;
+    }
+    let Ok(question_end) = skip_name(request, 12).map(|end| end + 4) else {
+        return false;
+    };
+    // Upstreams may change the case of the name, so compare it loosely.
+    response[4..6] == [0, 1]
+        && question_end <= response.len()
+        && response[12..question_end]
+            .eq_ignore_ascii_case(&request[12..question_end])
+}
+
+pub(crate) fn is_truncated(response: &[u8]) -> bool {
+    response.len() > 2 && response[2] & 0x02 != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A recursive query for `name`.
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut message = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(&encode_name(name));
+        message.extend_from_slice(&qtype.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message
+    }
+
+    /// A query for `name` with an OPT record advertising 1232 bytes and
+    /// `dnssec_ok` as its DO bit.
+    fn edns_query(name: &str, qtype: u16, dnssec_ok: bool) -> Vec<u8> {
+        let mut message = query(name, qtype);
+        message[11] = 1;
+        message.push(0);
+        message.extend_from_slice(&TYPE_OPT.to_be_bytes());
+        message.extend_from_slice(&1232u16.to_be_bytes());
+        message.extend_from_slice(&[0, 0, u8::from(dnssec_ok) << 7, 0]);
+        message.extend_from_slice(&[0, 0]);
+        message
+    }
+
+    fn a_record(owner: Option<&str>, address: [u8; 4]) -> Record {
+        Record {
+            owner: owner.map(str::to_owned),
+            rtype: TYPE_A,
+            class: CLASS_IN,
+            ttl: 60,
+            data: address.to_vec(),
+        }
+    }
+
+    fn count(message: &[u8], i: usize) -> u16 {
+        u16::from_be_bytes([message[i], message[i + 1]])
+    }
+
+    /// A header with one question followed by the name as given in wire
+    /// format, and then QTYPE and QCLASS.
+    fn query_with_wire_name(name: &[u8]) -> Vec<u8> {
+        let mut message = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(name);
+        message.extend_from_slice(&[0, 1, 0, 1]);
+        message
+    }
+
+    #[test]
+    fn follows_pointers_back_to_earlier_names() {
+        // A second name, "www" followed by a pointer to the question name.
+        let mut message = query("example.com", TYPE_A);
+        let second = message.len();
+        message.extend_from_slice(b"\x03www\xC0\x0C");
+        assert_eq!(
+            read_name(&message, second).unwrap(),
+            ("www.example.com".to_owned(), message.len())
+        );
+    }
+
+    #[test]
+    fn follows_a_pointer_in_the_question_name() {
+        // The last byte of the header is zero, which reads as the root.
+        let request = query_with_wire_name(b"\x03www\xC0\x0B");
+        let question = parse_dns_question(&request).unwrap();
+        assert_eq!(question.name, "www");
+        assert_eq!(question.qtype, TYPE_A);
+        assert_eq!(question.qclass, CLASS_IN);
+    }
+
+    #[test]
+    fn limits_pointer_jumps() {
+        // Each pointer leads to the one before it, and the first to the
+        // zero byte ending the header.
+        let mut message = query_with_wire_name(b"\xC0\x0B");
+        let mut last = 12;
+        for _ in 0..MAX_POINTER_JUMPS {
+            let next = message.len();
+            message.extend_from_slice(&[0xC0, last as u8]);
+            last = next;
+        }
+        assert_eq!(
+            read_name(&message, last - 2).map(|(name, _)| name),
+            Ok(".".to_owned())
+        );
+        assert_eq!(read_name(&message, last), Err(Error::TooManyPointers));
+    }
+
+    #[test]
+    fn parses_the_question_type_and_class() {
+        let mut request = query("example.com", TYPE_AAAA);
+        let end = request.len();
+        request[end - 2..].copy_from_slice(&CLASS_ANY.to_be_bytes());
+        let question = parse_dns_question(&request).unwrap();
+        assert_eq!(question.name, "example.com");
+        assert_eq!(question.qtype, TYPE_AAAA);
+        assert_eq!(question.qclass, CLASS_ANY);
+    }
+
+    #[test]
+    fn rejects_incomplete_queries() {
+        assert_eq!(parse_dns_question(&[0; 11]).err(), Some(Error::TooShort));
+        let mut request = query("example.com", TYPE_A);
+        request.truncate(request.len() - 2);
+        assert_eq!(
+            parse_dns_question(&request).err(),
+            Some(Error::MissingQtype)
+        );
+        let request = &query("example.com", TYPE_A)[..16];
+        assert_eq!(parse_dns_question(request).err(), Some(Error::InvalidName));
+    }
+
+    #[test]
+    fn udp_payload_size_is_clamped() {
+        let request = query("example.com", TYPE_A);
+        assert_eq!(udp_payload_size(&request), MIN_UDP_PAYLOAD);
+        let mut request = edns_query("example.com", TYPE_A, false);
+        assert_eq!(udp_payload_size(&request), 1232);
+        let size = request.len() - 8;
+        for (advertised, expected) in
+            [(100, MIN_UDP_PAYLOAD), (u16::MAX, MAX_UDP_PAYLOAD)]
+        {
+            request[size..size + 2].copy_from_slice(&advertised.to_be_bytes());
+            assert_eq!(udp_payload_size(&request), expected);
+        }
+    }
+
+    #[test]
+    fn truncates_to_the_question_with_tc() {
+        let request = query("example.com", TYPE_A);
+        let answers: Vec<_> =
+            (0..40).map(|i| a_record(None, [10, 0, 0, i])).collect();
+        let response =
+            create_answer_response(&request, 0, &answers, &[]).unwrap();
+        assert!(response.len() > MIN_UDP_PAYLOAD);
+
+        let truncated = truncate_response(response.clone(), MIN_UDP_PAYLOAD);
+        assert!(is_truncated(&truncated));
+        assert_eq!(truncated.len(), 12 + 13 + 4);
+        assert_eq!(count(&truncated, 4), 1);
+        assert_eq!(truncated[6..12], [0; 6]);
+
+        let fits = truncate_response(response.clone(), response.len());
+        assert_eq!(fits, response);
+    }
+
+    #[test]
+    fn reads_edns_flags() {
+        assert_eq!(
+            EdnsFlags::of(&query("example.com", TYPE_A)),
+            EdnsFlags::default()
+        );
+        let mut request = edns_query("example.com", TYPE_A, false);
+        assert_eq!(
+            EdnsFlags::of(&request),
+            EdnsFlags {
+                edns: true,
+                dnssec_ok: false,
+                checking_disabled: false,
+            }
+        );
+        request[3] |= 0x10;
+        let flags = EdnsFlags::of(&request);
+        assert!(flags.checking_disabled && !flags.dnssec_ok);
+        let flags = EdnsFlags::of(&edns_query("example.com", TYPE_A, true));
+        assert!(flags.dnssec_ok);
+    }
+
+    #[test]
+    fn parses_the_question_lowercased() {
+        let question =
+            parse_dns_question(&query("WWW.Example.COM", TYPE_AAAA)).unwrap();
+        assert_eq!(question.name, "www.example.com");
+        assert_eq!(question.qtype, TYPE_AAAA);
+        assert_eq!(question.qclass, CLASS_IN);
+    }
+
+    #[test]
+    fn cached_responses_take_the_request_id_and_case() {
+        let cached =
+            create_answer_response(&query("example.com", TYPE_A), 0, &[], &[])
+                .unwrap();
+        let mut request = query("ExAmPle.CoM", TYPE_A);
+        request[0..2].copy_from_slice(&[0xAB, 0xCD]);
+        let mut response = cached.clone();
+        match_request(&mut response, &request);
+        assert_eq!(response[0..2], [0xAB, 0xCD]);
+        assert_eq!(response[12..25], request[12..25]);
+        assert_eq!(response[25..], cached[25..]);
+    }
+
+    #[test]
+    fn answer_response_carries_records_and_edns() {
+        let request = edns_query("example.com", TYPE_A, true);
+        let response = create_answer_response(
+            &request,
+            0,
+            &[a_record(None, [10, 0, 0, 1])],
+            &[negative_soa(CLASS_IN, 300)],
+        )
+        .unwrap();
+        assert_eq!(count(&response, 6), 1);
+        assert_eq!(count(&response, 8), 1);
+        assert_eq!(count(&response, 10), 1);
+        assert_eq!(
+            EdnsFlags::of(&response),
+            EdnsFlags {
+                edns: true,
+                dnssec_ok: true,
+                checking_disabled: false,
+            }
+        );
+        assert_eq!(udp_payload_size(&response), MAX_UDP_PAYLOAD);
+
+        let request = query("example.com", TYPE_A);
+        let response = create_answer_response(&request, 0, &[], &[]).unwrap();
+        assert_eq!(count(&response, 10), 0);
+    }
+
+    #[test]
+    fn rejects_labels_over_63_octets() {
+        let mut name = vec![64];
+        name.extend_from_slice(&[b'a'; 64]);
+        name.push(0);
+        assert_eq!(
+            parse_dns_question(&query_with_wire_name(&name)).err(),
+            Some(Error::InvalidLabelType)
+        );
+    }
+
+    #[test]
+    fn rejects_pointers_that_dont_point_back() {
+        // A pointer to itself, and one to the QTYPE after it.
+        for target in [12, 14] {
+            let request = query_with_wire_name(&[0xC0, target]);
+            assert_eq!(
+                parse_dns_question(&request).err(),
+                Some(Error::InvalidPointer)
+            );
+        }
+    }
+
+    #[test]
+    fn recognizes_replies_to_a_request() {
+        let request = query("example.com", TYPE_A);
+        let reply = create_answer_response(&request, 0, &[], &[]).unwrap();
+        assert!(is_reply_to(&reply, &request));
+
+        // Upstreams may change the case of the name.
+        let mut recased = reply.clone();
+        recased[13..20].make_ascii_uppercase();
+        assert!(is_reply_to(&recased, &request));
+
+        let mut other_id = reply.clone();
+        other_id[1] ^= 1;
+        assert!(!is_reply_to(&other_id, &request));
+
+        let other_name =
+            create_answer_response(&query("example.org", TYPE_A), 0, &[], &[])
+                .unwrap();
+        assert!(!is_reply_to(&other_name, &request));
+
+        let other_type = create_answer_response(
+            &query("example.com", TYPE_AAAA),
+            0,
+            &[],
+            &[],
+        )
+        .unwrap();
+        assert!(!is_reply_to(&other_type, &request));
+
+        assert!(!is_reply_to(&request, &request));
+        assert!(!is_reply_to(&reply[..11], &request));
+    }
+
+    #[test]
+    fn accepts_errors_without_a_question() {
+        let request = query("example.com", TYPE_A);
+        let error = create_error_response(&request, RCODE_FORMERR);
+        assert!(is_reply_to(&error, &request));
+        let empty = create_error_response(&request, 0);
+        assert!(!is_reply_to(&empty, &request));
+    }
+
+    #[test]
+    fn question_response_drops_the_opt_record() {
+        let request = edns_query("example.com", TYPE_A, true);
+        let response =
+            create_question_response(&request, RCODE_SERVFAIL).unwrap();
+        assert_eq!(response.len(), 12 + 13 + 4);
+        assert_eq!(response[..2], request[..2]);
+        // QR and RD set, RA set and the rcode.
+        assert_eq!(response[2], 0x81);
+        assert_eq!(response[3], 0x80 | RCODE_SERVFAIL);
+        assert_eq!(count(&response, 4), 1);
+        assert_eq!(count(&response, 10), 0);
+        assert_eq!(response[12..], request[12..response.len()]);
+    }
+
+    #[test]
+    fn error_response_is_only_the_header() {
+        let request = query("example.com", TYPE_A);
+        let response = create_error_response(&request, RCODE_FORMERR);
+        assert_eq!(response.len(), 12);
+        assert_eq!(response[3] & 0x0F, RCODE_FORMERR);
+        assert_eq!(response[4..12], [0; 8]);
+    }
+
+    #[test]
+    fn parses_the_root_as_a_dot() {
+        let question = parse_dns_question(&query(".", TYPE_A)).unwrap();
+        assert_eq!(question.name, ".");
+    }
+
+    fn cname_record(owner: &str, target: &str) -> Record {
+        Record {
+            owner: Some(owner.to_owned()),
+            rtype: TYPE_CNAME,
+            class: CLASS_IN,
+            ttl: 60,
+            data: encode_name(target),
+        }
+    }
+
+    #[test]
+    fn follows_cname_chains() {
+        let request = query("a.example", TYPE_A);
+        let response = create_answer_response(
+            &request,
+            0,
+            &[
+                cname_record("a.example", "B.example"),
+                cname_record("b.example", "c.example"),
+                a_record(Some("c.example"), [10, 0, 0, 1]),
+                cname_record("unrelated.example", "d.example"),
+            ],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            cname_chain(&response, "a.example").unwrap(),
+            ["b.example", "c.example"]
+        );
+    }
+
+    #[test]
+    fn stops_at_cname_loops() {
+        let request = query("a.example", TYPE_A);
+        let response = create_answer_response(
+            &request,
+            0,
+            &[
+                cname_record("a.example", "b.example"),
+                cname_record("b.example", "a.example"),
+            ],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            cname_chain(&response, "a.example").unwrap(),
+            ["b.example", "a.example"]
+        );
+    }
+
+    #[test]
+    fn negative_soa_names_the_filter() {
+        let request = query("ads.example", TYPE_A);
+        let soa = negative_soa(CLASS_IN, 300);
+        let response =
+            create_answer_response(&request, 0, &[], &[soa]).unwrap();
+        // The record's RDATA follows a compressed owner name, TYPE, CLASS,
+        // TTL and RDLENGTH.
+        let (mname, end) = read_name(&response, request.len() + 12).unwrap();
+        assert_eq!(mname, "blocked.dnsfilter");
+        let (rname, end) = read_name(&response, end).unwrap();
+        assert_eq!(rname, "blocked.dnsfilter");
+        assert_eq!(&response[end + 16..end + 20], 300u32.to_be_bytes());
+        assert_eq!(end + 20, response.len());
+    }
+
+    #[test]
+    fn responses_keep_only_the_flags_that_carry_over() {
+        let mut request = query("example.com", TYPE_A);
+        // QR, AA, TC and RD; then Z, AD, CD and a stray RCODE.
+        request[2..4].copy_from_slice(&[0x87, 0x7F]);
+        let response =
+            create_question_response(&request, RCODE_SERVFAIL).unwrap();
+        assert_eq!(response[2..4], [0x81, 0x90 | RCODE_SERVFAIL]);
+
+        // The opcode stays, and RD and CD stay off if they were.
+        request[2..4].copy_from_slice(&[0x28, 0x00]);
+        let response = create_error_response(&request, RCODE_NOTIMP);
+        assert_eq!(response[2..4], [0xA8, 0x80 | RCODE_NOTIMP]);
+    }
+
+    #[test]
+    fn limits_names_to_255_octets() {
+        let label = "a".repeat(63);
+        // Three 64-octet labels, one of 62 and the root make 255 octets.
+        let longest = format!("{label}.{label}.{label}.{}", "a".repeat(61));
+        let request = query(&longest, TYPE_A);
+        assert_eq!(parse_dns_question(&request).unwrap().name, longest);
+
+        let too_long = format!("{longest}a");
+        assert_eq!(
+            parse_dns_question(&query(&too_long, TYPE_A)).err(),
+            Some(Error::NameTooLong)
+        );
+    }
+
+    #[test]
+    fn extended_error_goes_in_the_opt_record() {
+        let request = edns_query("example.com", TYPE_A, false);
+        let mut response =
+            create_answer_response(&request, 0, &[], &[]).unwrap();
+        add_extended_error(&mut response, EDE_FILTERED, "listed");
+        let opt = find_opt(&response).unwrap().unwrap();
+        assert_eq!(count(&response, opt + 8), 4 + 2 + 6);
+        assert_eq!(count(&response, opt + 10), OPTION_EDE);
+        assert_eq!(count(&response, opt + 12), 2 + 6);
+        assert_eq!(count(&response, opt + 14), EDE_FILTERED);
+        assert!(response.ends_with(b"listed"));
+
+        let request = query("example.com", TYPE_A);
+        let mut response =
+            create_answer_response(&request, 0, &[], &[]).unwrap();
+        let plain = response.clone();
+        add_extended_error(&mut response, EDE_FILTERED, "listed");
+        assert_eq!(response, plain);
+    }
+}
@@ -0,0 +1,355 @@
+//! dnstap (https://dnstap.info) output over a Frame Streams unix socket, so
+//! the queries and answers passing through can feed a telemetry pipeline.
+//!
+//! Messages are encoded as they happen and queued for a writer task, which
+//! owns the connection to the collector. A slow or missing collector costs
+//! messages, counted as dropped, rather than holding up answers.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+    sync::mpsc::{self, Receiver, Sender},
+    time::timeout,
+};
+use tracing::{info, warn};
+
+/// Messages waiting for the writer beyond which new ones are dropped.
+const QUEUE_SIZE: usize = 8192;
+
+/// Bounds on the wait between attempts to reach the collector.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Upper bound on the Frame Streams handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+/// Frame Streams control frame types.
+const CONTROL_ACCEPT: u32 = 1;
+const CONTROL_START: u32 = 2;
+const CONTROL_STOP: u32 = 3;
+const CONTROL_READY: u32 = 4;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 1;
+
+/// dnstap `Message.Type` values.
+const CLIENT_QUERY: u64 = 5;
+const CLIENT_RESPONSE: u64 = 6;
+const RESOLVER_QUERY: u64 = 3;
+const RESOLVER_RESPONSE: u64 = 4;
+
+/// How a message travelled, numbered as dnstap's `SocketProtocol`.
+#[derive(Clone, Copy)]
+pub enum Transport {
+    Udp = 1,
+    Tcp = 2,
+    Tls = 3,
+    Https = 4,
+    Quic = 7,
+}
+
+pub struct Dnstap {
+    frames: Sender<Vec<u8>>,
+    /// Messages lost to a full queue or a broken connection.
+    dropped: Arc<AtomicU64>,
+}
+
+impl Dnstap {
+    /// Starts the writer, which connects to the collector listening on
+    /// `path` and keeps reconnecting whenever it goes away. This must run
+    /// inside the runtime.
+    pub fn new(path: PathBuf) -> Self {
+        let (frames, receiver) = mpsc::channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        tokio::spawn(write_frames(path, receiver, Arc::clone(&dropped)));
+        Self { frames, dropped }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// A query as received from `client`.
+    pub fn client_query(
+        &self,
+        client: SocketAddr,
+        transport: Transport,
+        received: SystemTime,
+        query: &[u8],
+    ) {
+        let mut message = Message::new(CLIENT_QUERY, transport);
+        message.address(4, 6, client);
+        message.time(8, 9, received);
+        message.bytes(10, query);
+        self.send(message);
+    }
+
+    /// The answer sent back to `client` for a query received at `received`.
+    pub fn client_response(
+        &self,
+        client: SocketAddr,
+        transport: Transport,
+        received: SystemTime,
+        response: &[u8],
+    ) {
+        let mut message = Message::new(CLIENT_RESPONSE, transport);
+        message.address(4, 6, client);
+        message.time(8, 9, received);
+        message.time(12, 13, SystemTime::now());
+        message.bytes(14, response);
+        self.send(message);
+    }
+
+    /// A query sent upstream. DNS-over-HTTPS servers are known by URL rather
+    /// than address, so `upstream` may be missing.
+    pub fn resolver_query(
+        &self,
+        upstream: Option<SocketAddr>,
+        transport: Transport,
+        sent: SystemTime,
+        query: &[u8],
+    ) {
+        let mut message = Message::new(RESOLVER_QUERY, transport);
+        if let Some(upstream) = upstream {
+            message.address(5, 7, upstream);
+        }
+        message.time(8, 9, sent);
+        message.bytes(10, query);
+        self.send(message);
+    }
+
+    /// An upstream's answer to a query sent at `sent`.
+    pub fn resolver_response(
+        &self,
+        upstream: Option<SocketAddr>,
+        transport: Transport,
+        sent: SystemTime,
+        response: &[u8],
+    ) {
+        let mut message = Message::new(RESOLVER_RESPONSE, transport);
+        if let Some(upstream) = upstream {
+            message.address(5, 7, upstream);
+        }
+        message.time(8, 9, sent);
+        message.time(12, 13, SystemTime::now());
+        message.bytes(14, response);
+        self.send(message);
+    }
+
+    /// Wraps the message in a `Dnstap` and queues it as a data frame.
+    fn send(&self, message: Message) {
+        let mut dnstap = Protobuf::default();
+        let version = concat!("dnsfilter ", env!("CARGO_PKG_VERSION"));
+        dnstap.bytes(2, version.as_bytes());
+        // The only type there is, MESSAGE.
+        dnstap.varint(15, 1);
+        let Message(Protobuf(message)) = message;
+        dnstap.bytes(14, &message);
+        let payload = dnstap.0;
+        let mut frame = Vec::with_capacity(payload.len() + 4);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        if self.frames.try_send(frame).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A dnstap `Message` being encoded.
+struct Message(Protobuf);
+
+impl Message {
+    fn new(kind: u64, transport: Transport) -> Self {
+        let mut message = Protobuf::default();
+        message.varint(1, kind);
+        message.varint(3, transport as u64);
+        Self(message)
+    }
+
+    /// Sets the socket family along with the address and port, which go in
+    /// the given fields.
+    fn address(&mut self, address: u32, port: u32, addr: SocketAddr) {
+        let (family, ip) = match addr.ip().to_canonical() {
+            IpAddr::V4(ip) => (1, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (2, ip.octets().to_vec()),
+        };
+        self.0.varint(2, family);
+        self.0.bytes(address, &ip);
+        self.0.varint(port, addr.port().into());
+    }
+
+    /// Sets the seconds and nanoseconds fields of a timestamp.
+    fn time(&mut self, sec: u32, nsec: u32, time: SystemTime) {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.0.varint(sec, since_epoch.as_secs());
+        self.0.fixed32(nsec, since_epoch.subsec_nanos());
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.0.bytes(field, value);
+    }
+}
+
+/// Just enough of the protobuf wire format for dnstap's messages.
+#[derive(Default)]
+struct Protobuf(Vec<u8>);
+
+impl Protobuf {
+    fn key(&mut self, field: u32, wire_type: u32) {
+        self.raw_varint(u64::from(field << 3 | wire_type));
+    }
+
+    fn raw_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn varint(&mut self, field: u32, value: u64) {
+        self.key(field, 0);
+        self.raw_varint(value);
+    }
+
+    fn fixed32(&mut self, field: u32, value: u32) {
+        self.key(field, 5);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, 2);
+        self.raw_varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+}
+
+/// Sends queued frames to the collector for as long as the queue is open,
+/// reconnecting with a growing delay whenever the collector can't be
+/// reached. Frames keep queueing meanwhile, up to `QUEUE_SIZE`.
+async fn write_frames(
+    path: PathBuf,
+    mut frames: Receiver<Vec<u8>>,
+    dropped: Arc<AtomicU64>,
+) {
+    let mut delay = MIN_RECONNECT_DELAY;
+    // Only the first of a run of failed attempts is worth a warning.
+    let mut reported = false;
+    loop {
+        let opened = timeout(HANDSHAKE_TIMEOUT, open(&path))
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+        let stream = match opened {
+            Ok(stream) => stream,
+            Err(e) => {
+                if !reported {
+                    warn!(
+                        path = %path.display(),
+                        error = %e,
+                        "failed to connect to dnstap collector"
+                    );
+                    reported = true;
+                }
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                continue;
+            }
+        };
+        info!(path = %path.display(), "connected to dnstap collector");
+        delay = MIN_RECONNECT_DELAY;
+        reported = false;
+        let mut stream = BufWriter::new(stream);
+        loop {
+            let Some(frame) = frames.recv().await else {
+                // The service is going away, so end the stream cleanly.
+                let _ = write_control(&mut stream, CONTROL_STOP).await;
+                return;
+            };
+            let mut written = stream.write_all(&frame).await;
+            // Write out a batch once the queue has been drained.
+            if written.is_ok() && frames.is_empty() {
+                written = stream.flush().await;
+            }
+            if let Err(e) = written {
+                // Whatever was still buffered is lost along with the frame.
+                dropped.fetch_add(1, Ordering::Relaxed);
+                warn!(error = %e, "lost connection to dnstap collector");
+                break;
+            }
+        }
+    }
+}
+
+/// Connects and goes through the bidirectional Frame Streams handshake:
+/// READY, then ACCEPT from the collector, then START.
+async fn open(path: &Path) -> io::Result<Stream> {
+    let mut stream = connect(path).await?;
+    write_control(&mut stream, CONTROL_READY).await?;
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header).await?;
+    let escape = u32::from_be_bytes(header[0..4].try_into().unwrap());
+    let len = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+    if escape != 0 || !(4..=512).contains(&len) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "expected a Frame Streams control frame",
+        ));
+    }
+    let mut control = vec![0u8; len];
+    stream.read_exact(&mut control).await?;
+    if u32::from_be_bytes(control[0..4].try_into().unwrap()) != CONTROL_ACCEPT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "collector did not accept the stream",
+        ));
+    }
+    write_control(&mut stream, CONTROL_START).await?;
+    Ok(stream)
+}
+
+/// Writes a control frame, which carries the content type unless it is a
+/// STOP.
+async fn write_control<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    control: u32,
+) -> io::Result<()> {
+    let mut body = control.to_be_bytes().to_vec();
+    if control != CONTROL_STOP {
+        body.extend_from_slice(&CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+        body.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        body.extend_from_slice(CONTENT_TYPE);
+    }
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    stream.write_all(&frame).await?;
+    stream.flush().await
+}
+
+#[cfg(unix)]
+type Stream = tokio::net::UnixStream;
+
+#[cfg(unix)]
+async fn connect(path: &Path) -> io::Result<Stream> {
+    Stream::connect(path).await
+}
+
+#[cfg(not(unix))]
+type Stream = tokio::net::TcpStream;
+
+#[cfg(not(unix))]
+async fn connect(_: &Path) -> io::Result<Stream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "dnstap needs unix sockets",
+    ))
+}
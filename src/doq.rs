@@ -0,0 +1,151 @@
+//! DNS-over-QUIC upstream (RFC 9250).
+//!
+//! One QUIC connection is kept open and every query gets its own
+//! bidirectional stream, so the handshake is paid once rather than per query
+//! and only the stream exchange counts against the query timeout.
+
+use crate::{
+    dot,
+    server::{read_tcp_message, write_tcp_message},
+    upstream::UPSTREAM_TIMED_OUT,
+};
+use quinn::{
+    crypto::rustls::QuicClientConfig, ClientConfig, Connection, Endpoint,
+    TransportConfig,
+};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::Mutex, time::timeout};
+
+/// Upper bound on dialing the server, so an unreachable or filtered UDP/853
+/// fails with a clear error instead of hanging.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keep-alives hold the connection open between queries, and a peer that
+/// stops acknowledging them is declared dead after `IDLE_TIMEOUT`, so the
+/// next query redials instead of timing out on a stale connection.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(4);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct DoqClient {
+    endpoint: Endpoint,
+    addr: SocketAddr,
+    server_name: String,
+    connection: Mutex<Option<Connection>>,
+}
+
+impl DoqClient {
+    /// Builds a client from `ip[:port][#hostname]` (see `dot::parse_server`).
+    pub fn new(
+        spec: &str,
+        insecure: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let (addr, server_name) = dot::parse_server(spec)?;
+        let mut tls = dot::client_config(insecure)?;
+        tls.alpn_protocols = vec![b"doq".to_vec()];
+        let mut config = ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(Arc::new(tls))?,
+        ));
+        let mut transport = TransportConfig::default();
+        transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+        transport.max_idle_timeout(Some(IDLE_TIMEOUT.try_into()?));
+        config.transport_config(Arc::new(transport));
+
+        let bind: SocketAddr = if addr.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let mut endpoint = Endpoint::client(bind)?;
+        endpoint.set_default_client_config(config);
+
+        Ok(Self {
+            endpoint,
+            addr,
+            server_name: server_name.to_str().into_owned(),
+            connection: Mutex::new(None),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub async fn query(
+        &self,
+        request: &[u8],
+        query_timeout: Duration,
+    ) -> Result<Vec<u8>, &'static str> {
+        if request.len() < 12 {
+            return Err("Invalid DNS request");
+        }
+        let connection = self.connection().await?;
+        let result = timeout(query_timeout, exchange(&connection, request))
+            .await
+            .map_err(|_| UPSTREAM_TIMED_OUT)?;
+        match result {
+            // The connection died under us, so redial and try once more.
+            Err(_) if connection.close_reason().is_some() => {
+                let connection = self.connection().await?;
+                timeout(query_timeout, exchange(&connection, request))
+                    .await
+                    .map_err(|_| UPSTREAM_TIMED_OUT)?
+            }
+            result => result,
+        }
+    }
+
+    /// Returns the open connection, dialing a new one if the previous one
+    /// was closed. Holding the lock while dialing keeps concurrent queries
+    /// from each starting their own handshake.
+    async fn connection(&self) -> Result<Connection, &'static str> {
+        let mut current = self.connection.lock().await;
+        if let Some(connection) = current.as_ref() {
+            if connection.close_reason().is_none() {
+                return Ok(connection.clone());
+            }
+        }
+
+        let connecting = self
+            .endpoint
+            .connect(self.addr, &self.server_name)
+            .map_err(|_| "Failed to connect to DoQ server")?;
+        let connection = timeout(HANDSHAKE_TIMEOUT, connecting)
+            .await
+            .map_err(|_| "DoQ handshake timed out")?
+            .map_err(|_| "DoQ handshake failed")?;
+        *current = Some(connection.clone());
+        Ok(connection)
+    }
+}
+
+async fn exchange(
+    connection: &Connection,
+    request: &[u8],
+) -> Result<Vec<u8>, &'static str> {
+    let (mut send, mut recv) = connection
+        .open_bi()
+        .await
+        .map_err(|_| "Failed to open DoQ stream")?;
+
+    // The stream identifies the query, so the message ID must be zero on the
+    // wire (RFC 9250 4.2.1).
+    let mut message = request.to_vec();
+    message[0..2].copy_from_slice(&[0, 0]);
+    write_tcp_message(&mut send, &message)
+        .await
+        .map_err(|_| "Failed to forward over QUIC")?;
+    send.finish().map_err(|_| "Failed to forward over QUIC")?;
+
+    let mut response = read_tcp_message(&mut recv)
+        .await
+        .map_err(|_| "Failed to receive QUIC response")?;
+    if response.len() < 2 {
+        return Err("Invalid DoQ response");
+    }
+    response[0..2].copy_from_slice(&request[0..2]);
+    Ok(response)
+}
@@ -0,0 +1,311 @@
+//! DNS-over-TLS upstream (RFC 7858) over a single persistent connection.
+//!
+//! Queries are pipelined: each one is sent with a connection-local
+//! transaction ID, and a reader task routes responses back to the waiting
+//! caller by that ID before the client's original ID is restored.
+
+use crate::server::{read_tcp_message, write_tcp_message};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    io::{ReadHalf, WriteHalf},
+    net::TcpStream,
+    sync::{oneshot, Mutex},
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{
+        self,
+        client::danger::{
+            HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+        },
+        crypto::{ring, CryptoProvider},
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    },
+    TlsConnector,
+};
+
+const DEFAULT_PORT: u16 = 853;
+
+/// Parses `ip[:port][#hostname]`, defaulting to port 853. The hostname is
+/// used for SNI and certificate validation; without one the IP address must
+/// appear in the server's certificate.
+pub fn parse_server(
+    spec: &str,
+) -> Result<(SocketAddr, ServerName<'static>), Box<dyn std::error::Error>> {
+    let (addr, hostname) = match spec.split_once('#') {
+        Some((addr, hostname)) => (addr, Some(hostname)),
+        None => (spec, None),
+    };
+    let addr = match addr.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(_) => SocketAddr::new(addr.parse::<IpAddr>()?, DEFAULT_PORT),
+    };
+    let server_name = match hostname {
+        Some(hostname) => ServerName::try_from(hostname.to_owned())?,
+        None => ServerName::IpAddress(addr.ip().into()),
+    };
+    Ok((addr, server_name))
+}
+
+/// TLS settings shared by the encrypted upstreams. Certificates are verified
+/// against the bundled web PKI roots unless `insecure` is set.
+pub fn client_config(insecure: bool) -> Result<ClientConfig, rustls::Error> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let config = if insecure {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(
+                provider,
+            )))
+            .with_no_client_auth()
+    } else {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+    Ok(config)
+}
+
+pub struct DotClient {
+    addr: SocketAddr,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+    connection: Mutex<Option<Arc<Connection>>>,
+}
+
+impl DotClient {
+    /// Builds a client from `ip[:port][#hostname]` (see `parse_server`).
+    pub fn new(
+        spec: &str,
+        insecure: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let (addr, server_name) = parse_server(spec)?;
+        let config = client_config(insecure)?;
+        Ok(Self {
+            addr,
+            server_name,
+            connector: TlsConnector::from(Arc::new(config)),
+            connection: Mutex::new(None),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub async fn query(&self, request: &[u8]) -> Result<Vec<u8>, &'static str> {
+        if request.len() < 12 {
+            return Err("Invalid DNS request");
+        }
+        let connection = self.connection().await?;
+        match connection.exchange(request).await {
+            // The server may have closed an idle connection just before we
+            // wrote to it, so give a fresh connection one more try.
+            Err(_) if !connection.is_alive() => {
+                self.connection().await?.exchange(request).await
+            }
+            result => result,
+        }
+    }
+
+    /// Returns the live connection, dialing a new one if the previous one
+    /// was closed. Holding the lock while dialing keeps concurrent queries
+    /// from each opening their own connection.
+    async fn connection(&self) -> Result<Arc<Connection>, &'static str> {
+        let mut current = self.connection.lock().await;
+        if let Some(connection) = current.as_ref() {
+            if connection.is_alive() {
+                return Ok(Arc::clone(connection));
+            }
+        }
+
+        let tcp = TcpStream::connect(self.addr)
+            .await
+            .map_err(|_| "Failed to connect to DoT server")?;
+        let tls = self
+            .connector
+            .connect(self.server_name.clone(), tcp)
+            .await
+            .map_err(|_| "TLS handshake with DoT server failed")?;
+        let (reader, writer) = tokio::io::split(tls);
+        let connection = Arc::new(Connection {
+            writer: Mutex::new(writer),
+            pending: std::sync::Mutex::new(HashMap::new()),
+            next_id: AtomicU16::new(0),
+            alive: AtomicBool::new(true),
+        });
+        tokio::spawn(Arc::clone(&connection).read_responses(reader));
+        *current = Some(Arc::clone(&connection));
+        Ok(connection)
+    }
+}
+
+struct Connection {
+    writer: Mutex<WriteHalf<TlsStream<TcpStream>>>,
+    pending: std::sync::Mutex<HashMap<u16, oneshot::Sender<Vec<u8>>>>,
+    next_id: AtomicU16,
+    alive: AtomicBool,
+}
+
+impl Connection {
+    fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Acquire)
+    }
+
+    fn close(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        self.alive.store(false, Ordering::Release);
+        // Dropping the senders wakes every waiting query with an error.
+        pending.clear();
+    }
+
+    async fn exchange(&self, request: &[u8]) -> Result<Vec<u8>, &'static str> {
+        let (tx, rx) = oneshot::channel();
+        let id = {
+            let mut pending = self.pending.lock().unwrap();
+            if !self.is_alive() {
+                return Err("DoT connection closed");
+            }
+            let id = loop {
+                let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                if !pending.contains_key(&id) {
+                    break id;
+                }
+            };
+            pending.insert(id, tx);
+            id
+        };
+        let _guard = PendingGuard {
+            connection: self,
+            id,
+        };
+
+        let mut message = request.to_vec();
+        message[0..2].copy_from_slice(&id.to_be_bytes());
+        let written = {
+            let mut writer = self.writer.lock().await;
+            // An earlier write may have been cut short while this one waited.
+            if !self.is_alive() {
+                return Err("DoT connection closed");
+            }
+            // The caller's timeout can cancel the write partway through a
+            // message. What was sent would then garble every later message
+            // on the connection, so an unfinished write closes it.
+            let unfinished = CloseOnDrop(self);
+            let written = write_tcp_message(&mut *writer, &message).await;
+            std::mem::forget(unfinished);
+            written
+        };
+        if written.is_err() {
+            self.close();
+            return Err("Failed to forward over TLS");
+        }
+
+        let mut response = rx.await.map_err(|_| "DoT connection closed")?;
+        if response.len() < 2 {
+            return Err("Invalid DoT response");
+        }
+        response[0..2].copy_from_slice(&request[0..2]);
+        Ok(response)
+    }
+
+    async fn read_responses(
+        self: Arc<Self>,
+        mut reader: ReadHalf<TlsStream<TcpStream>>,
+    ) {
+        while let Ok(response) = read_tcp_message(&mut reader).await {
+            if response.len() < 2 {
+                continue;
+            }
+            let id = u16::from_be_bytes([response[0], response[1]]);
+            let waiter = self.pending.lock().unwrap().remove(&id);
+            if let Some(waiter) = waiter {
+                let _ = waiter.send(response);
+            }
+        }
+        self.close();
+    }
+}
+
+/// Closes the connection unless forgotten first.
+struct CloseOnDrop<'a>(&'a Connection);
+
+impl Drop for CloseOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// Forgets a pending query when its caller stops waiting, e.g. on timeout,
+/// so a late response is discarded instead of leaking the map entry.
+struct PendingGuard<'a> {
+    connection: &'a Connection,
+    id: u16,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.connection.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Accepts any certificate for `--tls-insecure`, while still checking that
+/// the handshake signatures are valid for the presented certificate.
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,1217 @@
+//! The denylists and allowlists, and what they say about a domain.
+
+use crate::{
+    args::ListFormat,
+    dns::parse_qtype,
+    policy::PolicyConfig,
+    remote::{self, Fetcher},
+};
+use arc_swap::ArcSwap;
+use qfilter::Filter;
+use regex::{Regex, RegexSet, RegexSetBuilder};
+use std::{
+    collections::{hash_map, HashMap},
+    fmt,
+    fs::File,
+    io::BufRead,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+use tracing::{debug, info, warn};
+
+/// Regex rules per list beyond which startup warns that matching will be slow.
+const MANY_PATTERNS: usize = 1000;
+
+/// Names that hosts files map to themselves rather than to block them.
+const LOCAL_HOST_NAMES: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "broadcasthost",
+    "local",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+];
+
+/// Separators of adblock element hiding rules, such as "example.com##.ad".
+const COSMETIC_MARKERS: &[&str] = &["##", "#@#", "#?#", "#$#", "#%#"];
+
+/// A set of domains where the quotient filter rejects most misses cheaply and
+/// the exact map confirms hits, so a filter false positive never blocks. The
+/// map holds the query types each domain is limited to and the addresses to
+/// answer it with.
+struct DomainSet {
+    set: Filter,
+    exact: Rules,
+}
+
+/// Entries by domain. Most entries are for every query type and give no
+/// addresses, so they have no `Rule`, which keeps the map small for lists of
+/// millions of domains.
+type Rules = HashMap<Box<str>, Option<Box<Rule>>>;
+
+/// What the entries for one domain say about it.
+#[derive(Default)]
+pub(crate) struct Rule {
+    /// Query types blocked, or empty for every type.
+    qtypes: Vec<u16>,
+    /// Addresses to answer with instead of the usual block answer.
+    addresses: Vec<IpAddr>,
+}
+
+impl Rule {
+    /// Whether the rule says no more than that the domain is listed.
+    fn is_plain(&self) -> bool {
+        self.qtypes.is_empty() && self.addresses.is_empty()
+    }
+}
+
+impl DomainSet {
+    /// Indexes the entries, taking over their map as the exact one.
+    fn new(entries: Entries) -> Self {
+        let mut set = Filter::new(entries.len() as u64, 0.00000001).unwrap();
+        for domain in entries.rules.keys() {
+            set.insert(domain).unwrap();
+        }
+        Self {
+            set,
+            exact: entries.rules,
+        }
+    }
+
+    /// Returns the addresses to answer with if the domain is in the set for
+    /// this query type, which are empty unless its entries gave some.
+    fn get(&self, s: &str, qtype: u16) -> Option<&[IpAddr]> {
+        if !self.set.contains(s) {
+            return None;
+        }
+        let Some(rule) = self.exact.get(s)? else {
+            return Some(&[]);
+        };
+        (rule.qtypes.is_empty() || rule.qtypes.contains(&qtype))
+            .then_some(&rule.addresses[..])
+    }
+
+    fn len(&self) -> usize {
+        self.exact.len()
+    }
+}
+
+/// The entries of one list file, split by what they match.
+pub(crate) struct DomainList {
+    /// Plain entries read with wildcards off, which match only that exact
+    /// name.
+    names: DomainSet,
+    /// `.example.com` and `||example.com^` entries, which match the domain
+    /// and all of its subdomains.
+    wildcards: DomainSet,
+    /// `*.example.com` entries, which match the subdomains but not the
+    /// domain itself.
+    subdomains: DomainSet,
+    /// `/regex/` entries, matched against the full name only when no domain
+    /// entry decides it since they are far slower to check.
+    patterns: RegexSet,
+    /// The lines skipped while reading the lists.
+    pub(crate) stats: LoadStats,
+}
+
+impl DomainList {
+    fn new(entries: ListEntries) -> std::io::Result<Self> {
+        if entries.patterns.len() > MANY_PATTERNS {
+            warn!(
+                rules = entries.patterns.len(),
+                "many regex rules, which are much slower to match than \
+                 domain entries"
+            );
+        }
+        let patterns = RegexSetBuilder::new(&entries.patterns)
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| {
+                std::io::Error::other(format!(
+                    "Failed to compile the regex rules: {}",
+                    e
+                ))
+            })?;
+        Ok(Self {
+            names: DomainSet::new(entries.names),
+            wildcards: DomainSet::new(entries.wildcards),
+            subdomains: DomainSet::new(entries.subdomains),
+            patterns,
+            stats: entries.stats,
+        })
+    }
+
+    /// Checks one suffix of a query name, where `whole` says whether it is
+    /// the full name or one of its parents, returning the addresses of the
+    /// matching entry.
+    fn matches(
+        &self,
+        suffix: &str,
+        whole: bool,
+        qtype: u16,
+    ) -> Option<&[IpAddr]> {
+        // Plain entries match only the name itself, `*.` ones only the
+        // names below it.
+        let set = if whole { &self.names } else { &self.subdomains };
+        set.get(suffix, qtype)
+            .or_else(|| self.wildcards.get(suffix, qtype))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.names.len()
+            + self.wildcards.len()
+            + self.subdomains.len()
+            + self.patterns.len()
+    }
+
+    /// Counts the domain entries here that `other` doesn't have.
+    pub(crate) fn count_missing_from(&self, other: &DomainList) -> usize {
+        let missing = |ours: &DomainSet, theirs: &DomainSet| {
+            ours.exact
+                .keys()
+                .filter(|domain| !theirs.exact.contains_key(*domain))
+                .count()
+        };
+        missing(&self.names, &other.names)
+            + missing(&self.wildcards, &other.wildcards)
+            + missing(&self.subdomains, &other.subdomains)
+    }
+}
+
+/// The denylist together with the allowlist that punches holes in it.
+pub struct Blocklists {
+    pub(crate) denylist: DomainList,
+    pub(crate) allowlist: DomainList,
+}
+
+/// Where and how the blocklists are read, kept so they can be reloaded.
+pub(crate) struct ListConfig {
+    /// The default lists, which rereading the config file may change.
+    pub(crate) lists: ArcSwap<ListSources>,
+    /// Treat plain entries as wildcards, as lists written before wildcard
+    /// syntax existed expect. On unless turned off.
+    pub(crate) implicit_wildcards: bool,
+    /// How the denylists are written.
+    pub(crate) denylist_format: ListFormat,
+    /// The file of regex rules blocking names for every client.
+    pub(crate) regex_denylist: Option<PathBuf>,
+    /// Skip lists that can't be downloaded rather than failing the load.
+    pub(crate) fail_open: bool,
+    pub(crate) fetcher: Fetcher,
+    /// The lists of each client policy, in the order of `Service::policies`.
+    pub(crate) policies: Vec<PolicyConfig>,
+    /// The file of records answered locally, reloaded with the lists.
+    pub(crate) local_records: Option<PathBuf>,
+}
+
+impl ListConfig {
+    /// Every list read, whichever clients it is for.
+    pub(crate) fn sources(&self) -> Vec<String> {
+        let lists = self.lists.load();
+        let policies = self.policies.iter().flat_map(|policy| {
+            policy.denylists.iter().chain(&policy.allowlists)
+        });
+        lists
+            .denylists
+            .iter()
+            .chain(&lists.allowlists)
+            .chain(policies)
+            .cloned()
+            .collect()
+    }
+}
+
+pub(crate) struct ListSources {
+    /// Files and URLs, combined into one denylist.
+    pub(crate) denylists: Vec<String>,
+    /// Files and URLs, combined into one allowlist.
+    pub(crate) allowlists: Vec<String>,
+}
+
+impl Blocklists {
+    /// Reads the given lists, doing the parsing and indexing on blocking
+    /// threads so a reload doesn't stall the async workers.
+    pub(crate) async fn load(
+        config: &ListConfig,
+        denylists: &[String],
+        allowlists: &[String],
+    ) -> std::io::Result<Self> {
+        let mut denied = ListEntries::default();
+        for source in denylists {
+            let entries =
+                read_list(config, source, config.denylist_format).await?;
+            let stats = entries.stats;
+            info!(
+                source,
+                entries = entries.len(),
+                comments = stats.comments,
+                malformed = stats.malformed,
+                unsupported = stats.unsupported,
+                "read denylist"
+            );
+            denied.extend(entries);
+        }
+        if let Some(path) = &config.regex_denylist {
+            let source = path.clone();
+            let entries =
+                tokio::task::spawn_blocking(move || read_regex_list(&source))
+                    .await??;
+            info!(
+                source = %path.display(),
+                rules = entries.len(),
+                malformed = entries.stats.malformed,
+                "read regex denylist"
+            );
+            denied.extend(entries);
+        }
+        let mut allowed = ListEntries::default();
+        for source in allowlists {
+            let entries = read_list(config, source, ListFormat::Auto).await?;
+            let stats = entries.stats;
+            info!(
+                source,
+                entries = entries.len(),
+                comments = stats.comments,
+                malformed = stats.malformed,
+                unsupported = stats.unsupported,
+                "read allowlist"
+            );
+            allowed.extend(entries);
+        }
+        tokio::task::spawn_blocking(move || Self::build(denied, allowed))
+            .await?
+    }
+
+    /// Indexes the entries read from the lists.
+    pub fn build(
+        mut denied: ListEntries,
+        mut allowed: ListEntries,
+    ) -> std::io::Result<Self> {
+        allowed.wildcards.append(&mut denied.exceptions);
+        allowed.wildcards.append(&mut allowed.exceptions);
+        Ok(Self {
+            denylist: DomainList::new(denied)?,
+            allowlist: DomainList::new(allowed)?,
+        })
+    }
+
+    /// Decides a domain by the most specific entry matching it: allowing
+    /// `*.tracker.example.com` lets it through despite a denylisted
+    /// `*.example.com`, while a denylisted `evil.tracker.example.com` is
+    /// still blocked. The allowlist wins when both lists match equally, and
+    /// the `runtime` rules win over these lists. Regex rules are only tried
+    /// once no domain entry matches. A blocked domain comes with the
+    /// addresses its entry gave, if any.
+    pub fn decide<'a>(
+        &'a self,
+        runtime: &'a Blocklists,
+        domain: &'a str,
+        qtype: u16,
+    ) -> Verdict<'a> {
+        let layers = [
+            (runtime, "runtime allowlist", "runtime denylist"),
+            (self, "allowlist", "denylist"),
+        ];
+        for (i, suffix) in suffixes(domain).enumerate() {
+            for (lists, allowlist, denylist) in layers {
+                if lists.allowlist.matches(suffix, i == 0, qtype).is_some() {
+                    return Verdict::Allowed(Reason {
+                        list: allowlist,
+                        name: Some(suffix),
+                    });
+                }
+                if let Some(addresses) =
+                    lists.denylist.matches(suffix, i == 0, qtype)
+                {
+                    let reason = Reason {
+                        list: denylist,
+                        name: Some(suffix),
+                    };
+                    return Verdict::Blocked(reason, addresses);
+                }
+            }
+        }
+        for (lists, allowlist, denylist) in layers {
+            if lists.allowlist.patterns.is_match(domain) {
+                return Verdict::Allowed(Reason {
+                    list: allowlist,
+                    name: None,
+                });
+            }
+            if lists.denylist.patterns.is_match(domain) {
+                let reason = Reason {
+                    list: denylist,
+                    name: None,
+                };
+                return Verdict::Blocked(reason, &[]);
+            }
+        }
+        Verdict::Unlisted
+    }
+}
+
+/// What the lists say about a domain.
+pub enum Verdict<'a> {
+    Allowed(Reason<'a>),
+    /// Blocked, to be answered with these addresses if there are any.
+    Blocked(Reason<'a>, &'a [IpAddr]),
+    Unlisted,
+}
+
+/// The list that decided a domain and the listed name that matched it,
+/// which regex rules don't have.
+pub struct Reason<'a> {
+    list: &'static str,
+    name: Option<&'a str>,
+}
+
+impl fmt::Display for Reason<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(name) => write!(f, "{} entry {}", self.list, name),
+            None => write!(f, "{} regex rule", self.list),
+        }
+    }
+}
+
+/// A domain from a list.
+pub(crate) struct Entry {
+    domain: String,
+    /// Query types the entry is limited to, or empty for every type.
+    qtypes: Vec<u16>,
+    /// Addresses to answer with, as in "example.com 10.0.0.5".
+    addresses: Vec<IpAddr>,
+}
+
+impl Entry {
+    fn new(domain: &str) -> Self {
+        Self {
+            domain: domain.to_owned(),
+            qtypes: Vec::new(),
+            addresses: Vec::new(),
+        }
+    }
+}
+
+/// Entries of one kind, with those for the same domain merged as they are
+/// read. Lists often overlap, and keeping each domain once as it streams in
+/// saves holding every line until the lists are indexed.
+#[derive(Default)]
+struct Entries {
+    rules: Rules,
+}
+
+impl Entries {
+    fn insert(&mut self, entry: Entry) {
+        let rule = Rule {
+            qtypes: entry.qtypes,
+            addresses: entry.addresses,
+        };
+        let rule = (!rule.is_plain()).then(|| Box::new(rule));
+        self.add(entry.domain.into_boxed_str(), rule);
+    }
+
+    fn add(&mut self, domain: Box<str>, rule: Option<Box<Rule>>) {
+        let merged = match self.rules.entry(domain) {
+            hash_map::Entry::Vacant(vacant) => {
+                vacant.insert(rule);
+                return;
+            }
+            hash_map::Entry::Occupied(occupied) => occupied.into_mut(),
+        };
+        let (mut ours, theirs) =
+            (merged.take().unwrap_or_default(), rule.unwrap_or_default());
+        // An entry for every type covers any narrower one.
+        if ours.qtypes.is_empty() || theirs.qtypes.is_empty() {
+            ours.qtypes.clear();
+        } else {
+            ours.qtypes.extend(theirs.qtypes);
+        }
+        for address in theirs.addresses {
+            if !ours.addresses.contains(&address) {
+                ours.addresses.push(address);
+            }
+        }
+        *merged = (!ours.is_plain()).then_some(ours);
+    }
+
+    /// Moves every entry of `other` into these.
+    fn append(&mut self, other: &mut Entries) {
+        if self.rules.is_empty() {
+            std::mem::swap(self, other);
+            return;
+        }
+        for (domain, rule) in other.rules.drain() {
+            self.add(domain, rule);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.rules.len()
+    }
+}
+
+/// Lists as read, before their domains are indexed for lookups.
+#[derive(Default)]
+pub struct ListEntries {
+    names: Entries,
+    wildcards: Entries,
+    subdomains: Entries,
+    patterns: Vec<String>,
+    /// Adblock `@@||example.com^` exceptions, which go to the allowlist
+    /// whichever list they appear in.
+    exceptions: Entries,
+    pub stats: LoadStats,
+}
+
+/// Lines of a list that gave no entries, by why they were skipped.
+#[derive(Clone, Copy, Default)]
+pub struct LoadStats {
+    /// Blank lines and comments.
+    pub comments: usize,
+    /// Lines with a name that can't be a domain or a regex that doesn't
+    /// compile.
+    pub malformed: usize,
+    /// Adblock rules with no DNS equivalent, such as element hiding.
+    pub unsupported: usize,
+}
+
+impl LoadStats {
+    fn add(&mut self, other: LoadStats) {
+        self.comments += other.comments;
+        self.malformed += other.malformed;
+        self.unsupported += other.unsupported;
+    }
+}
+
+impl ListEntries {
+    pub fn len(&self) -> usize {
+        self.names.len()
+            + self.wildcards.len()
+            + self.subdomains.len()
+            + self.patterns.len()
+            + self.exceptions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds a regex rule, skipping it with a warning if it doesn't compile
+    /// so a typo only costs that line.
+    fn add_pattern(&mut self, pattern: &str, path: &str, line: usize) {
+        match Regex::new(pattern) {
+            Ok(_) => self.patterns.push(pattern.to_owned()),
+            Err(e) => {
+                self.stats.malformed += 1;
+                warn!(path, line, error = %e, "skipping invalid regex");
+            }
+        }
+    }
+
+    fn extend(&mut self, mut other: ListEntries) {
+        self.names.append(&mut other.names);
+        self.wildcards.append(&mut other.wildcards);
+        self.subdomains.append(&mut other.subdomains);
+        self.patterns.append(&mut other.patterns);
+        self.exceptions.append(&mut other.exceptions);
+        self.stats.add(other.stats);
+    }
+}
+
+/// Reads one list from a file or, for an http(s) URL, from the network.
+async fn read_list(
+    config: &ListConfig,
+    source: &str,
+    format: ListFormat,
+) -> std::io::Result<ListEntries> {
+    let wildcards = config.implicit_wildcards;
+    let source = source.to_owned();
+    if !remote::is_url(&source) {
+        return tokio::task::spawn_blocking(move || {
+            read_denylist(&source, wildcards, format)
+        })
+        .await?;
+    }
+    let body = match config.fetcher.fetch(&source).await {
+        Ok(body) => body,
+        Err(e) if config.fail_open => {
+            warn!(error = %e, "skipping list");
+            return Ok(ListEntries::default());
+        }
+        Err(e) => return Err(e),
+    };
+    tokio::task::spawn_blocking(move || {
+        parse_list(&body[..], &source, wildcards, format)
+    })
+    .await?
+}
+
+pub fn read_denylist(
+    path: &str,
+    implicit_wildcards: bool,
+    format: ListFormat,
+) -> std::io::Result<ListEntries> {
+    let file = File::open(path).map_err(|e| {
+        std::io::Error::new(e.kind(), format!("Failed to read {}: {}", path, e))
+    })?;
+    parse_list(
+        std::io::BufReader::new(file),
+        path,
+        implicit_wildcards,
+        format,
+    )
+}
+
+pub fn read_regex_list(path: &Path) -> std::io::Result<ListEntries> {
+    let file = File::open(path).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("Failed to read {}: {}", path.display(), e),
+        )
+    })?;
+    parse_regex_list(std::io::BufReader::new(file), &path.to_string_lossy())
+}
+
+/// Parses a file of regex rules, one per line and written without the
+/// slashes of `/regex/` list entries. Lines starting with '#' are comments.
+/// `path` names the file in warnings.
+pub fn parse_regex_list(
+    reader: impl BufRead,
+    path: &str,
+) -> std::io::Result<ListEntries> {
+    let mut entries = ListEntries::default();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            entries.stats.comments += 1;
+            continue;
+        }
+        entries.add_pattern(line, path, number + 1);
+    }
+    Ok(entries)
+}
+
+/// Parses a list of domains, `/regex/` rules, hosts-file lines and adblock
+/// rules, with `implicit_wildcards` making every domain cover its subdomains
+/// whether or not it is written as a wildcard. Domain lines may end in record
+/// types, as in "example.com AAAA", to only match queries of those types,
+/// and in addresses to answer with, as in "example.com 10.0.0.5". Addresses
+/// at the start of hosts-file lines are ignored, since lists give 0.0.0.0 or
+/// 127.0.0.1 there just to fill the column. Each line's format is told from
+/// how it looks unless `format` fixes one. `path` names the list in warnings.
+pub fn parse_list(
+    reader: impl BufRead,
+    path: &str,
+    implicit_wildcards: bool,
+    format: ListFormat,
+) -> std::io::Result<ListEntries> {
+    let mut entries = ListEntries::default();
+
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.starts_with(['!', '[', '#']) {
+            // Adblock comments and headers such as "[Adblock Plus 2.0]", and
+            // comment lines, banners such as "## Ads" or "#####" included.
+            entries.stats.comments += 1;
+            continue;
+        }
+        // Element hiding rules, such as "example.com##.ad", mean nothing to
+        // DNS, and would otherwise be cut at the '#' and read as a domain.
+        // Their marker follows the domains directly, while a comment after
+        // an entry is set off by whitespace.
+        let cosmetic = COSMETIC_MARKERS
+            .iter()
+            .filter_map(|marker| line.find(marker))
+            .min()
+            .is_some_and(|at| !line[..at].contains(char::is_whitespace));
+        if cosmetic {
+            entries.stats.unsupported += 1;
+            continue;
+        }
+        // Regex rules come before comments are cut off, since '#' and ';'
+        // can be part of a pattern.
+        if let Some(pattern) = line
+            .strip_prefix('/')
+            .and_then(|line| line.strip_suffix('/'))
+            .filter(|_| format != ListFormat::Hosts)
+        {
+            entries.add_pattern(pattern, path, number + 1);
+            continue;
+        }
+        // Comments start with '#', or with ';' as in zone files.
+        let line = match line.split_once(['#', ';']) {
+            Some((before_comment, _)) => before_comment,
+            None => line,
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            entries.stats.comments += 1;
+            continue;
+        }
+        let line = line.to_lowercase();
+        let rule = match format {
+            ListFormat::Auto => parse_adblock_rule(&line),
+            // Lines that aren't "||domain^" rules, such as "example.com",
+            // are URL filters in adblock lists.
+            ListFormat::Abp => Some(
+                parse_adblock_rule(&line).unwrap_or(AdblockRule::Unsupported),
+            ),
+            ListFormat::Domains | ListFormat::Hosts => None,
+        };
+        match rule {
+            Some(AdblockRule::Block(domain)) => {
+                entries.wildcards.insert(Entry::new(domain));
+                continue;
+            }
+            Some(AdblockRule::Allow(domain)) => {
+                entries.exceptions.insert(Entry::new(domain));
+                continue;
+            }
+            Some(AdblockRule::Unsupported) => {
+                entries.stats.unsupported += 1;
+                continue;
+            }
+            None => {}
+        }
+        let mut tokens: Vec<&str> = line.split_whitespace().collect();
+        let starts_with_address =
+            tokens.first().is_some_and(|token| is_address(token));
+        if format == ListFormat::Hosts && !starts_with_address {
+            debug!(path, line = number + 1, "skipping line without an address");
+            entries.stats.malformed += 1;
+            continue;
+        }
+        // Hosts-file lines map an address to one or more host names.
+        let hosts_line = starts_with_address && format != ListFormat::Domains;
+        if hosts_line {
+            tokens.remove(0);
+        }
+        // Other lines may end in the record types they are limited to and
+        // the addresses to answer with.
+        let mut qtypes = Vec::new();
+        let mut addresses = Vec::new();
+        while !hosts_line && tokens.len() > 1 {
+            let token = tokens[tokens.len() - 1];
+            if let Some(qtype) = parse_qtype(token) {
+                qtypes.push(qtype);
+            } else if let Ok(address) = token.parse::<IpAddr>() {
+                addresses.insert(0, address);
+            } else {
+                break;
+            }
+            tokens.pop();
+        }
+        let new_entry = |domain: &str| Entry {
+            domain: domain.to_owned(),
+            qtypes: qtypes.clone(),
+            addresses: addresses.clone(),
+        };
+        let mut malformed = false;
+        for entry in tokens {
+            // Besides the local names, some lists map an address to itself,
+            // as in "0.0.0.0 0.0.0.0".
+            if hosts_line
+                && (LOCAL_HOST_NAMES.contains(&entry) || is_address(entry))
+            {
+                continue;
+            }
+            if !is_domain(
+                entry.trim_start_matches("*.").trim_start_matches('.'),
+            ) {
+                debug!(
+                    path,
+                    line = number + 1,
+                    entry,
+                    "skipping malformed entry"
+                );
+                malformed = true;
+                continue;
+            }
+            if let Some(domain) = entry.strip_prefix("*.") {
+                entries.subdomains.insert(new_entry(domain));
+                continue;
+            }
+            // A top-level domain on its own, as in "zip", stands for
+            // everything under it even with wildcards off: nobody lists a
+            // TLD to block just the name.
+            let (list, domain) = match entry.strip_prefix('.') {
+                Some(domain) => (&mut entries.wildcards, domain),
+                None if implicit_wildcards || !entry.contains('.') => {
+                    (&mut entries.wildcards, entry)
+                }
+                None => (&mut entries.names, entry),
+            };
+            list.insert(new_entry(domain));
+        }
+        if malformed {
+            entries.stats.malformed += 1;
+        }
+    }
+
+    let stats = entries.stats;
+    if stats.malformed > 0 {
+        warn!(path, lines = stats.malformed, "skipped malformed lines");
+    }
+    if stats.unsupported > 0 {
+        warn!(
+            path,
+            rules = stats.unsupported,
+            "skipped unsupported adblock rules"
+        );
+    }
+    Ok(entries)
+}
+
+/// Whether a list entry can be a domain name. Anything but ASCII letters,
+/// digits, hyphens and underscores in a label, such as a stray URL or HTML
+/// from a list that failed to download properly, rules it out.
+pub(crate) fn is_domain(name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label.chars().all(|c| {
+                    !c.is_ascii()
+                        || c.is_ascii_alphanumeric()
+                        || c == '-'
+                        || c == '_'
+                })
+        })
+}
+
+/// Whether a hosts-file token is an IP address. Link-local addresses may
+/// carry a zone, as in "fe80::1%lo0".
+fn is_address(token: &str) -> bool {
+    let address = token.split('%').next().unwrap_or(token);
+    address.parse::<IpAddr>().is_ok()
+}
+
+/// An adblock-style network rule, as published by lists like OISD and the
+/// AdGuard DNS filter.
+enum AdblockRule<'a> {
+    /// `||example.com^`: block the domain and its subdomains.
+    Block(&'a str),
+    /// `@@||example.com^`: exempt the domain and its subdomains.
+    Allow(&'a str),
+    /// A rule with modifiers or a path, which DNS can't honor faithfully.
+    Unsupported,
+}
+
+/// Parses `line` as an adblock rule, or returns `None` if it isn't one.
+fn parse_adblock_rule(line: &str) -> Option<AdblockRule<'_>> {
+    let (exception, rule) = match line.strip_prefix("@@") {
+        Some(rule) => (true, rule),
+        None => (false, line),
+    };
+    let Some(rule) = rule.strip_prefix("||") else {
+        return exception.then_some(AdblockRule::Unsupported);
+    };
+    match rule.strip_suffix('^') {
+        Some(domain)
+            if !domain.is_empty()
+                && !domain.contains(['/', '*', '^', '$', '|']) =>
+        {
+            Some(if exception {
+                AdblockRule::Allow(domain)
+            } else {
+                AdblockRule::Block(domain)
+            })
+        }
+        _ => Some(AdblockRule::Unsupported),
+    }
+}
+
+/// Yields `domain` and each of its parent domains, most specific first,
+/// down to the top-level domain, so that an entry like ".zip" blocks a
+/// whole TLD.
+fn suffixes(domain: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(domain), |suffix| {
+        suffix.split_once('.').map(|(_, parent)| parent)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{TYPE_A, TYPE_AAAA};
+
+    fn lists(denied: &str, allowed: &str, wildcards: bool) -> Blocklists {
+        let parse = |text: &str| {
+            parse_list(text.as_bytes(), "test", wildcards, ListFormat::Auto)
+                .unwrap()
+        };
+        Blocklists::build(parse(denied), parse(allowed)).unwrap()
+    }
+
+    fn empty() -> Blocklists {
+        lists("", "", false)
+    }
+
+    /// Whether `lists` block a query for `name` of type `qtype`.
+    fn blocks(lists: &Blocklists, name: &str, qtype: u16) -> bool {
+        matches!(lists.decide(&empty(), name, qtype), Verdict::Blocked(..))
+    }
+
+    #[test]
+    fn dotted_tld_entry_blocks_the_whole_tld() {
+        for wildcards in [false, true] {
+            let lists = lists(".zip", "", wildcards);
+            assert!(blocks(&lists, "zip", TYPE_A));
+            assert!(blocks(&lists, "anything.zip", TYPE_A));
+            assert!(blocks(&lists, "a.b.zip", TYPE_A));
+            assert!(!blocks(&lists, "zip.example", TYPE_A));
+        }
+    }
+
+    #[test]
+    fn plain_tld_entry_blocks_the_whole_tld() {
+        for wildcards in [false, true] {
+            let lists = lists("zip", "", wildcards);
+            assert!(blocks(&lists, "zip", TYPE_A));
+            assert!(blocks(&lists, "anything.zip", TYPE_A));
+            assert!(!blocks(&lists, "zipper.example", TYPE_A));
+        }
+    }
+
+    #[test]
+    fn allowlist_makes_exceptions_within_a_blocked_tld() {
+        let lists = lists(".zip", "ok.zip", false);
+        assert!(blocks(&lists, "bad.zip", TYPE_A));
+        assert!(!blocks(&lists, "ok.zip", TYPE_A));
+    }
+
+    #[test]
+    fn subdomain_only_tld_entry_spares_the_tld() {
+        let lists = lists("*.mov", "", false);
+        assert!(!blocks(&lists, "mov", TYPE_A));
+        assert!(blocks(&lists, "x.mov", TYPE_A));
+        assert!(blocks(&lists, "x.mov", TYPE_AAAA));
+    }
+
+    /// What `lists`, under the `runtime` rules, say about a query, as the
+    /// query log would put it.
+    fn verdict(
+        lists: &Blocklists,
+        runtime: &Blocklists,
+        name: &str,
+        qtype: u16,
+    ) -> String {
+        match lists.decide(runtime, name, qtype) {
+            Verdict::Allowed(reason) => format!("allowed by {reason}"),
+            Verdict::Blocked(reason, _) => format!("blocked by {reason}"),
+            Verdict::Unlisted => "unlisted".to_owned(),
+        }
+    }
+
+    #[test]
+    fn allowlist_wins_a_tie() {
+        let lists = lists("x.example\nz.example", "x.example", false);
+        assert_eq!(
+            verdict(&lists, &empty(), "x.example", TYPE_A),
+            "allowed by allowlist entry x.example"
+        );
+        assert_eq!(
+            verdict(&lists, &empty(), "z.example", TYPE_A),
+            "blocked by denylist entry z.example"
+        );
+        assert_eq!(verdict(&lists, &empty(), "y.example", TYPE_A), "unlisted");
+    }
+
+    #[test]
+    fn most_specific_entry_wins() {
+        let lists = lists(
+            "*.example.com\nevil.tracker.example.com",
+            "*.tracker.example.com",
+            false,
+        );
+        assert_eq!(
+            verdict(&lists, &empty(), "ads.example.com", TYPE_A),
+            "blocked by denylist entry example.com"
+        );
+        assert_eq!(
+            verdict(&lists, &empty(), "cdn.tracker.example.com", TYPE_A),
+            "allowed by allowlist entry tracker.example.com"
+        );
+        assert_eq!(
+            verdict(&lists, &empty(), "evil.tracker.example.com", TYPE_A),
+            "blocked by denylist entry evil.tracker.example.com"
+        );
+    }
+
+    #[test]
+    fn wildcard_entry_forms() {
+        let lists = lists(".dot.example\n*.star.example", "", false);
+        assert!(blocks(&lists, "dot.example", TYPE_A));
+        assert!(blocks(&lists, "x.dot.example", TYPE_A));
+        assert!(!blocks(&lists, "star.example", TYPE_A));
+        assert!(blocks(&lists, "x.star.example", TYPE_A));
+        assert!(blocks(&lists, "x.y.star.example", TYPE_A));
+    }
+
+    #[test]
+    fn tells_element_hiding_rules_from_banners() {
+        let text = "## Ads ##\n\
+                    #####\n\
+                    example.com##.ad\n\
+                    example.com#@#.ad\n\
+                    example.net ## not element hiding";
+        let entries =
+            parse_list(text.as_bytes(), "test", false, ListFormat::Auto)
+                .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries.stats.comments, 2);
+        assert_eq!(entries.stats.unsupported, 2);
+    }
+
+    fn parse(text: &str, format: ListFormat) -> ListEntries {
+        parse_list(text.as_bytes(), "test", false, format).unwrap()
+    }
+
+    /// The addresses `lists` answer a blocked query with.
+    fn addresses(lists: &Blocklists, name: &str, qtype: u16) -> Vec<IpAddr> {
+        match lists.decide(&empty(), name, qtype) {
+            Verdict::Blocked(_, addresses) => addresses.to_vec(),
+            _ => panic!("{name} isn't blocked"),
+        }
+    }
+
+    #[test]
+    fn reads_hosts_files() {
+        let text = "127.0.0.1 localhost\n\
+                    ::1 ip6-localhost ip6-loopback\n\
+                    0.0.0.0 0.0.0.0\n\
+                    0.0.0.0 ads.example tracker.example\n\
+                    0.0.0.0\ttabbed.example\n\
+                    127.0.0.1\t\tlocalhost\n\
+                    fe80::1%lo0 link.example";
+        for format in [ListFormat::Auto, ListFormat::Hosts] {
+            let entries = parse(text, format);
+            assert_eq!(entries.len(), 4);
+            assert_eq!(entries.stats.malformed, 0);
+            let lists =
+                Blocklists::build(entries, ListEntries::default()).unwrap();
+            assert!(blocks(&lists, "tracker.example", TYPE_A));
+            assert!(blocks(&lists, "tabbed.example", TYPE_A));
+            assert!(blocks(&lists, "link.example", TYPE_A));
+            assert!(!blocks(&lists, "localhost", TYPE_A));
+            // The address fills the column rather than being an answer.
+            assert!(addresses(&lists, "ads.example", TYPE_A).is_empty());
+        }
+    }
+
+    #[test]
+    fn a_fixed_format_reads_every_line_one_way() {
+        let text = "0.0.0.0 ads.example\n\
+                    tracker.example 10.0.0.5\n\
+                    ||adblock.example^";
+        let hosts = parse(text, ListFormat::Hosts);
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts.stats.malformed, 2);
+
+        let domains = parse(text, ListFormat::Domains);
+        assert_eq!(domains.stats.malformed, 1);
+        let lists = Blocklists::build(domains, ListEntries::default()).unwrap();
+        assert!(blocks(&lists, "ads.example", TYPE_A));
+        assert_eq!(
+            addresses(&lists, "tracker.example", TYPE_A),
+            ["10.0.0.5".parse::<IpAddr>().unwrap()]
+        );
+        assert!(!blocks(&lists, "adblock.example", TYPE_A));
+    }
+
+    #[test]
+    fn adblock_rules_cover_the_domain_and_its_subdomains() {
+        for format in [ListFormat::Auto, ListFormat::Abp] {
+            let entries =
+                parse("||tracker.net^\n||ads.example^$third-party", format);
+            assert_eq!(entries.stats.unsupported, 1);
+            let lists =
+                Blocklists::build(entries, ListEntries::default()).unwrap();
+            assert!(blocks(&lists, "tracker.net", TYPE_A));
+            assert!(blocks(&lists, "sub.tracker.net", TYPE_A));
+            assert!(!blocks(&lists, "nottracker.net", TYPE_A));
+        }
+    }
+
+    #[test]
+    fn adblock_lists_only_follow_domain_rules() {
+        let entries = parse(
+            "||ads.example^\nexample.com\n0.0.0.0 x.example",
+            ListFormat::Abp,
+        );
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries.stats.unsupported, 2);
+    }
+
+    #[test]
+    fn adblock_exceptions_allow_in_any_list() {
+        let lists = lists("||ads.example^\n@@||ok.ads.example^", "", false);
+        assert!(blocks(&lists, "x.ads.example", TYPE_A));
+        assert!(!blocks(&lists, "ok.ads.example", TYPE_A));
+        assert!(!blocks(&lists, "x.ok.ads.example", TYPE_A));
+    }
+
+    #[test]
+    fn plain_entries_cover_subdomains_unless_wildcards_are_off() {
+        let wildcards = lists("ads.example", "", true);
+        assert!(blocks(&wildcards, "ads.example", TYPE_A));
+        assert!(blocks(&wildcards, "x.ads.example", TYPE_A));
+
+        let names = lists("ads.example", "", false);
+        assert!(blocks(&names, "ads.example", TYPE_A));
+        assert!(!blocks(&names, "x.ads.example", TYPE_A));
+        assert!(!blocks(&names, "example", TYPE_A));
+    }
+
+    #[test]
+    fn entries_limited_to_record_types() {
+        let lists = lists("v6.example AAAA\nboth.example A aaaa", "", false);
+        assert!(blocks(&lists, "v6.example", TYPE_AAAA));
+        assert!(!blocks(&lists, "v6.example", TYPE_A));
+        assert!(blocks(&lists, "both.example", TYPE_A));
+        assert!(blocks(&lists, "both.example", TYPE_AAAA));
+    }
+
+    #[test]
+    fn entries_give_addresses_to_answer_with() {
+        let lists = lists(
+            "nas.example 10.0.0.5\n\
+             nas.example 10.0.0.6 fd00::5\n\
+             nas.example 10.0.0.5",
+            "",
+            false,
+        );
+        let expected: Vec<IpAddr> = ["10.0.0.5", "10.0.0.6", "fd00::5"]
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect();
+        assert_eq!(addresses(&lists, "nas.example", TYPE_A), expected);
+    }
+
+    #[test]
+    fn regex_rules_apply_when_no_domain_entry_does() {
+        let lists = lists("/^ad[0-9]+\\./", "ad2.example", false);
+        assert_eq!(
+            verdict(&lists, &empty(), "AD1.example", TYPE_A),
+            "blocked by denylist regex rule"
+        );
+        assert_eq!(
+            verdict(&lists, &empty(), "ad2.example", TYPE_A),
+            "allowed by allowlist entry ad2.example"
+        );
+        assert_eq!(
+            verdict(&lists, &empty(), "ads.example", TYPE_A),
+            "unlisted"
+        );
+    }
+
+    #[test]
+    fn reads_regex_files_one_rule_per_line() {
+        let text = "# trackers\n\
+                    ^ad[0-9]+\\.\n\
+                    \n\
+                    (unclosed\n\
+                    ^metrics\\.";
+        let entries = parse_regex_list(text.as_bytes(), "test").unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries.stats.comments, 2);
+        assert_eq!(entries.stats.malformed, 1);
+        let lists = Blocklists::build(entries, ListEntries::default()).unwrap();
+        assert!(blocks(&lists, "ad1.example", TYPE_A));
+        assert!(blocks(&lists, "metrics.example", TYPE_A));
+        assert!(!blocks(&lists, "example.com", TYPE_A));
+    }
+
+    #[test]
+    fn counts_unsupported_and_malformed_lines() {
+        let text = "example.com##.ad\n\
+                    example.com#@#.ad\n\
+                    ||example.com^$third-party\n\
+                    ||example.com/ads^\n\
+                    @@/path\n\
+                    <html>\n\
+                    http://example.com/list.txt\n\
+                    /(unclosed/\n\
+                    ok.example bad!name";
+        let entries = parse(text, ListFormat::Auto);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries.stats.unsupported, 5);
+        assert_eq!(entries.stats.malformed, 4);
+    }
+
+    #[test]
+    fn runtime_rules_win_over_the_lists() {
+        let runtime = lists("y.example", "x.example", false);
+        let lists = lists("x.example", "y.example", false);
+        assert_eq!(
+            verdict(&lists, &runtime, "x.example", TYPE_A),
+            "allowed by runtime allowlist entry x.example"
+        );
+        assert_eq!(
+            verdict(&lists, &runtime, "y.example", TYPE_A),
+            "blocked by runtime denylist entry y.example"
+        );
+    }
+
+    #[test]
+    fn an_entry_for_every_type_covers_narrower_ones() {
+        let lists = lists("x.example AAAA\nx.example", "", false);
+        assert!(blocks(&lists, "x.example", TYPE_A));
+    }
+
+    #[test]
+    fn large_generated_lists_decide_like_small_ones() {
+        let denied: String = (0..5000)
+            .map(|i| format!("host{i}.tracker{}.example\n", i % 50))
+            .collect();
+        // Every tenth denied name is allowed again.
+        let allowed: String = (0..5000)
+            .step_by(10)
+            .map(|i| format!("host{i}.tracker{}.example\n", i % 50))
+            .collect();
+        let lists = lists(&denied, &allowed, false);
+        for i in 0..5000 {
+            let name = format!("host{i}.tracker{}.example", i % 50);
+            assert_eq!(blocks(&lists, &name, TYPE_A), i % 10 != 0, "{name}");
+            let miss = format!("host{}.tracker{}.example", i + 5000, i % 50);
+            assert!(!blocks(&lists, &miss, TYPE_A), "{miss}");
+            let sibling = format!("host{i}.tracker{}.example", (i + 1) % 50);
+            assert!(!blocks(&lists, &sibling, TYPE_A), "{sibling}");
+        }
+    }
+
+    #[test]
+    fn skips_comments_and_banners() {
+        let text = "# comment\n\
+                    ## Ads ##\n\
+                    #####\n\
+                    ! adblock comment\n\
+                    [Adblock Plus 2.0]\n\
+                    \n\
+                    a.example # trailing comment\n\
+                    b.example ; zone file comment\n\
+                    c.example ## not element hiding";
+        let entries = parse(text, ListFormat::Auto);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries.stats.comments, 6);
+        assert_eq!(entries.stats.unsupported, 0);
+    }
+
+    #[test]
+    fn regex_rules_keep_comment_characters() {
+        let lists = lists("/^ad[0-9];?\\./\n/^x#[0-9]+\\./", "", false);
+        assert!(blocks(&lists, "ad1.example", TYPE_A));
+        assert!(blocks(&lists, "ad1;.example", TYPE_A));
+        assert!(blocks(&lists, "x#1.example", TYPE_A));
+        assert!(!blocks(&lists, "ad.example", TYPE_A));
+    }
+}
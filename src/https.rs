@@ -0,0 +1,126 @@
+//! DNS-over-HTTPS listener (RFC 8484).
+
+use crate::{
+    cache::min_answer_ttl,
+    dns::{parse_dns_question, DNS_MESSAGE},
+    dnstap::Transport,
+    server::{accept, handle_request, Service, TCP_IDLE_TIMEOUT},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{CACHE_CONTROL, CONTENT_TYPE},
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use tokio::{net::TcpListener, time::timeout};
+use tokio_rustls::TlsAcceptor;
+use tracing::{error_span, Instrument};
+
+const DNS_QUERY_PATH: &str = "/dns-query";
+
+/// DNS messages are capped at 64 KiB by their TCP length prefix, so larger
+/// bodies can't be valid queries.
+const MAX_QUERY_SIZE: usize = 65535;
+
+pub async fn serve_https(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    service: Arc<Service>,
+) -> Result<(), std::io::Error> {
+    loop {
+        let (stream, peer) = accept(|| listener.accept()).await;
+        if !service.admits(peer.ip()) {
+            continue;
+        }
+        let acceptor = acceptor.clone();
+        let service = Arc::clone(&service);
+        let span = error_span!("https", client = %peer);
+        tokio::spawn(async move {
+            let Ok(Ok(stream)) =
+                timeout(TCP_IDLE_TIMEOUT, acceptor.accept(stream)).await
+            else {
+                return;
+            };
+            // HTTP/2 requests run on tasks of their own, so each request is
+            // instrumented rather than just this connection task.
+            let handler = service_fn(|request| {
+                handle_https_request(request, peer, Arc::clone(&service))
+                    .instrument(span.clone())
+            });
+            let _ = auto::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), handler)
+                .await;
+        });
+    }
+}
+
+async fn handle_https_request(
+    request: Request<Incoming>,
+    client: SocketAddr,
+    service: Arc<Service>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    if request.uri().path() != DNS_QUERY_PATH {
+        return Ok(status(StatusCode::NOT_FOUND));
+    }
+    let query = match *request.method() {
+        Method::GET => {
+            let encoded = request.uri().query().and_then(|query| {
+                query
+                    .split('&')
+                    .find_map(|param| param.strip_prefix("dns="))
+            });
+            match encoded.map(|encoded| URL_SAFE_NO_PAD.decode(encoded)) {
+                Some(Ok(query)) => query,
+                _ => return Ok(status(StatusCode::BAD_REQUEST)),
+            }
+        }
+        Method::POST => {
+            let content_type = request
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok());
+            if content_type != Some(DNS_MESSAGE) {
+                return Ok(status(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+            }
+            match Limited::new(request.into_body(), MAX_QUERY_SIZE)
+                .collect()
+                .await
+            {
+                Ok(body) => body.to_bytes().to_vec(),
+                Err(_) => return Ok(status(StatusCode::PAYLOAD_TOO_LARGE)),
+            }
+        }
+        _ => return Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+    };
+
+    let _query = service.track_query();
+    // A response where a query belongs is the client's mistake, not a
+    // failure to reach the upstream.
+    if parse_dns_question(&query).is_err() || query[2] & 0x80 != 0 {
+        return Ok(status(StatusCode::BAD_REQUEST));
+    }
+    let Ok(answer) =
+        handle_request(&query, client, Transport::Https, &service).await
+    else {
+        return Ok(status(StatusCode::BAD_GATEWAY));
+    };
+
+    let mut response = Response::builder().header(CONTENT_TYPE, DNS_MESSAGE);
+    if let Some(ttl) = min_answer_ttl(&answer) {
+        response = response.header(CACHE_CONTROL, format!("max-age={}", ttl));
+    }
+    Ok(response.body(Full::new(Bytes::from(answer))).unwrap())
+}
+
+fn status(code: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::default());
+    *response.status_mut() = code;
+    response
+}
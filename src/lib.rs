@@ -0,0 +1,31 @@
+//! A DNS proxy that answers queries for listed domains itself and forwards
+//! the rest upstream.
+//!
+//! [`server::Server`] answers queries on sockets its caller provides, while
+//! [`dns`] and [`filter`] cover reading and answering DNS messages and
+//! deciding from the lists on their own.
+
+pub mod args;
+pub mod config;
+pub mod dns;
+pub mod filter;
+pub mod server;
+
+mod acl;
+mod cache;
+mod control;
+mod dnstap;
+mod doq;
+mod dot;
+mod https;
+mod local;
+mod metrics;
+mod policy;
+mod querylog;
+mod ratelimit;
+mod remote;
+mod singleflight;
+mod tls;
+mod udp;
+mod upstream;
+mod watch;
@@ -0,0 +1,234 @@
+//! Records the filter answers itself, such as names on the home network,
+//! read from the file given with --local-records:
+//!
+//! ```text
+//! A      nas.home      192.168.1.40
+//! AAAA   nas.home      fd00::40
+//! CNAME  media.home    nas.home
+//! TXT    key.home      "some value"
+//! A      *.lab.home    192.168.1.50
+//! ```
+//!
+//! A name only matches its own records, while a `*.` entry matches every
+//! name below it that has none, the nearest entry winning. Queries for a
+//! name with records never go upstream: those of a type it has no records
+//! of get an empty answer. CNAMEs are followed as long as they point at
+//! other local names.
+
+use crate::{
+    dns::{
+        encode_name, Record, CLASS_IN, TYPE_A, TYPE_AAAA, TYPE_CNAME, TYPE_TXT,
+    },
+    filter::is_domain,
+};
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+/// CNAMEs followed for one answer before giving up on a loop.
+const MAX_CNAME_CHAIN: usize = 8;
+
+#[derive(Default)]
+pub struct LocalRecords {
+    names: HashMap<String, Vec<LocalRecord>>,
+    /// Records of `*.` entries, by the name after the `*.`.
+    wildcards: HashMap<String, Vec<LocalRecord>>,
+}
+
+struct LocalRecord {
+    rtype: u16,
+    data: Vec<u8>,
+    /// For a CNAME, the name it points at.
+    target: Option<String>,
+}
+
+impl LocalRecords {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let mut records = Self::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            records.add(line).map_err(|e| {
+                format!(
+                    "Invalid record on line {} of {}: {}",
+                    number + 1,
+                    path.display(),
+                    e
+                )
+            })?;
+        }
+        Ok(records)
+    }
+
+    fn add(&mut self, line: &str) -> Result<(), String> {
+        let (rtype, rest) = split_word(line);
+        let (name, value) = split_word(rest);
+        let name = name.to_lowercase();
+        let name = name.strip_suffix('.').unwrap_or(&name);
+        let (set, owner) = match name.strip_prefix("*.") {
+            Some(parent) => (&mut self.wildcards, parent),
+            None => (&mut self.names, name),
+        };
+        if !is_domain(owner) || !owner.is_ascii() {
+            return Err(format!("invalid name {:?}", name));
+        }
+        let record = match rtype.to_ascii_uppercase().as_str() {
+            "A" => {
+                let ip: Ipv4Addr = value
+                    .parse()
+                    .map_err(|_| format!("invalid IPv4 address {:?}", value))?;
+                LocalRecord {
+                    rtype: TYPE_A,
+                    data: ip.octets().to_vec(),
+                    target: None,
+                }
+            }
+            "AAAA" => {
+                let ip: Ipv6Addr = value
+                    .parse()
+                    .map_err(|_| format!("invalid IPv6 address {:?}", value))?;
+                LocalRecord {
+                    rtype: TYPE_AAAA,
+                    data: ip.octets().to_vec(),
+                    target: None,
+                }
+            }
+            "CNAME" => {
+                let target = value.to_lowercase();
+                let target = target.strip_suffix('.').unwrap_or(&target);
+                if !is_domain(target) || !target.is_ascii() {
+                    return Err(format!("invalid target {:?}", value));
+                }
+                LocalRecord {
+                    rtype: TYPE_CNAME,
+                    data: encode_name(target),
+                    target: Some(target.to_owned()),
+                }
+            }
+            "TXT" => LocalRecord {
+                rtype: TYPE_TXT,
+                data: parse_txt(value)?,
+                target: None,
+            },
+            _ => return Err(format!("unsupported record type {:?}", rtype)),
+        };
+        let records = set.entry(owner.to_owned()).or_default();
+        // A CNAME stands in for every other record of its name (RFC 1034
+        // 3.6.2), so it can't have company.
+        if record.rtype == TYPE_CNAME && !records.is_empty()
+            || records.iter().any(|record| record.rtype == TYPE_CNAME)
+        {
+            return Err(format!("{} has a CNAME and other records", name));
+        }
+        records.push(record);
+        Ok(())
+    }
+
+    /// Number of names and `*.` entries with records.
+    pub fn len(&self) -> usize {
+        self.names.len() + self.wildcards.len()
+    }
+
+    /// Whether queries for `name` are answered here.
+    pub fn contains(&self, name: &str) -> bool {
+        self.find(name).is_some()
+    }
+
+    /// The answer to a query for `name`, if it is answered here: its records
+    /// of type `qtype`, or the CNAMEs leading to those. The answer is empty
+    /// if the name has no such records.
+    pub fn answer(
+        &self,
+        name: &str,
+        qtype: u16,
+        ttl: u32,
+    ) -> Option<Vec<Record>> {
+        let mut records = self.find(name)?;
+        let mut owner = None;
+        let record = |owner: &Option<String>, local: &LocalRecord| Record {
+            owner: owner.clone(),
+            rtype: local.rtype,
+            class: CLASS_IN,
+            ttl,
+            data: local.data.clone(),
+        };
+        let mut answers = Vec::new();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let cname = records
+                .iter()
+                .find(|local| local.rtype == TYPE_CNAME && qtype != TYPE_CNAME);
+            let Some(cname) = cname else {
+                answers.extend(
+                    records
+                        .iter()
+                        .filter(|local| local.rtype == qtype)
+                        .map(|local| record(&owner, local)),
+                );
+                break;
+            };
+            answers.push(record(&owner, cname));
+            let Some(target) = &cname.target else {
+                break;
+            };
+            let Some(next) = self.find(target) else {
+                break;
+            };
+            owner = Some(target.clone());
+            records = next;
+        }
+        Some(answers)
+    }
+
+    fn find(&self, name: &str) -> Option<&[LocalRecord]> {
+        if let Some(records) = self.names.get(name) {
+            return Some(records);
+        }
+        let mut parent = name;
+        while let Some((_, rest)) = parent.split_once('.') {
+            if let Some(records) = self.wildcards.get(rest) {
+                return Some(records);
+            }
+            parent = rest;
+        }
+        None
+    }
+}
+
+/// Splits off the first whitespace-separated word.
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (text, ""),
+    }
+}
+
+/// Reads TXT record data as its character strings, each either quoted,
+/// as in "some value", or a single word.
+fn parse_txt(mut value: &str) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    while !value.is_empty() {
+        let (text, rest) = match value.strip_prefix('"') {
+            Some(quoted) => quoted
+                .split_once('"')
+                .ok_or_else(|| "unterminated quote".to_owned())?,
+            None => split_word(value),
+        };
+        if text.len() > 255 {
+            return Err("strings longer than 255 bytes must be split".into());
+        }
+        data.push(text.len() as u8);
+        data.extend_from_slice(text.as_bytes());
+        value = rest.trim_start();
+    }
+    if data.is_empty() {
+        return Err("missing TXT data".to_owned());
+    }
+    Ok(data)
+}
@@ -0,0 +1,615 @@
+//! Prometheus metrics, served as plain text over HTTP.
+
+use crate::{
+    acl::AccessList,
+    args::UpstreamStrategy,
+    dns::QTYPE_NAMES,
+    dnstap::Dnstap,
+    ratelimit::RateLimiter,
+    server::{accept, Service},
+    upstream::Upstreams,
+};
+use clap::ValueEnum;
+use http_body_util::Full;
+use hyper::{
+    body::{Bytes, Incoming},
+    header::CONTENT_TYPE,
+    service::service_fn,
+    Request, Response, StatusCode,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use std::{
+    convert::Infallible,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::net::TcpListener;
+use tracing::info;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] =
+    [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// Media type of the Prometheus text exposition format.
+const TEXT_FORMAT: &str = "text/plain; version=0.0.4";
+
+#[derive(Default)]
+pub struct Metrics {
+    pub queries: AtomicU64,
+    pub blocked: AtomicU64,
+    pub would_block: AtomicU64,
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+    pub upstream_timeouts: AtomicU64,
+    pub upstream_retries: AtomicU64,
+    pub upstream_retry_successes: AtomicU64,
+    pub upstreams_busy: AtomicU64,
+    pub stale_answers: AtomicU64,
+    pub deduplicated: AtomicU64,
+    pub rate_limited: AtomicU64,
+    pub unauthorized: AtomicU64,
+    pub forwarded: AtomicU64,
+    pub parse_failures: AtomicU64,
+    pub unsupported_opcodes: AtomicU64,
+    /// Queries by record type, in the order of `QTYPE_NAMES` with the other
+    /// types counted last.
+    queries_by_type: [AtomicU64; QTYPE_NAMES.len() + 1],
+    latency: Histogram,
+}
+
+/// A latency histogram whose buckets count observations at or below their
+/// bound, like Prometheus expects, rather than only those in between.
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Writes the series of the histogram, each carrying `labels` (e.g.,
+    /// `upstream="1.1.1.1:53"`) if there are any.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let count = self.count.load(Ordering::Relaxed);
+        let bounds = LATENCY_BUCKETS.iter().map(ToString::to_string);
+        let counts = self.buckets.iter().map(|b| b.load(Ordering::Relaxed));
+        for (bound, bucket) in
+            bounds.chain(["+Inf".to_owned()]).zip(counts.chain([count]))
+        {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, bound, bucket
+            );
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, count);
+    }
+}
+
+impl Metrics {
+    pub fn observe_latency(&self, latency: Duration) {
+        self.latency.observe(latency);
+    }
+
+    pub fn count_qtype(&self, qtype: u16) {
+        let index = QTYPE_NAMES
+            .iter()
+            .position(|(_, known)| *known == qtype)
+            .unwrap_or(QTYPE_NAMES.len());
+        self.queries_by_type[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("queries", "Queries received", &self.queries),
+            (
+                "blocked",
+                "Queries blocked by the lists or a record type policy",
+                &self.blocked,
+            ),
+            (
+                "would_block",
+                "Queries --dry-run let through that would have been blocked",
+                &self.would_block,
+            ),
+            (
+                "cache_hits",
+                "Queries answered from the cache",
+                &self.cache_hits,
+            ),
+            (
+                "cache_misses",
+                "Queries not found in the cache",
+                &self.cache_misses,
+            ),
+            (
+                "upstream_timeouts",
+                "Upstream exchanges that timed out",
+                &self.upstream_timeouts,
+            ),
+            (
+                "upstream_retries",
+                "UDP queries resent to an upstream that had not answered yet",
+                &self.upstream_retries,
+            ),
+            (
+                "upstream_retry_successes",
+                "UDP upstream exchanges answered after a resend",
+                &self.upstream_retry_successes,
+            ),
+            (
+                "upstreams_busy",
+                "Queries failed waiting for a slot under --max-concurrent",
+                &self.upstreams_busy,
+            ),
+            (
+                "stale_answers",
+                "Queries answered from expired cache entries after the \
+                 upstreams failed",
+                &self.stale_answers,
+            ),
+            (
+                "deduplicated",
+                "Queries answered by an identical query's upstream lookup",
+                &self.deduplicated,
+            ),
+            (
+                "rate_limited",
+                "Queries refused or dropped for exceeding the per-client rate \
+                 limit",
+                &self.rate_limited,
+            ),
+            (
+                "unauthorized",
+                "UDP queries and connections from addresses not allowed",
+                &self.unauthorized,
+            ),
+            (
+                "forwarded",
+                "Queries answered by an upstream",
+                &self.forwarded,
+            ),
+            (
+                "parse_failures",
+                "Queries dropped because they could not be parsed",
+                &self.parse_failures,
+            ),
+            (
+                "unsupported_opcodes",
+                "Messages with an opcode other than QUERY, such as UPDATE or \
+                 NOTIFY, answered with NOTIMP",
+                &self.unsupported_opcodes,
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP dnsfilter_{}_total {}.", name, help);
+            let _ = writeln!(out, "# TYPE dnsfilter_{}_total counter", name);
+            let _ = writeln!(
+                out,
+                "dnsfilter_{}_total {}",
+                name,
+                value.load(Ordering::Relaxed)
+            );
+        }
+
+        let name = "dnsfilter_queries_by_type_total";
+        let _ = writeln!(out, "# HELP {} Queries received, by type.", name);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let types = QTYPE_NAMES.iter().map(|(qtype, _)| qtype.to_uppercase());
+        for (qtype, count) in
+            types.chain(["other".to_owned()]).zip(&self.queries_by_type)
+        {
+            let _ = writeln!(
+                out,
+                "{}{{qtype=\"{}\"}} {}",
+                name,
+                qtype,
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        let name = "dnsfilter_query_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Time taken to answer a query.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        self.latency.render(&mut out, name, "");
+        out
+    }
+}
+
+/// Reports the upstream strategy and how each upstream is doing, which lives
+/// with the upstreams rather than in `Metrics`.
+fn render_upstreams(out: &mut String, upstreams: &Upstreams) {
+    if let Some(strategy) = upstreams.strategy.to_possible_value() {
+        let name = "dnsfilter_upstream_strategy";
+        let _ = writeln!(out, "# HELP {} Strategy choosing upstreams.", name);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let _ =
+            writeln!(out, "{}{{strategy=\"{}\"}} 1", name, strategy.get_name());
+    }
+
+    let now = Instant::now();
+    let up = "dnsfilter_upstream_up";
+    let latency = "dnsfilter_upstream_latency_seconds";
+    let _ = writeln!(out, "# HELP {} Whether an upstream is being used.", up);
+    let _ = writeln!(out, "# TYPE {} gauge", up);
+    for server in &upstreams.servers {
+        let _ = writeln!(
+            out,
+            "{}{{upstream=\"{}\"}} {}",
+            up,
+            label(&server.name),
+            u8::from(!server.is_down(now))
+        );
+    }
+    let _ = writeln!(
+        out,
+        "# HELP {} Moving average of upstream response times.",
+        latency
+    );
+    let _ = writeln!(out, "# TYPE {} gauge", latency);
+    for server in &upstreams.servers {
+        if let Some(average) = server.latency() {
+            let _ = writeln!(
+                out,
+                "{}{{upstream=\"{}\"}} {}",
+                latency,
+                label(&server.name),
+                average.as_secs_f64()
+            );
+        }
+    }
+
+    let failures = "dnsfilter_upstream_failures_total";
+    let _ = writeln!(
+        out,
+        "# HELP {} Upstream exchanges that failed, timeouts included.",
+        failures
+    );
+    let _ = writeln!(out, "# TYPE {} counter", failures);
+    for server in &upstreams.servers {
+        let _ = writeln!(
+            out,
+            "{}{{upstream=\"{}\"}} {}",
+            failures,
+            label(&server.name),
+            server.failures.load(Ordering::Relaxed)
+        );
+    }
+
+    if upstreams.strategy == UpstreamStrategy::Race {
+        let wins = "dnsfilter_upstream_race_wins_total";
+        let _ = writeln!(
+            out,
+            "# HELP {} Queries an upstream answered first in a race.",
+            wins
+        );
+        let _ = writeln!(out, "# TYPE {} counter", wins);
+        for server in &upstreams.servers {
+            let _ = writeln!(
+                out,
+                "{}{{upstream=\"{}\"}} {}",
+                wins,
+                label(&server.name),
+                server.race_wins.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    let durations = "dnsfilter_upstream_duration_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {} Time taken by upstream exchanges.",
+        durations
+    );
+    let _ = writeln!(out, "# TYPE {} histogram", durations);
+    for server in &upstreams.servers {
+        let labels = format!("upstream=\"{}\"", label(&server.name));
+        server.durations.render(out, durations, &labels);
+    }
+}
+
+/// Reports the sizes of the lists in use and the queries being answered
+/// right now.
+fn render_gauges(out: &mut String, service: &Service) {
+    let lists = service.lists.load();
+    let gauges = [
+        (
+            "denylist_entries",
+            "Entries in the denylist",
+            lists.denylist.len(),
+        ),
+        (
+            "allowlist_entries",
+            "Entries in the allowlist",
+            lists.allowlist.len(),
+        ),
+        (
+            "in_flight_queries",
+            "Queries being answered",
+            *service.in_flight.borrow(),
+        ),
+    ];
+    for (name, help, value) in gauges {
+        let _ = writeln!(out, "# HELP dnsfilter_{} {}.", name, help);
+        let _ = writeln!(out, "# TYPE dnsfilter_{} gauge", name);
+        let _ = writeln!(out, "dnsfilter_{} {}", name, value);
+    }
+
+    let name = "dnsfilter_list_skipped_lines";
+    let _ = writeln!(
+        out,
+        "# HELP {} Lines of the lists in use skipped in whole or part.",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for (list, stats) in [
+        ("denylist", lists.denylist.stats),
+        ("allowlist", lists.allowlist.stats),
+    ] {
+        for (reason, lines) in [
+            ("comment", stats.comments),
+            ("malformed", stats.malformed),
+            ("unsupported", stats.unsupported),
+        ] {
+            let _ = writeln!(
+                out,
+                "{}{{list=\"{}\",reason=\"{}\"}} {}",
+                name, list, reason, lines
+            );
+        }
+    }
+}
+
+/// Reports the clients the rate limiter holds back. A client drops out once
+/// it has been quiet long enough to be forgotten, resetting its count.
+fn render_rate_limits(out: &mut String, limiter: &RateLimiter) {
+    let name = "dnsfilter_rate_limited_client_total";
+    let _ = writeln!(
+        out,
+        "# HELP {} Queries refused or dropped over the rate limit, by client.",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (client, count) in limiter.limited_clients() {
+        let _ = writeln!(out, "{}{{client=\"{}\"}} {}", name, client, count);
+    }
+}
+
+/// Reports the addresses turned away most recently. Only so many are kept,
+/// so a client can drop out and later start again from zero.
+fn render_rejected(out: &mut String, access: &AccessList) {
+    let name = "dnsfilter_unauthorized_client_total";
+    let _ = writeln!(
+        out,
+        "# HELP {} UDP queries and connections turned away, by client.",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (client, count) in access.rejected_clients() {
+        let _ = writeln!(out, "{}{{client=\"{}\"}} {}", name, client, count);
+    }
+}
+
+fn render_dnstap(out: &mut String, dnstap: &Dnstap) {
+    let name = "dnsfilter_dnstap_dropped_total";
+    let _ = writeln!(
+        out,
+        "# HELP {} dnstap messages dropped because the collector was slow \
+         or unreachable.",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, dnstap.dropped());
+}
+
+/// Escapes a label value for the text format.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Counts behind the statistics summary, read at one moment so a summary
+/// can cover the time between two readings.
+#[derive(Clone, Copy, Default)]
+struct Totals {
+    queries: u64,
+    blocked: u64,
+    cache_hits: u64,
+    cache_misses: u64,
+    upstream_failures: u64,
+    upstream_retries: u64,
+    upstream_retry_successes: u64,
+    /// Upstream exchanges at or below each latency bucket's bound.
+    upstream_buckets: [u64; LATENCY_BUCKETS.len()],
+    upstream_exchanges: u64,
+    upstream_micros: u64,
+}
+
+impl Totals {
+    fn read(service: &Service) -> Self {
+        let metrics = &service.metrics;
+        let mut totals = Self {
+            queries: metrics.queries.load(Ordering::Relaxed),
+            blocked: metrics.blocked.load(Ordering::Relaxed),
+            cache_hits: metrics.cache_hits.load(Ordering::Relaxed),
+            cache_misses: metrics.cache_misses.load(Ordering::Relaxed),
+            upstream_retries: metrics.upstream_retries.load(Ordering::Relaxed),
+            upstream_retry_successes: metrics
+                .upstream_retry_successes
+                .load(Ordering::Relaxed),
+            ..Self::default()
+        };
+        for server in &service.upstreams.servers {
+            totals.upstream_failures += server.failures.load(Ordering::Relaxed);
+            let durations = &server.durations;
+            for (total, bucket) in
+                totals.upstream_buckets.iter_mut().zip(&durations.buckets)
+            {
+                *total += bucket.load(Ordering::Relaxed);
+            }
+            totals.upstream_exchanges +=
+                durations.count.load(Ordering::Relaxed);
+            totals.upstream_micros +=
+                durations.sum_micros.load(Ordering::Relaxed);
+        }
+        totals
+    }
+
+    /// What was counted since `earlier`.
+    fn since(&self, earlier: &Self) -> Self {
+        let mut upstream_buckets = self.upstream_buckets;
+        for (bucket, before) in
+            upstream_buckets.iter_mut().zip(earlier.upstream_buckets)
+        {
+            *bucket -= before;
+        }
+        Self {
+            queries: self.queries - earlier.queries,
+            blocked: self.blocked - earlier.blocked,
+            cache_hits: self.cache_hits - earlier.cache_hits,
+            cache_misses: self.cache_misses - earlier.cache_misses,
+            upstream_failures: self.upstream_failures
+                - earlier.upstream_failures,
+            upstream_retries: self.upstream_retries - earlier.upstream_retries,
+            upstream_retry_successes: self.upstream_retry_successes
+                - earlier.upstream_retry_successes,
+            upstream_buckets,
+            upstream_exchanges: self.upstream_exchanges
+                - earlier.upstream_exchanges,
+            upstream_micros: self.upstream_micros - earlier.upstream_micros,
+        }
+    }
+
+    /// Logs the counts as one line covering `period`.
+    fn log(&self, period: Duration, in_flight: usize) {
+        // Rounded to a tenth, which is all a log line needs.
+        let percent = |part: u64, whole: u64| {
+            (whole > 0)
+                .then(|| (part as f64 * 1000.0 / whole as f64).round() / 10.0)
+        };
+        let exchanges = self.upstream_exchanges;
+        let upstream_avg_ms = (exchanges > 0)
+            .then(|| self.upstream_micros as f64 / exchanges as f64 / 1e3);
+        // The bound of the bucket holding the 95th percentile, or infinity
+        // past the last bucket.
+        let upstream_p95_ms = (exchanges > 0).then(|| {
+            let rank = exchanges - exchanges / 20;
+            LATENCY_BUCKETS
+                .iter()
+                .zip(self.upstream_buckets)
+                .find(|(_, count)| *count >= rank)
+                .map_or(f64::INFINITY, |(bound, _)| bound * 1e3)
+        });
+        info!(
+            period = %humantime::format_duration(period),
+            queries = self.queries,
+            blocked = self.blocked,
+            blocked_percent = percent(self.blocked, self.queries),
+            cache_hit_percent =
+                percent(self.cache_hits, self.cache_hits + self.cache_misses),
+            upstream_avg_ms,
+            upstream_p95_ms,
+            upstream_failures = self.upstream_failures,
+            upstream_retries = self.upstream_retries,
+            upstream_retry_successes = self.upstream_retry_successes,
+            in_flight,
+            "statistics"
+        );
+    }
+}
+
+/// Logs a summary of the queries answered every `interval`, each covering
+/// only that interval.
+pub async fn log_stats(interval: Duration, service: Arc<Service>) {
+    let mut last = Totals::default();
+    let mut ticks = tokio::time::interval(interval);
+    // The first tick is immediate, and there is nothing to report yet.
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        let totals = Totals::read(&service);
+        totals
+            .since(&last)
+            .log(interval, *service.in_flight.borrow());
+        last = totals;
+    }
+}
+
+/// Logs a summary of everything answered since the service started.
+pub fn log_final_stats(service: &Service, uptime: Duration) {
+    let uptime = Duration::from_secs(uptime.as_secs());
+    Totals::read(service).log(uptime, *service.in_flight.borrow());
+}
+
+pub async fn serve_metrics(
+    listener: TcpListener,
+    service: Arc<Service>,
+) -> Result<(), std::io::Error> {
+    loop {
+        let (stream, _) = accept(|| listener.accept()).await;
+        let service = Arc::clone(&service);
+        tokio::spawn(async move {
+            let handler = service_fn(|request| {
+                handle_metrics_request(request, Arc::clone(&service))
+            });
+            let _ = auto::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), handler)
+                .await;
+        });
+    }
+}
+
+async fn handle_metrics_request(
+    request: Request<Incoming>,
+    service: Arc<Service>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    if request.uri().path() != "/metrics" {
+        let mut response = Response::new(Full::default());
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+    let mut body = service.metrics.render();
+    render_upstreams(&mut body, &service.upstreams);
+    render_gauges(&mut body, &service);
+    if let Some(limiter) = &service.rate_limit {
+        render_rate_limits(&mut body, limiter);
+    }
+    render_rejected(&mut body, &service.access);
+    if let Some(dnstap) = &service.dnstap {
+        render_dnstap(&mut body, dnstap);
+    }
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, TEXT_FORMAT)
+        .body(Full::new(Bytes::from(body)))
+        .unwrap())
+}
@@ -0,0 +1,97 @@
+//! Lists chosen by client address, so that some clients, such as a child's
+//! tablet, can get stricter blocking than the rest. Policies come from the
+//! TOML file given with --client-policy:
+//!
+//! ```toml
+//! [[policy]]
+//! name = "kids"
+//! clients = ["192.168.1.20", "10.0.1.0/24"]
+//! list = ["kids.txt", "https://example.com/strict.txt"]
+//! allowlist = ["homework.txt"]
+//! ```
+//!
+//! A client gets the first policy covering its address, in place of the
+//! lists given with --list and --allowlist. Clients no policy covers get
+//! those.
+
+use crate::{
+    acl::{covers, nets},
+    filter::Blocklists,
+};
+use arc_swap::ArcSwap;
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::{collections::HashSet, net::IpAddr, path::Path};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    policy: Vec<PolicyConfig>,
+}
+
+/// One `[[policy]]` of the file, with its lists yet to be read.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+    pub name: String,
+    #[serde(deserialize_with = "nets")]
+    clients: Vec<IpNet>,
+    /// Files and URLs, combined into the policy's denylist.
+    #[serde(rename = "list")]
+    pub denylists: Vec<String>,
+    /// Files and URLs, combined into the policy's allowlist.
+    #[serde(default, rename = "allowlist")]
+    pub allowlists: Vec<String>,
+}
+
+impl PolicyConfig {
+    /// Reads the policies, which must have distinct names and at least one
+    /// client each.
+    pub fn load(path: &Path) -> Result<Vec<Self>, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let invalid = |e: &dyn std::fmt::Display| {
+            format!("Invalid client policy {}: {}", path.display(), e)
+        };
+        let file: PolicyFile =
+            toml::from_str(&text).map_err(|e| invalid(&e))?;
+        let mut names = HashSet::new();
+        for policy in &file.policy {
+            if !names.insert(&policy.name) {
+                return Err(invalid(&format!(
+                    "policy {:?} is defined twice",
+                    policy.name
+                )));
+            }
+            if policy.clients.is_empty() {
+                return Err(invalid(&format!(
+                    "policy {:?} has no clients",
+                    policy.name
+                )));
+            }
+        }
+        Ok(file.policy)
+    }
+}
+
+/// A policy with its lists loaded.
+pub struct ClientPolicy {
+    pub name: String,
+    clients: Vec<IpNet>,
+    pub lists: ArcSwap<Blocklists>,
+}
+
+impl ClientPolicy {
+    pub fn new(config: &PolicyConfig, lists: Blocklists) -> Self {
+        Self {
+            name: config.name.clone(),
+            clients: config.clients.clone(),
+            lists: ArcSwap::from_pointee(lists),
+        }
+    }
+
+    pub fn covers(&self, client: IpAddr) -> bool {
+        covers(&self.clients, client)
+    }
+}
@@ -0,0 +1,241 @@
+//! A file with one line per query, for finding out after the fact what a
+//! client asked and what it got.
+//!
+//! Queries hand their lines to a writer task over a bounded channel, so a slow
+//! disk costs lines rather than holding up answers.
+
+use crate::dns::QTYPE_NAMES;
+use std::{
+    error::Error,
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc::{self, Receiver, Sender},
+};
+use tracing::{info, warn};
+
+/// Lines waiting for the writer beyond which new ones are dropped.
+const QUEUE_SIZE: usize = 4096;
+
+/// How often buffered lines are written out.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+const RCODE_NAMES: &[&str] = &[
+    "NOERROR", "FORMERR", "SERVFAIL", "NXDOMAIN", "NOTIMP", "REFUSED",
+];
+
+/// What became of one query, filled in while it is answered.
+#[derive(Default)]
+pub struct QueryRecord<'a> {
+    pub name: Option<String>,
+    pub qtype: Option<u16>,
+    /// Such as "BLOCKED" or "FORWARDED".
+    pub decision: Option<&'static str>,
+    /// The upstream server that answered.
+    pub upstream: Option<&'a str>,
+    /// Why the query could not be answered, if it got SERVFAIL.
+    pub error: Option<String>,
+}
+
+pub struct QueryLog {
+    lines: Sender<String>,
+    /// Lines dropped since the writer last reported them.
+    dropped: Arc<AtomicU64>,
+}
+
+impl QueryLog {
+    /// Opens `path` for appending and starts the writer, so this must run
+    /// inside the runtime.
+    pub async fn open(path: PathBuf) -> io::Result<Self> {
+        let file = open(&path).await?;
+        let reopen = Reopen::new()?;
+        let (lines, receiver) = mpsc::channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        tokio::spawn(write_lines(
+            path,
+            file,
+            receiver,
+            reopen,
+            Arc::clone(&dropped),
+        ));
+        Ok(Self { lines, dropped })
+    }
+
+    /// Queues the line for a query, which is dropped if the writer has
+    /// fallen too far behind. Queries that got no response at all log why.
+    pub fn write(
+        &self,
+        client: IpAddr,
+        record: &QueryRecord,
+        result: &Result<Vec<u8>, Box<dyn Error>>,
+        elapsed: Duration,
+    ) {
+        let timestamp = humantime::format_rfc3339_millis(SystemTime::now());
+        let name = record
+            .name
+            .as_deref()
+            .map_or("-".into(), |name| name.escape_debug().to_string());
+        let qtype = record.qtype.map_or("-".into(), qtype_name);
+        let rcode = match result {
+            Ok(response) if response.len() >= 4 => {
+                rcode_name(response[3] & 0x0F)
+            }
+            _ => "-".into(),
+        };
+        let decision = match result {
+            Ok(_) => record.decision.unwrap_or("-"),
+            Err(_) => record.decision.unwrap_or("ERROR"),
+        };
+        let mut line = format!(
+            "{} {} {} {} {} {} {} {}us",
+            timestamp,
+            client.to_canonical(),
+            name,
+            qtype,
+            decision,
+            record.upstream.unwrap_or("-"),
+            rcode,
+            elapsed.as_micros(),
+        );
+        let error = match result {
+            Ok(_) => record.error.clone(),
+            Err(e) => Some(e.to_string()),
+        };
+        if let Some(error) = error {
+            line.push_str(&format!(" error={:?}", error));
+        }
+        line.push('\n');
+        if self.lines.try_send(line).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Names a record type as in zone files, such as "AAAA" or "TYPE65".
+pub fn qtype_name(qtype: u16) -> String {
+    match QTYPE_NAMES.iter().find(|(_, value)| *value == qtype) {
+        Some((name, _)) => name.to_ascii_uppercase(),
+        None => format!("TYPE{}", qtype),
+    }
+}
+
+fn rcode_name(rcode: u8) -> String {
+    match RCODE_NAMES.get(usize::from(rcode)) {
+        Some(name) => name.to_string(),
+        None => format!("RCODE{}", rcode),
+    }
+}
+
+async fn open(path: &Path) -> io::Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    Ok(BufWriter::new(file))
+}
+
+/// Writes queued lines until the log is dropped, flushing every
+/// `FLUSH_INTERVAL` and starting on a fresh file when asked to reopen.
+async fn write_lines(
+    path: PathBuf,
+    mut file: BufWriter<File>,
+    mut lines: Receiver<String>,
+    mut reopen: Reopen,
+    dropped: Arc<AtomicU64>,
+) {
+    let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+    // Only the first of a run of failed writes is worth a warning.
+    let mut failing = false;
+    loop {
+        let result = tokio::select! {
+            line = lines.recv() => match line {
+                Some(line) => file.write_all(line.as_bytes()).await,
+                None => break,
+            },
+            _ = flush.tick() => {
+                let dropped = dropped.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    warn!(lines = dropped, "query log fell behind");
+                }
+                file.flush().await
+            }
+            () = reopen.recv() => {
+                let flushed = file.flush().await;
+                match open(&path).await {
+                    Ok(reopened) => {
+                        info!(path = %path.display(), "reopened query log");
+                        file = reopened;
+                        flushed
+                    }
+                    // Keep writing to the old file rather than lose lines.
+                    Err(e) => Err(e),
+                }
+            }
+        };
+        match result {
+            Ok(()) => failing = false,
+            Err(e) => {
+                if !failing {
+                    warn!(
+                        path = %path.display(),
+                        error = %e,
+                        "failed to write query log"
+                    );
+                }
+                failing = true;
+            }
+        }
+    }
+    let _ = file.flush().await;
+}
+
+/// The signals asking for the log file to be reopened, as log rotation does
+/// after moving it aside.
+#[cfg(unix)]
+struct Reopen {
+    hangup: Signal,
+    user2: Signal,
+}
+
+#[cfg(unix)]
+impl Reopen {
+    fn new() -> io::Result<Self> {
+        Ok(Self {
+            hangup: signal(SignalKind::hangup())?,
+            user2: signal(SignalKind::user_defined2())?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        tokio::select! {
+            _ = self.hangup.recv() => {}
+            _ = self.user2.recv() => {}
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct Reopen;
+
+#[cfg(not(unix))]
+impl Reopen {
+    fn new() -> io::Result<Self> {
+        Ok(Self)
+    }
+
+    async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
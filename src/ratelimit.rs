@@ -0,0 +1,128 @@
+//! Per-client query rate limiting with a token bucket for each source IP.
+
+use std::{collections::HashMap, net::IpAddr, sync::Mutex, time::Instant};
+
+pub struct RateLimiter {
+    /// Tokens added per second, which is the sustained query rate.
+    rate: f64,
+    /// Tokens a bucket holds when full, which is the largest burst allowed.
+    burst: f64,
+    /// Clients the limit doesn't apply to.
+    exempt: Vec<IpAddr>,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Queries refused or dropped since the bucket was created.
+    limited: u64,
+}
+
+impl RateLimiter {
+    /// Allows `qps` queries per second from each client, in bursts of up to
+    /// `burst` queries or, without one, a second's worth.
+    pub fn new(qps: u32, burst: Option<u32>, exempt: Vec<IpAddr>) -> Self {
+        Self {
+            rate: f64::from(qps.max(1)),
+            burst: f64::from(burst.unwrap_or(qps).max(1)),
+            exempt: exempt.iter().map(IpAddr::to_canonical).collect(),
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Takes a token from the client's bucket, returning false if it is
+    /// empty and the query should be refused. IPv4-mapped IPv6 addresses
+    /// (::ffff:a.b.c.d), as dual-stack sockets report IPv4 clients, count
+    /// as the IPv4 address they carry.
+    pub fn allow(&self, client: IpAddr, now: Instant) -> bool {
+        let client = client.to_canonical();
+        if self.exempt.contains(&client) {
+            return true;
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+            limited: 0,
+        });
+        bucket.tokens = self.refill(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            bucket.limited += 1;
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Forgets clients whose buckets have filled up again, which behave the
+    /// same as a new bucket, so only recently active clients take memory.
+    pub fn expire(&self, now: Instant) {
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| self.refill(bucket, now) < self.burst);
+    }
+
+    /// Lists the tracked clients that went over the limit, with how many of
+    /// their queries were refused.
+    pub fn limited_clients(&self) -> Vec<(IpAddr, u64)> {
+        self.buckets
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, bucket)| bucket.limited > 0)
+            .map(|(client, bucket)| (*client, bucket.limited))
+            .collect()
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        (bucket.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn limits_each_client_on_its_own() {
+        let limiter = RateLimiter::new(2, None, Vec::new());
+        let now = Instant::now();
+        let (first, second) = (ip("192.0.2.1"), ip("192.0.2.2"));
+        assert!(limiter.allow(first, now));
+        assert!(limiter.allow(first, now));
+        assert!(!limiter.allow(first, now));
+        // The first client's burst leaves the second one's bucket full.
+        assert!(limiter.allow(second, now));
+        assert!(limiter.allow(second, now));
+        assert_eq!(limiter.limited_clients(), [(first, 1)]);
+
+        // Half a second at two queries a second buys one more.
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.allow(first, later));
+        assert!(!limiter.allow(first, later));
+    }
+
+    #[test]
+    fn exempts_ipv4_clients_on_dual_stack_sockets() {
+        let exempt = vec![ip("192.0.2.1"), ip("::ffff:192.0.2.2")];
+        let limiter = RateLimiter::new(1, None, exempt);
+        let now = Instant::now();
+        for client in ["::ffff:192.0.2.1", "192.0.2.2"] {
+            for _ in 0..3 {
+                assert!(limiter.allow(ip(client), now), "{client}");
+            }
+        }
+        // Either form of an address that isn't exempt shares one bucket.
+        assert!(limiter.allow(ip("192.0.2.3"), now));
+        assert!(!limiter.allow(ip("::ffff:192.0.2.3"), now));
+    }
+}
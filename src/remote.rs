@@ -0,0 +1,157 @@
+//! Blocklists downloaded over HTTP(S).
+
+use reqwest::{
+    header::{
+        HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+    },
+    StatusCode,
+};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+pub fn is_url(source: &str) -> bool {
+    source.starts_with("https://") || source.starts_with("http://")
+}
+
+/// Downloads lists and remembers the last copy of each, so asking again
+/// for an unchanged list costs a conditional request rather than the body.
+pub struct Fetcher {
+    client: reqwest::Client,
+    /// Where downloads are saved, so a restart while the network is down
+    /// still has the lists.
+    cache_dir: Option<PathBuf>,
+    last: Mutex<HashMap<String, Download>>,
+}
+
+#[derive(Clone)]
+struct Download {
+    body: Arc<Vec<u8>>,
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+}
+
+impl Fetcher {
+    pub fn new(client: reqwest::Client, cache_dir: Option<PathBuf>) -> Self {
+        Self {
+            client,
+            cache_dir,
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the list at `url`, falling back to the last copy downloaded
+    /// or saved to the cache directory if the server can't be reached.
+    pub async fn fetch(&self, url: &str) -> io::Result<Arc<Vec<u8>>> {
+        let error = match self.download(url).await {
+            Ok((body, _)) => return Ok(body),
+            Err(e) => e,
+        };
+        let last = self.last.lock().unwrap().get(url).cloned();
+        if let Some(last) = last {
+            warn!(url, error = %error, "download failed, using previous copy");
+            return Ok(last.body);
+        }
+        let Some(path) = self.cache_path(url) else {
+            return Err(io::Error::other(format!(
+                "Failed to download {}: {}",
+                url, error
+            )));
+        };
+        match tokio::fs::read(&path).await {
+            Ok(body) => {
+                warn!(
+                    url,
+                    path = %path.display(),
+                    error = %error,
+                    "download failed, using cached copy"
+                );
+                Ok(Arc::new(body))
+            }
+            Err(_) => Err(io::Error::other(format!(
+                "Failed to download {} and no cached copy exists: {}",
+                url, error
+            ))),
+        }
+    }
+
+    /// Checks whether the list at `url` changed since it was last fetched,
+    /// keeping the new copy for the next `fetch` if so.
+    pub async fn refresh(&self, url: &str) -> Result<bool, reqwest::Error> {
+        Ok(self.download(url).await?.1)
+    }
+
+    /// Fetches `url`, following redirects and decoding gzip bodies, and says
+    /// whether the body differs from the last download.
+    async fn download(
+        &self,
+        url: &str,
+    ) -> Result<(Arc<Vec<u8>>, bool), reqwest::Error> {
+        let last = self.last.lock().unwrap().get(url).cloned();
+        let mut request = self.client.get(url);
+        if let Some(last) = &last {
+            if let Some(etag) = &last.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &last.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let response = request.send().await?;
+        if let (StatusCode::NOT_MODIFIED, Some(last)) =
+            (response.status(), &last)
+        {
+            return Ok((Arc::clone(&last.body), false));
+        }
+
+        let response = response.error_for_status()?;
+        let etag = response.headers().get(ETAG).cloned();
+        let last_modified = response.headers().get(LAST_MODIFIED).cloned();
+        let body = Arc::new(response.bytes().await?.to_vec());
+        // Servers without validators resend the same list every time.
+        let changed = last.is_none_or(|last| last.body != body);
+        if let Some(path) = self.cache_path(url) {
+            if let Err(e) = save(&path, &body).await {
+                warn!(
+                    url,
+                    path = %path.display(),
+                    error = %e,
+                    "failed to cache downloaded list"
+                );
+            }
+        }
+        let download = Download {
+            body: Arc::clone(&body),
+            etag,
+            last_modified,
+        };
+        self.last.lock().unwrap().insert(url.to_owned(), download);
+        Ok((body, changed))
+    }
+
+    /// Names the cached copy after its URL, with anything that isn't safe in
+    /// a file name replaced.
+    fn cache_path(&self, url: &str) -> Option<PathBuf> {
+        let name: String = url
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+                _ => '_',
+            })
+            .collect();
+        Some(self.cache_dir.as_ref()?.join(name))
+    }
+}
+
+/// Writes the copy beside its final name and renames it into place, so an
+/// interrupted write never leaves a truncated list behind.
+async fn save(path: &Path, body: &[u8]) -> io::Result<()> {
+    let mut partial = PathBuf::from(path);
+    partial.as_mut_os_string().push(".part");
+    tokio::fs::write(&partial, body).await?;
+    tokio::fs::rename(&partial, path).await
+}
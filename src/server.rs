@@ -0,0 +1,2339 @@
+//! Answering queries: deciding from the lists, answering from the cache
+//! or asking the upstreams, on whichever sockets the server is given.
+
+use crate::{
+    acl::AccessList,
+    args::{AnyAnswer, Args, BlockMode, NoForwardRcode},
+    cache::Cache,
+    config::Reread,
+    control::{self, RuntimeRules},
+    dns::{
+        self, add_extended_error, cname_chain, create_answer_response,
+        create_error_response, create_question_response, match_request,
+        negative_soa, parse_dns_question, truncate_response, udp_payload_size,
+        EdnsFlags, Question, Record, CLASS_ANY, CLASS_IN, EDE_FILTERED,
+        MAX_UDP_PAYLOAD, RCODE_FORMERR, RCODE_NOTIMP, RCODE_NXDOMAIN,
+        RCODE_REFUSED, RCODE_SERVFAIL, TYPE_A, TYPE_AAAA, TYPE_ANY, TYPE_HINFO,
+        TYPE_TXT,
+    },
+    dnstap::{Dnstap, Transport},
+    filter::{Blocklists, ListConfig, ListSources, Verdict},
+    https,
+    local::LocalRecords,
+    metrics::{self, Metrics},
+    policy::{ClientPolicy, PolicyConfig},
+    querylog::{QueryLog, QueryRecord},
+    ratelimit::RateLimiter,
+    remote::{self, Fetcher},
+    singleflight::SingleFlight,
+    tls,
+    upstream::{self, Upstreams},
+    watch,
+};
+use arc_swap::ArcSwap;
+use socket2::{Domain, Socket, Type};
+use std::{
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use thiserror::Error;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    sync::{watch::Sender, Semaphore},
+    task::JoinSet,
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error_span, info, warn, Instrument};
+
+/// How long a TCP client may stay silent before its connection is dropped.
+pub(crate) const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// First pause after a failed accept, doubled for each failure in a row.
+/// Errors such as running out of file descriptors last until connections
+/// close, so retrying at once would only spin.
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Where queries go if no upstream server is given.
+const DEFAULT_UPSTREAM: &str = "1.1.1.1:53";
+
+/// Upper bound on downloading one blocklist.
+const LIST_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long shutdown waits for queries already being answered.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// First delay before retrying a failed list refresh.
+const MIN_REFRESH_RETRY: Duration = Duration::from_secs(60);
+
+/// TTL of the HINFO record given for ANY queries, which RFC 8482 suggests
+/// making long so clients don't keep asking.
+const ANY_HINFO_TTL: u32 = 3600;
+
+/// How often clients that stopped querying are dropped from the rate limiter.
+const RATE_LIMIT_EXPIRY: Duration = Duration::from_secs(60);
+
+/// Why the server couldn't start or stopped.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// A setting that can't be used, such as an address that doesn't parse
+    /// or a list that can't be read.
+    #[error("{0}")]
+    Setup(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Self::Setup(message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Self::Setup(message.to_owned())
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for Error {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        Self::Setup(e.to_string())
+    }
+}
+
+/// The filter with its lists loaded, ready to answer queries on whatever
+/// sockets it is handed.
+pub struct Server {
+    service: Arc<Service>,
+    lists: Arc<ListConfig>,
+    runtime_rules: Arc<RuntimeRules>,
+}
+
+impl Server {
+    /// Loads the lists and sets up the upstreams, cache and the rest as the
+    /// settings say. Listen addresses and other settings for the whole
+    /// process, such as the control socket, are left to [`run`].
+    pub async fn new(args: &Args) -> Result<Self, Error> {
+        if let Some(url) = args.upstream_doh.iter().find(|url| {
+            !url.starts_with("https://") && !url.starts_with("http://")
+        }) {
+            return Err(format!("Invalid DNS-over-HTTPS URL {:?}", url).into());
+        }
+        let mut servers: Vec<&str> = args
+            .dns
+            .iter()
+            .chain(&args.upstream_doh)
+            .map(String::as_str)
+            .collect();
+        if servers.is_empty() {
+            servers.push(DEFAULT_UPSTREAM);
+        }
+        let upstreams = Upstreams {
+            servers: servers
+                .into_iter()
+                .map(|dns| upstream::Server::new(dns, args.tls_insecure))
+                .collect::<Result<_, _>>()?,
+            strategy: args.upstream_strategy,
+            next: AtomicUsize::new(0),
+            timeout: args.upstream_timeout,
+            stream_timeout: args.upstream_stream_timeout,
+            retries: args.upstream_retries,
+            slots: Semaphore::new(args.max_concurrent.get() as usize),
+        };
+        let query_log = match &args.query_log {
+            Some(path) => {
+                Some(QueryLog::open(path.clone()).await.map_err(|e| {
+                    format!(
+                        "Failed to open query log {}: {}",
+                        path.display(),
+                        e
+                    )
+                })?)
+            }
+            None => None,
+        };
+        let policies = match &args.client_policy {
+            Some(path) => PolicyConfig::load(path)?,
+            None => Vec::new(),
+        };
+        let client = reqwest::Client::builder()
+            .timeout(LIST_DOWNLOAD_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let list_config = Arc::new(ListConfig {
+            lists: ArcSwap::from_pointee(ListSources {
+                denylists: args.list.clone(),
+                allowlists: args.allowlist.clone(),
+            }),
+            policies,
+            local_records: args.local_records.clone(),
+            implicit_wildcards: args.implicit_wildcards,
+            denylist_format: args.denylist_format,
+            regex_denylist: args.regex_denylist.clone(),
+            fail_open: args.fail_open,
+            fetcher: Fetcher::new(client, args.list_cache_dir.clone()),
+        });
+        let sources = list_config.lists.load();
+        let lists = Blocklists::load(
+            &list_config,
+            &sources.denylists,
+            &sources.allowlists,
+        )
+        .await?;
+        info!(
+            denylist = lists.denylist.len(),
+            allowlist = lists.allowlist.len(),
+            malformed = lists.denylist.stats.malformed
+                + lists.allowlist.stats.malformed,
+            "loaded blocklists"
+        );
+        let mut policies = Vec::new();
+        for config in &list_config.policies {
+            let lists = Blocklists::load(
+                &list_config,
+                &config.denylists,
+                &config.allowlists,
+            )
+            .await?;
+            info!(
+                policy = %config.name,
+                denylist = lists.denylist.len(),
+                allowlist = lists.allowlist.len(),
+                "loaded client policy"
+            );
+            policies.push(ClientPolicy::new(config, lists));
+        }
+        let runtime_rules = RuntimeRules::load(
+            args.persist_runtime_rules.clone(),
+            args.implicit_wildcards,
+        )?;
+        let runtime = runtime_rules.lists()?;
+        let local = match &args.local_records {
+            Some(path) => {
+                let local = LocalRecords::load(path)?;
+                info!(names = local.len(), "loaded local records");
+                local
+            }
+            None => LocalRecords::default(),
+        };
+        let service = Arc::new(Service {
+            lists: ArcSwap::from_pointee(lists),
+            runtime: ArcSwap::from_pointee(runtime),
+            local: ArcSwap::from_pointee(local),
+            local_ttl: args.local_ttl,
+            policies,
+            protected: args
+                .protected_suffix
+                .iter()
+                .map(|suffix| suffix.trim_matches('.').to_lowercase())
+                .collect(),
+            upstreams,
+            block: BlockAnswer {
+                mode: args.block_mode,
+                ipv4: args.sinkhole_ip,
+                ipv6: args.sinkhole_ip6,
+                ttl: args.block_ttl,
+                extended_errors: args.extended_errors,
+            },
+            dry_run: args.dry_run,
+            block_cnames: args.block_cnames,
+            blocked_qtypes: args.block_qtype.clone(),
+            any_answer: args.any_answer,
+            no_forward: args.no_forward.then_some(
+                match args.no_forward_rcode {
+                    NoForwardRcode::Servfail => RCODE_SERVFAIL,
+                    NoForwardRcode::Nxdomain => RCODE_NXDOMAIN,
+                    NoForwardRcode::Refused => RCODE_REFUSED,
+                },
+            ),
+            cache: NonZeroUsize::new(args.cache_size).map(|size| {
+                Cache::new(size, args.max_negative_ttl, args.stale_window)
+            }),
+            lookups: SingleFlight::default(),
+            rate_limit: args.rate_limit.map(|qps| {
+                RateLimiter::new(
+                    qps,
+                    args.rate_limit_burst,
+                    args.rate_limit_exempt.clone(),
+                )
+            }),
+            rate_limit_drop: args.rate_limit_drop,
+            access: AccessList::new(args.allow_from.clone()),
+            refuse_unauthorized: args.refuse_unauthorized,
+            query_log,
+            dnstap: args.dnstap_socket.clone().map(Dnstap::new),
+            metrics: Metrics::default(),
+            in_flight: Sender::new(0),
+            health_domain: args
+                .health_domain
+                .trim_end_matches('.')
+                .to_lowercase(),
+            started: Instant::now(),
+        });
+        Ok(Self {
+            service,
+            lists: list_config,
+            runtime_rules: Arc::new(runtime_rules),
+        })
+    }
+
+    /// Answers queries arriving on `socket` until receiving fails.
+    pub async fn serve_udp(&self, socket: UdpSocket) -> std::io::Result<()> {
+        serve_udp(socket, Arc::clone(&self.service)).await
+    }
+
+    /// Answers queries over connections accepted from `listener`.
+    pub async fn serve_tcp(
+        &self,
+        listener: TcpListener,
+    ) -> std::io::Result<()> {
+        serve_tcp(listener, Arc::clone(&self.service)).await
+    }
+
+    /// Rereads the lists and swaps them in, keeping the old ones if that
+    /// fails.
+    pub async fn reload_lists(&self) {
+        reload_lists(&self.lists, &self.service).await;
+    }
+}
+
+/// Runs the server as the settings say until SIGTERM or Ctrl-C, rereading the
+/// config file on SIGHUP if given a `reread`.
+pub async fn run(args: Args, reread: Option<Reread>) -> Result<(), Error> {
+    if (args.listen_tls.is_some() || args.listen_doh.is_some())
+        && (args.tls_cert.is_none() || args.tls_key.is_none())
+    {
+        return Err("The TLS and HTTPS listeners need a certificate and key \
+                    (--tls-cert and --tls-key)"
+            .into());
+    }
+    let listen = args
+        .listen
+        .iter()
+        .map(|addr| {
+            addr.parse::<SocketAddr>().map_err(|e| {
+                format!("Invalid listen address {:?}: {}", addr, e)
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let server_config = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::server_config(cert, key)?),
+        _ => None,
+    };
+    let tls = match (&args.listen_tls, &server_config) {
+        (Some(addr), Some(config)) => {
+            let addr: SocketAddr = addr.parse().map_err(|e| {
+                format!("Invalid TLS listen address {:?}: {}", addr, e)
+            })?;
+            Some((addr, TlsAcceptor::from(Arc::new(config.clone()))))
+        }
+        _ => None,
+    };
+    let metrics = match &args.metrics_addr {
+        Some(addr) => Some(addr.parse::<SocketAddr>().map_err(|e| {
+            format!("Invalid metrics address {:?}: {}", addr, e)
+        })?),
+        None => None,
+    };
+    let https = match (&args.listen_doh, server_config) {
+        (Some(addr), Some(mut config)) => {
+            let addr: SocketAddr = addr.parse().map_err(|e| {
+                format!("Invalid DoH listen address {:?}: {}", addr, e)
+            })?;
+            config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+            Some((addr, TlsAcceptor::from(Arc::new(config))))
+        }
+        _ => None,
+    };
+    let Server {
+        service,
+        lists: list_config,
+        runtime_rules,
+    } = Server::new(&args).await?;
+    #[cfg(unix)]
+    {
+        let hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(reload_on_hangup(
+            hangup,
+            reread,
+            Arc::clone(&list_config),
+            Arc::clone(&service),
+        ));
+    }
+    #[cfg(not(unix))]
+    drop(reread);
+    if let Some(interval) = args.refresh_interval {
+        tokio::spawn(refresh_lists(
+            interval,
+            Arc::clone(&list_config),
+            Arc::clone(&service),
+        ));
+    }
+    if service.rate_limit.is_some() {
+        tokio::spawn(expire_rate_limits(Arc::clone(&service)));
+    }
+    if !args.stats_interval.is_zero() {
+        tokio::spawn(metrics::log_stats(
+            args.stats_interval,
+            Arc::clone(&service),
+        ));
+    }
+    match &args.control_socket {
+        #[cfg(unix)]
+        Some(path) => {
+            let listener = control::bind(path).map_err(|e| {
+                format!(
+                    "Failed to open control socket {}: {}",
+                    path.display(),
+                    e
+                )
+            })?;
+            tokio::spawn(control::serve_control(
+                listener,
+                runtime_rules,
+                Arc::clone(&list_config),
+                Arc::clone(&service),
+            ));
+        }
+        #[cfg(not(unix))]
+        Some(_) => return Err("--control-socket needs unix sockets".into()),
+        None => {}
+    }
+    if args.watch {
+        watch::watch_lists(list_config, Arc::clone(&service))?;
+    }
+    tokio::select! {
+        result = start_service(
+            listen,
+            tls,
+            https,
+            metrics,
+            Arc::clone(&service),
+        ) => result?,
+        result = shutdown_signal() => {
+            result?;
+            // Dropping the listeners stopped new queries; let the ones
+            // already being answered finish.
+            info!("shutting down");
+            let mut in_flight = service.in_flight.subscribe();
+            let drained = in_flight.wait_for(|&queries| queries == 0);
+            if timeout(SHUTDOWN_TIMEOUT, drained).await.is_err() {
+                warn!(
+                    queries = *service.in_flight.borrow(),
+                    "gave up waiting for queries"
+                );
+            }
+            if !args.stats_interval.is_zero() {
+                metrics::log_final_stats(&service, service.started.elapsed());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Everything that goes into the answer for a blocked domain.
+struct BlockAnswer {
+    mode: BlockMode,
+    /// Addresses handed out in zeroip mode.
+    ipv4: Ipv4Addr,
+    ipv6: Ipv6Addr,
+    ttl: u32,
+    /// Whether to say why, with an Extended DNS Error.
+    extended_errors: bool,
+}
+
+/// State shared by every request the service handles.
+pub(crate) struct Service {
+    /// Swapped out wholesale on reload, so a query sees either the old or the
+    /// new lists and never a partially built set.
+    pub(crate) lists: ArcSwap<Blocklists>,
+    /// Rules added through the control socket, which reloading the lists
+    /// leaves alone.
+    pub(crate) runtime: ArcSwap<Blocklists>,
+    /// Records answered here rather than upstream, reloaded with the lists.
+    pub(crate) local: ArcSwap<LocalRecords>,
+    local_ttl: u32,
+    /// Lists that replace `lists` for some clients, tried in order.
+    policies: Vec<ClientPolicy>,
+    /// Domains whose names are forwarded without consulting the lists.
+    protected: Vec<String>,
+    pub(crate) upstreams: Upstreams,
+    block: BlockAnswer,
+    /// Only log what would be blocked.
+    dry_run: bool,
+    /// Check the CNAMEs in answers against the lists too.
+    block_cnames: bool,
+    /// Record types answered with no records whatever the name.
+    blocked_qtypes: Vec<u16>,
+    any_answer: AnyAnswer,
+    /// Response code for queries that miss the cache, which then never go
+    /// upstream.
+    no_forward: Option<u8>,
+    pub(crate) cache: Option<Cache>,
+    /// Upstream lookups in progress, which identical queries wait for.
+    lookups: SingleFlight,
+    pub(crate) rate_limit: Option<RateLimiter>,
+    /// Drop queries over the rate limit instead of refusing them.
+    rate_limit_drop: bool,
+    /// The clients that may query at all.
+    pub(crate) access: AccessList,
+    refuse_unauthorized: bool,
+    query_log: Option<QueryLog>,
+    pub(crate) dnstap: Option<Dnstap>,
+    pub(crate) metrics: Metrics,
+    /// Number of queries being answered, which shutdown waits to reach zero.
+    pub(crate) in_flight: Sender<usize>,
+    /// Name answered with the server's health rather than looked up.
+    health_domain: String,
+    pub(crate) started: Instant,
+}
+
+impl Service {
+    /// Whether `client` may query the server, counting it if not.
+    pub(crate) fn admits(&self, client: IpAddr) -> bool {
+        if self.access.check(client) {
+            return true;
+        }
+        self.metrics.unauthorized.fetch_add(1, Ordering::Relaxed);
+        debug!(%client, "turning away client outside --allow-from");
+        false
+    }
+
+    /// The lists that apply to `client`: those of the first policy covering
+    /// it, or else the default ones.
+    pub(crate) fn lists_for(&self, client: IpAddr) -> &ArcSwap<Blocklists> {
+        self.policies
+            .iter()
+            .find(|policy| policy.covers(client))
+            .map_or(&self.lists, |policy| &policy.lists)
+    }
+
+    /// Whether `domain` is one of the protected domains or under one.
+    pub(crate) fn is_protected(&self, domain: &str) -> bool {
+        self.protected.iter().any(|suffix| {
+            domain
+                .strip_suffix(suffix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+        })
+    }
+
+    /// Whether `client` has gone over the rate limit, using up one of its
+    /// queries if not.
+    pub(crate) fn over_rate_limit(&self, client: IpAddr) -> bool {
+        self.rate_limit
+            .as_ref()
+            .is_some_and(|limiter| !limiter.allow(client, Instant::now()))
+    }
+
+    /// Counts a query as in flight until the returned guard is dropped.
+    pub(crate) fn track_query(&self) -> InFlight<'_> {
+        self.in_flight.send_modify(|queries| *queries += 1);
+        InFlight(&self.in_flight)
+    }
+}
+
+pub(crate) struct InFlight<'a>(&'a Sender<usize>);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.send_modify(|queries| *queries -= 1);
+    }
+}
+
+/// Resolves on Ctrl-C or, on unix, SIGTERM.
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+async fn start_service(
+    listen: Vec<SocketAddr>,
+    tls: Option<(SocketAddr, TlsAcceptor)>,
+    https: Option<(SocketAddr, TlsAcceptor)>,
+    metrics: Option<SocketAddr>,
+    service: Arc<Service>,
+) -> Result<(), std::io::Error> {
+    let mut plain = Vec::with_capacity(listen.len());
+    for addr in listen {
+        let socket = bind_udp(addr)?;
+        // Serve TCP on the same port, even if the system chose it.
+        let addr = socket.local_addr()?;
+        plain.push((socket, bind_tcp("TCP", addr)?));
+    }
+    let tls = match tls {
+        Some((addr, acceptor)) => Some((bind_tcp("TLS", addr)?, acceptor)),
+        None => None,
+    };
+    let https = match https {
+        Some((addr, acceptor)) => Some((bind_tcp("DoH", addr)?, acceptor)),
+        None => None,
+    };
+    let metrics = match metrics {
+        Some(addr) => Some(bind_tcp("metrics", addr)?),
+        None => None,
+    };
+    // With port 0 the system picks the port, so report what was bound.
+    let mut servers = JoinSet::new();
+    for (socket, listener) in plain {
+        info!(addr = %listener.local_addr()?, "serving DNS over UDP and TCP");
+        servers.spawn(serve_udp(socket, Arc::clone(&service)));
+        servers.spawn(serve_tcp(listener, Arc::clone(&service)));
+    }
+    if let Some((listener, _)) = &tls {
+        info!(addr = %listener.local_addr()?, "serving DNS over TLS");
+    }
+    if let Some((listener, _)) = &https {
+        info!(addr = %listener.local_addr()?, "serving DNS over HTTPS");
+    }
+    if let Some(listener) = &metrics {
+        info!(addr = %listener.local_addr()?, "serving metrics");
+    }
+    tokio::try_join!(
+        async {
+            // The servers only return on error, which ends the service.
+            while let Some(result) = servers.join_next().await {
+                result??;
+            }
+            Ok(())
+        },
+        async {
+            match tls {
+                Some((listener, acceptor)) => {
+                    serve_tls(listener, acceptor, Arc::clone(&service)).await
+                }
+                None => Ok(()),
+            }
+        },
+        async {
+            match https {
+                Some((listener, acceptor)) => {
+                    https::serve_https(listener, acceptor, Arc::clone(&service))
+                        .await
+                }
+                None => Ok(()),
+            }
+        },
+        async {
+            match metrics {
+                Some(listener) => {
+                    metrics::serve_metrics(listener, Arc::clone(&service)).await
+                }
+                None => Ok(()),
+            }
+        },
+    )?;
+    Ok(())
+}
+
+fn bind_udp(addr: SocketAddr) -> Result<UdpSocket, std::io::Error> {
+    new_socket(addr, Type::DGRAM)
+        .and_then(|socket| UdpSocket::from_std(socket.into()))
+        .map_err(|e| bind_error("UDP", addr, e))
+}
+
+fn bind_tcp(
+    what: &str,
+    addr: SocketAddr,
+) -> Result<TcpListener, std::io::Error> {
+    new_socket(addr, Type::STREAM)
+        .and_then(|socket| {
+            socket.listen(1024)?;
+            TcpListener::from_std(socket.into())
+        })
+        .map_err(|e| bind_error(what, addr, e))
+}
+
+/// Binds a non-blocking socket to `addr`. IPv6 sockets are made IPv6-only,
+/// as some systems do by default anyway, so "[::]:53" can be bound next to
+/// "0.0.0.0:53" rather than clashing with it.
+fn new_socket(addr: SocketAddr, ty: Type) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    // Like the standard library's listeners, allow rebinding a port whose
+    // old connections are still in TIME_WAIT.
+    #[cfg(unix)]
+    if ty == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+/// Names the listener that failed, since the bare error ("Address already in
+/// use", "Permission denied") doesn't say which one.
+fn bind_error(
+    what: &str,
+    addr: SocketAddr,
+    e: std::io::Error,
+) -> std::io::Error {
+    std::io::Error::new(
+        e.kind(),
+        format!("Failed to bind {} listener on {}: {}", what, addr, e),
+    )
+}
+
+async fn serve_udp(
+    socket: UdpSocket,
+    service: Arc<Service>,
+) -> Result<(), std::io::Error> {
+    let socket = Arc::new(socket);
+    loop {
+        let mut buf = [0u8; MAX_UDP_PAYLOAD];
+        let (len, src) = socket.recv_from(&mut buf).await?;
+        if !service.admits(src.ip()) {
+            // Refuse without spawning a task, and without waiting for room to
+            // send, so a flood of unwanted queries stays cheap.
+            let request = &buf[..len];
+            if service.refuse_unauthorized
+                && len >= 12
+                && request[2] & 0x80 == 0
+            {
+                let response = create_error_response(request, RCODE_REFUSED);
+                let _ = socket.try_send_to(&response, src);
+            }
+            continue;
+        }
+        // Likewise for queries over the rate limit, which are what a flood
+        // is made of.
+        if service.over_rate_limit(src.ip()) {
+            service.metrics.queries.fetch_add(1, Ordering::Relaxed);
+            if let Some(response) = rate_limited_response(&buf[..len], &service)
+            {
+                let _ = socket.try_send_to(&response, src);
+            }
+            continue;
+        }
+        let socket = Arc::clone(&socket);
+        let service = Arc::clone(&service);
+        let span = error_span!("udp", client = %src);
+        tokio::spawn(
+            async move {
+                let _query = service.track_query();
+                let request = &buf[0..len];
+                let Ok(response) =
+                    handle_request(request, src, Transport::Udp, &service)
+                        .await
+                else {
+                    return;
+                };
+                let response =
+                    truncate_response(response, udp_payload_size(request));
+                let _ = socket.send_to(&response, src).await;
+            }
+            .instrument(span),
+        );
+    }
+}
+
+async fn serve_tcp(
+    listener: TcpListener,
+    service: Arc<Service>,
+) -> Result<(), std::io::Error> {
+    loop {
+        let (stream, peer) = accept(|| listener.accept()).await;
+        if !service.admits(peer.ip()) {
+            continue;
+        }
+        let service = Arc::clone(&service);
+        let span = error_span!("tcp", client = %peer);
+        tokio::spawn(
+            async move {
+                let _ = handle_tcp_connection(
+                    stream,
+                    peer,
+                    Transport::Tcp,
+                    &service,
+                )
+                .await;
+            }
+            .instrument(span),
+        );
+    }
+}
+
+async fn serve_tls(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    service: Arc<Service>,
+) -> Result<(), std::io::Error> {
+    loop {
+        let (stream, peer) = accept(|| listener.accept()).await;
+        if !service.admits(peer.ip()) {
+            continue;
+        }
+        let acceptor = acceptor.clone();
+        let service = Arc::clone(&service);
+        let span = error_span!("tls", client = %peer);
+        tokio::spawn(
+            async move {
+                let Ok(Ok(stream)) =
+                    timeout(TCP_IDLE_TIMEOUT, acceptor.accept(stream)).await
+                else {
+                    return;
+                };
+                let _ = handle_tcp_connection(
+                    stream,
+                    peer,
+                    Transport::Tls,
+                    &service,
+                )
+                .await;
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// Periodically forgets clients that have stopped querying, so the rate
+/// limiter's memory follows the number of active clients.
+async fn expire_rate_limits(service: Arc<Service>) {
+    let Some(limiter) = &service.rate_limit else {
+        return;
+    };
+    let mut ticks = tokio::time::interval(RATE_LIMIT_EXPIRY);
+    loop {
+        ticks.tick().await;
+        limiter.expire(Instant::now());
+    }
+}
+
+/// Rebuilds the blocklists each time the process receives SIGHUP, first
+/// rereading the config file if there is one.
+#[cfg(unix)]
+async fn reload_on_hangup(
+    mut hangup: Signal,
+    reread: Option<Reread>,
+    config: Arc<ListConfig>,
+    service: Arc<Service>,
+) {
+    while hangup.recv().await.is_some() {
+        if let Some(sources) = reread.as_ref().and_then(Reread::reread) {
+            config.lists.store(Arc::new(sources));
+        }
+        reload_lists(&config, &service).await;
+    }
+}
+
+/// Checks the list URLs every `interval` and reloads once any of them has
+/// changed. A failed check is retried sooner, backing off from
+/// `MIN_REFRESH_RETRY` up to the interval, while the old lists stay in use.
+async fn refresh_lists(
+    interval: Duration,
+    config: Arc<ListConfig>,
+    service: Arc<Service>,
+) {
+    let mut retry = None;
+    loop {
+        tokio::time::sleep(retry.unwrap_or(interval)).await;
+        // Rereading the config file may have changed the lists.
+        let mut urls = config.sources();
+        urls.retain(|source| remote::is_url(source));
+        let mut changed = false;
+        let mut failed = false;
+        for url in &urls {
+            match config.fetcher.refresh(url).await {
+                Ok(updated) => changed |= updated,
+                Err(e) => {
+                    warn!(url = %url, error = %e, "failed to refresh list");
+                    failed = true;
+                }
+            }
+        }
+        retry = failed.then(|| {
+            retry
+                .map_or(MIN_REFRESH_RETRY, |retry: Duration| retry * 2)
+                .min(interval)
+        });
+        if changed {
+            reload_lists(&config, &service).await;
+        } else if !failed {
+            debug!("remote lists unchanged");
+        }
+    }
+}
+
+/// Rereads the blocklists and swaps them in. Loading runs off the async
+/// workers, and if it fails the old lists stay in place.
+pub(crate) async fn reload_lists(config: &Arc<ListConfig>, service: &Service) {
+    if let Some(path) = &config.local_records {
+        match LocalRecords::load(path) {
+            Ok(local) => {
+                info!(names = local.len(), "reloaded local records");
+                service.local.store(Arc::new(local));
+            }
+            Err(e) => warn!(
+                error = %e,
+                "failed to reload local records, keeping old ones"
+            ),
+        }
+    }
+    for (policy, sources) in service.policies.iter().zip(&config.policies) {
+        let loaded =
+            Blocklists::load(config, &sources.denylists, &sources.allowlists)
+                .await;
+        match loaded {
+            Ok(lists) => {
+                info!(
+                    policy = %policy.name,
+                    denylist = lists.denylist.len(),
+                    allowlist = lists.allowlist.len(),
+                    "reloaded client policy"
+                );
+                policy.lists.store(Arc::new(lists));
+            }
+            Err(e) => warn!(
+                policy = %policy.name,
+                error = %e,
+                "failed to reload client policy, keeping old lists"
+            ),
+        }
+    }
+    let sources = config.lists.load_full();
+    let lists = match Blocklists::load(
+        config,
+        &sources.denylists,
+        &sources.allowlists,
+    )
+    .await
+    {
+        Ok(lists) => lists,
+        Err(e) => {
+            warn!(error = %e, "failed to reload blocklists, keeping old ones");
+            return;
+        }
+    };
+    let old = service.lists.load_full();
+    // Comparing against the old lists walks every entry, so it runs off the
+    // async workers too.
+    let compare = tokio::task::spawn_blocking(move || {
+        let added = lists.denylist.count_missing_from(&old.denylist);
+        let removed = old.denylist.count_missing_from(&lists.denylist);
+        (lists, added, removed)
+    });
+    let Ok((lists, added, removed)) = compare.await else {
+        return;
+    };
+    let (denied, allowed) = (lists.denylist.len(), lists.allowlist.len());
+    service.lists.store(Arc::new(lists));
+    info!(
+        denylist = denied,
+        allowlist = allowed,
+        added,
+        removed,
+        "reloaded blocklists"
+    );
+}
+
+/// Serves length-prefixed DNS messages (RFC 1035 4.2.2) until the client
+/// closes the connection or stays idle for longer than `TCP_IDLE_TIMEOUT`.
+/// DNS-over-TLS uses the same framing, so this also serves TLS streams.
+async fn handle_tcp_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    client: SocketAddr,
+    transport: Transport,
+    service: &Service,
+) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        let request =
+            match timeout(TCP_IDLE_TIMEOUT, read_tcp_message(&mut stream))
+                .await?
+            {
+                Ok(request) => request,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+        let _query = service.track_query();
+        let response =
+            handle_request(&request, client, transport, service).await?;
+        write_tcp_message(&mut stream, &response).await?;
+    }
+}
+
+/// Waits for the next connection from `accept`, which calls a listener's
+/// accept. A failed accept is logged and retried after a pause that grows
+/// while the failures continue.
+pub(crate) async fn accept<T, F>(mut accept: impl FnMut() -> F) -> T
+where
+    F: Future<Output = std::io::Result<T>>,
+{
+    let mut pause = MIN_ACCEPT_BACKOFF;
+    loop {
+        match accept().await {
+            Ok(accepted) => return accepted,
+            Err(e) => {
+                warn!(error = %e, "failed to accept a connection");
+                tokio::time::sleep(pause).await;
+                pause = MAX_ACCEPT_BACKOFF.min(pause * 2);
+            }
+        }
+    }
+}
+
+pub(crate) async fn read_tcp_message<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> std::io::Result<Vec<u8>> {
+    let mut len_buf = [0u8; 2];
+    stream.read_exact(&mut len_buf).await?;
+    let mut message = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+pub(crate) async fn write_tcp_message<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    message: &[u8],
+) -> std::io::Result<()> {
+    let mut buf = Vec::with_capacity(message.len() + 2);
+    buf.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    buf.extend_from_slice(message);
+    stream.write_all(&buf).await
+}
+
+/// How a query was answered, as logged.
+#[derive(Clone, Copy)]
+enum Action {
+    Blocked,
+    Cached,
+    /// Answered by the upstream server at this index.
+    Forwarded(usize),
+    /// Answered with the server's health.
+    Health,
+    /// Answered from the local records.
+    Local,
+    /// Answered with an error because forwarding is off.
+    NotForwarded,
+    Stale,
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Action::Blocked => "BLOCKED",
+            Action::Cached => "CACHED",
+            Action::Forwarded(_) => "FORWARDED",
+            Action::Health => "HEALTH",
+            Action::Local => "LOCAL",
+            Action::NotForwarded => "NOT_FORWARDED",
+            Action::Stale => "STALE",
+        }
+    }
+}
+
+/// Answers one query, recording it in the query log and dnstap output if
+/// they are enabled.
+pub(crate) async fn handle_request(
+    request: &[u8],
+    client: SocketAddr,
+    transport: Transport,
+    service: &Service,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let start = Instant::now();
+    let received = SystemTime::now();
+    if let Some(dnstap) = &service.dnstap {
+        dnstap.client_query(client, transport, received, request);
+    }
+    let mut record = QueryRecord::default();
+    let result =
+        respond(request, client.ip(), transport, service, &mut record).await;
+    if let Some(log) = &service.query_log {
+        log.write(client.ip(), &record, &result, start.elapsed());
+    }
+    if let (Some(dnstap), Ok(response)) = (&service.dnstap, &result) {
+        dnstap.client_response(client, transport, received, response);
+    }
+    result
+}
+
+/// Counts a query over the rate limit and returns what to answer it with:
+/// REFUSED, or nothing with --rate-limit-drop or if it isn't even a DNS
+/// header.
+fn rate_limited_response(request: &[u8], service: &Service) -> Option<Vec<u8>> {
+    service.metrics.rate_limited.fetch_add(1, Ordering::Relaxed);
+    if service.rate_limit_drop || request.len() < 12 || request[2] & 0x80 != 0 {
+        debug!("dropping query over rate limit");
+        return None;
+    }
+    debug!("refusing query over rate limit");
+    // Echo the question if it can be read, and otherwise just the header.
+    Some(
+        create_question_response(request, RCODE_REFUSED)
+            .unwrap_or_else(|_| create_error_response(request, RCODE_REFUSED)),
+    )
+}
+
+/// Answers one query and logs it, noting in `record` what became of it.
+/// The transport's span carries the client address, so the events here only
+/// add what was asked and what happened. Those spans are at error level so
+/// the address shows at any log level.
+async fn respond<'a>(
+    request: &[u8],
+    client: IpAddr,
+    transport: Transport,
+    service: &'a Service,
+    record: &mut QueryRecord<'a>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let start = Instant::now();
+    service.metrics.queries.fetch_add(1, Ordering::Relaxed);
+    // The limit comes before any answer, error answers included, so that a
+    // flood of malformed queries isn't reflected at full rate either. UDP
+    // queries were checked before their task was spawned.
+    if !matches!(transport, Transport::Udp) && service.over_rate_limit(client) {
+        record.decision = Some("RATE_LIMITED");
+        return rate_limited_response(request, service)
+            .ok_or_else(|| "Over the rate limit".into());
+    }
+    if request.len() >= 12 {
+        if request[2] & 0x80 != 0 {
+            // Answering a response could set two servers replying to each
+            // other forever.
+            info!("dropping response sent as a query");
+            return Err("Not a query".into());
+        }
+        let opcode = (request[2] >> 3) & 0x0F;
+        if opcode != 0 {
+            service
+                .metrics
+                .unsupported_opcodes
+                .fetch_add(1, Ordering::Relaxed);
+            info!(opcode, "rejecting query with unsupported opcode");
+            record.decision = Some("REJECTED");
+            return Ok(create_error_response(request, RCODE_NOTIMP));
+        }
+        let questions = u16::from_be_bytes([request[4], request[5]]);
+        if questions != 1 {
+            info!(questions, "rejecting query without exactly one question");
+            record.decision = Some("REJECTED");
+            return Ok(create_error_response(request, RCODE_FORMERR));
+        }
+    }
+    let question = match parse_dns_question(request) {
+        Ok(question) => question,
+        Err(e) => {
+            service
+                .metrics
+                .parse_failures
+                .fetch_add(1, Ordering::Relaxed);
+            // Names over the length limits are still readable enough to
+            // answer, unlike garbage.
+            if matches!(
+                e,
+                dns::Error::NameTooLong | dns::Error::InvalidLabelType
+            ) {
+                info!(error = %e, "rejecting query with an invalid name");
+                record.decision = Some("REJECTED");
+                return Ok(create_error_response(request, RCODE_FORMERR));
+            }
+            info!(error = %e, "dropping unparseable query");
+            return Err(e.into());
+        }
+    };
+    service.metrics.count_qtype(question.qtype);
+    record.name = Some(question.name.clone());
+    record.qtype = Some(question.qtype);
+    let result = answer(request, &question, client, service).await;
+    let latency = start.elapsed();
+    service.metrics.observe_latency(latency);
+    let latency_us = latency.as_micros() as u64;
+    match &result {
+        Ok((_, action)) => info!(
+            domain = %question.name,
+            qtype = question.qtype,
+            action = %action.as_str(),
+            latency_us,
+            "query"
+        ),
+        Err(e) => warn!(
+            domain = %question.name,
+            qtype = question.qtype,
+            error = %e,
+            latency_us,
+            "query failed"
+        ),
+    }
+    match result {
+        Ok((response, action)) => {
+            record.decision = Some(action.as_str());
+            if let Action::Forwarded(upstream) = action {
+                service.metrics.forwarded.fetch_add(1, Ordering::Relaxed);
+                record.upstream =
+                    Some(&service.upstreams.servers[upstream].name);
+            }
+            Ok(response)
+        }
+        Err(e) => {
+            record.decision = Some("ERROR");
+            record.error = Some(e.to_string());
+            // Fail fast rather than leave the client waiting out its
+            // timeout.
+            Ok(create_question_response(request, RCODE_SERVFAIL)?)
+        }
+    }
+}
+
+async fn answer(
+    request: &[u8],
+    question: &Question,
+    client: IpAddr,
+    service: &Service,
+) -> Result<(Vec<u8>, Action), Box<dyn std::error::Error>> {
+    if question.name == service.health_domain {
+        let response = create_health_response(request, question, service)?;
+        return Ok((response, Action::Health));
+    }
+    // The lists and record type policies are about internet names, so
+    // queries of other classes, such as CHAOS ones for version.bind, skip
+    // them and go upstream. So do protected names such as reverse lookups.
+    let filtered = matches!(question.qclass, CLASS_IN | CLASS_ANY)
+        && !service.is_protected(&question.name);
+    // Record type policies apply to every name, so they come before the
+    // lists.
+    let policy = if !filtered {
+        None
+    } else if service.blocked_qtypes.contains(&question.qtype) {
+        let soa = negative_soa(question.qclass, service.block.ttl);
+        let mut response = create_answer_response(request, 0, &[], &[soa])?;
+        if service.block.extended_errors {
+            add_extended_error(
+                &mut response,
+                EDE_FILTERED,
+                "record type blocked",
+            );
+        }
+        Some(response)
+    } else if question.qtype == TYPE_ANY {
+        match service.any_answer {
+            AnyAnswer::Hinfo => {
+                // CPU "RFC8482" and an empty OS, as the RFC suggests.
+                let hinfo = Record {
+                    owner: None,
+                    rtype: TYPE_HINFO,
+                    class: question.qclass,
+                    ttl: ANY_HINFO_TTL,
+                    data: b"\x07RFC8482\x00".to_vec(),
+                };
+                Some(create_answer_response(request, 0, &[hinfo], &[])?)
+            }
+            AnyAnswer::Notimp => {
+                Some(create_answer_response(request, RCODE_NOTIMP, &[], &[])?)
+            }
+            AnyAnswer::Forward => None,
+        }
+    } else {
+        None
+    };
+    match policy {
+        Some(_) if service.dry_run => {
+            service.metrics.would_block.fetch_add(1, Ordering::Relaxed);
+            info!(
+                domain = %question.name,
+                qtype = question.qtype,
+                "WOULD BLOCK by record type policy"
+            );
+        }
+        Some(response) => {
+            service.metrics.blocked.fetch_add(1, Ordering::Relaxed);
+            return Ok((response, Action::Blocked));
+        }
+        None => {}
+    }
+    let local = if matches!(question.qclass, CLASS_IN | CLASS_ANY) {
+        service.local.load().answer(
+            &question.name,
+            question.qtype,
+            service.local_ttl,
+        )
+    } else {
+        None
+    };
+    if let Some(answers) = local {
+        // Names answered here exist, so types they lack get NODATA.
+        let mut authority = Vec::new();
+        if answers.is_empty() {
+            authority.push(negative_soa(CLASS_IN, service.local_ttl));
+        }
+        let response =
+            create_answer_response(request, 0, &answers, &authority)?;
+        return Ok((response, Action::Local));
+    }
+    // An allowlisted name is let through whatever it is an alias for.
+    let mut check_cnames = service.block_cnames && filtered;
+    let blocked = if filtered {
+        let lists = service.lists_for(client).load();
+        let runtime = service.runtime.load();
+        match lists.decide(&runtime, &question.name, question.qtype) {
+            Verdict::Blocked(reason, addresses) => {
+                Some((reason.to_string(), addresses.to_vec()))
+            }
+            Verdict::Allowed(_) => {
+                check_cnames = false;
+                None
+            }
+            Verdict::Unlisted => None,
+        }
+    } else {
+        None
+    };
+    if blocked.is_some() && service.dry_run {
+        service.metrics.would_block.fetch_add(1, Ordering::Relaxed);
+        info!(
+            domain = %question.name,
+            qtype = question.qtype,
+            "WOULD BLOCK"
+        );
+    } else if let Some((reason, addresses)) = blocked {
+        let response = create_block_response(
+            request,
+            &service.block,
+            question,
+            &addresses,
+            &format!("blocked by {}", reason),
+        )?;
+        service.metrics.blocked.fetch_add(1, Ordering::Relaxed);
+        return Ok((response, Action::Blocked));
+    }
+    let (response, action) = resolve(request, question, service).await?;
+    let cloaked = if check_cnames {
+        cloaked_target(&response, question, client, service)
+    } else {
+        None
+    };
+    let Some((target, addresses)) = cloaked else {
+        return Ok((response, action));
+    };
+    if service.dry_run {
+        service.metrics.would_block.fetch_add(1, Ordering::Relaxed);
+        info!(
+            domain = %question.name,
+            cname = %target,
+            qtype = question.qtype,
+            "WOULD BLOCK"
+        );
+        return Ok((response, action));
+    }
+    info!(
+        domain = %question.name,
+        cname = %target,
+        "blocking answer with a listed CNAME"
+    );
+    let response = create_block_response(
+        request,
+        &service.block,
+        question,
+        &addresses,
+        &format!("CNAME target {} is blocked", target),
+    )?;
+    service.metrics.blocked.fetch_add(1, Ordering::Relaxed);
+    Ok((response, Action::Blocked))
+}
+
+/// Answers a query for the health domain: TXT queries get "ok" and the
+/// uptime in seconds, as in "ok" "uptime=3600", and others an empty answer.
+fn create_health_response(
+    request: &[u8],
+    question: &Question,
+    service: &Service,
+) -> Result<Vec<u8>, dns::Error> {
+    if question.qtype != TYPE_TXT {
+        let soa = negative_soa(question.qclass, 0);
+        return create_answer_response(request, 0, &[], &[soa]);
+    }
+    let uptime = format!("uptime={}", service.started.elapsed().as_secs());
+    let mut data = vec![2];
+    data.extend_from_slice(b"ok");
+    data.push(uptime.len() as u8);
+    data.extend_from_slice(uptime.as_bytes());
+    let txt = Record {
+        owner: None,
+        rtype: TYPE_TXT,
+        class: question.qclass,
+        ttl: 0,
+        data,
+    };
+    create_answer_response(request, 0, &[txt], &[])
+}
+
+/// Finds the first name the answer's CNAMEs lead to that the lists block,
+/// with the addresses its entry gives.
+fn cloaked_target(
+    response: &[u8],
+    question: &Question,
+    client: IpAddr,
+    service: &Service,
+) -> Option<(String, Vec<IpAddr>)> {
+    let chain = cname_chain(response, &question.name).ok()?;
+    let lists = service.lists_for(client).load();
+    let runtime = service.runtime.load();
+    chain.iter().find_map(|target| {
+        match lists.decide(&runtime, target, question.qtype) {
+            Verdict::Blocked(_, addresses) => {
+                Some((target.clone(), addresses.to_vec()))
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Answers a query the lists let through: from the cache, or else from the
+/// upstreams, or from a stale cached answer if they can't be reached.
+async fn resolve(
+    request: &[u8],
+    question: &Question,
+    service: &Service,
+) -> Result<(Vec<u8>, Action), Box<dyn std::error::Error>> {
+    let key = (
+        question.name.clone(),
+        question.qtype,
+        question.qclass,
+        EdnsFlags::of(request),
+    );
+    if let Some(cache) = &service.cache {
+        if let Some(mut response) = cache.get(&key) {
+            service.metrics.cache_hits.fetch_add(1, Ordering::Relaxed);
+            match_request(&mut response, request);
+            return Ok((response, Action::Cached));
+        }
+        service.metrics.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+    if let Some(rcode) = service.no_forward {
+        let response = create_question_response(request, rcode)?;
+        return Ok((response, Action::NotForwarded));
+    }
+    // Caching the answer within the lookup means a query arriving right
+    // after it finishes finds the answer cached rather than looking it up
+    // again.
+    let lookup = async {
+        let result = service
+            .upstreams
+            .forward(request, &service.metrics, service.dnstap.as_ref())
+            .await;
+        if let (Ok((response, _)), Some(cache)) = (&result, &service.cache) {
+            cache.insert(key.clone(), response);
+        }
+        result
+    };
+    let (result, shared) = service.lookups.run(&key, lookup).await;
+    let error = match result {
+        Ok((mut response, upstream)) => {
+            if shared {
+                service.metrics.deduplicated.fetch_add(1, Ordering::Relaxed);
+                match_request(&mut response, request);
+            }
+            return Ok((response, Action::Forwarded(upstream)));
+        }
+        Err(e) => e,
+    };
+    // A stale answer beats none while the upstreams are unreachable.
+    let stale = service
+        .cache
+        .as_ref()
+        .and_then(|cache| cache.get_stale(&key));
+    let Some(mut response) = stale else {
+        return Err(error.into());
+    };
+    warn!(domain = %question.name, error, "answering from stale cache");
+    service
+        .metrics
+        .stale_answers
+        .fetch_add(1, Ordering::Relaxed);
+    match_request(&mut response, request);
+    Ok((response, Action::Stale))
+}
+
+/// Answers a blocked query the way `block` says, or with `addresses` if the
+/// list entry gave any.
+fn create_block_response(
+    request: &[u8],
+    block: &BlockAnswer,
+    question: &Question,
+    addresses: &[IpAddr],
+    reason: &str,
+) -> Result<Vec<u8>, dns::Error> {
+    // An entry's own addresses are given out like the zeroip ones.
+    let mode = if addresses.is_empty() {
+        block.mode
+    } else {
+        BlockMode::Zeroip
+    };
+    let rcode = match mode {
+        BlockMode::Nxdomain => RCODE_NXDOMAIN,
+        BlockMode::Nodata | BlockMode::Zeroip => 0,
+        BlockMode::Refused => RCODE_REFUSED,
+    };
+    let sinkhole = [IpAddr::V4(block.ipv4), IpAddr::V6(block.ipv6)];
+    let addresses = if addresses.is_empty() {
+        &sinkhole[..]
+    } else {
+        addresses
+    };
+    let mut answers = Vec::new();
+    if mode == BlockMode::Zeroip && question.qclass == CLASS_IN {
+        // Other query types, and those the entry has no address for, get an
+        // empty NOERROR answer.
+        for address in addresses {
+            let data = match (question.qtype, address) {
+                (TYPE_A, IpAddr::V4(ip)) => ip.octets().to_vec(),
+                (TYPE_AAAA, IpAddr::V6(ip)) => ip.octets().to_vec(),
+                _ => continue,
+            };
+            answers.push(Record {
+                owner: None,
+                rtype: question.qtype,
+                class: CLASS_IN,
+                ttl: block.ttl,
+                data,
+            });
+        }
+    }
+    // Negative answers carry an SOA so clients cache them for the block TTL.
+    let mut authority = Vec::new();
+    if answers.is_empty() && rcode != RCODE_REFUSED {
+        authority.push(negative_soa(question.qclass, block.ttl));
+    }
+    let mut response =
+        create_answer_response(request, rcode, &answers, &authority)?;
+    if block.extended_errors {
+        add_extended_error(&mut response, EDE_FILTERED, reason);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{encode_name, TYPE_CNAME};
+    use clap::Parser;
+    use std::path::PathBuf;
+    use tokio::{net::TcpStream, task::JoinSet};
+
+    /// Builds the upstream's answer to a query, or `None` to leave it
+    /// unanswered.
+    type Answerer = fn(&[u8]) -> Option<Vec<u8>>;
+
+    /// A UDP upstream answering queries after a delay, counting the queries
+    /// it gets and the most it had unanswered at once.
+    struct MockUpstream {
+        addr: SocketAddr,
+        queries: Arc<AtomicUsize>,
+        peak: Arc<AtomicUsize>,
+    }
+
+    impl MockUpstream {
+        /// An upstream answering every query with 10.0.0.1.
+        async fn start(delay: Duration) -> Self {
+            Self::answering(delay, |request| {
+                Some(answer(request, [10, 0, 0, 1]))
+            })
+            .await
+        }
+
+        async fn answering(delay: Duration, answer: Answerer) -> Self {
+            let socket =
+                Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let queries = Arc::new(AtomicUsize::new(0));
+            let peak = Arc::new(AtomicUsize::new(0));
+            let waiting = Arc::new(AtomicUsize::new(0));
+            let mock = Self {
+                addr: socket.local_addr().unwrap(),
+                queries: Arc::clone(&queries),
+                peak: Arc::clone(&peak),
+            };
+            tokio::spawn(async move {
+                let mut buf = [0; MAX_UDP_PAYLOAD];
+                while let Ok((size, from)) = socket.recv_from(&mut buf).await {
+                    queries.fetch_add(1, Ordering::SeqCst);
+                    let now = waiting.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    let request = buf[..size].to_vec();
+                    let (socket, waiting) =
+                        (Arc::clone(&socket), Arc::clone(&waiting));
+                    tokio::spawn(async move {
+                        tokio::time::sleep(delay).await;
+                        waiting.fetch_sub(1, Ordering::SeqCst);
+                        if let Some(response) = answer(&request) {
+                            let _ = socket.send_to(&response, from).await;
+                        }
+                    });
+                }
+            });
+            mock
+        }
+    }
+
+    /// A response to `request` with one A record for `address`.
+    fn answer(request: &[u8], address: [u8; 4]) -> Vec<u8> {
+        let record = Record {
+            owner: None,
+            rtype: TYPE_A,
+            class: CLASS_IN,
+            ttl: 60,
+            data: address.to_vec(),
+        };
+        create_answer_response(request, 0, &[record], &[]).unwrap()
+    }
+
+    /// A file holding a list or other input for the length of a test.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(text: &str) -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let name = format!(
+                "dnsfilter-test-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            );
+            let path = std::env::temp_dir().join(name);
+            std::fs::write(&path, text).unwrap();
+            Self(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// A server forwarding to `upstream` without resends, so the upstream
+    /// sees each lookup once.
+    async fn server(upstream: &MockUpstream, flags: &[&str]) -> Server {
+        let upstream = upstream.addr.to_string();
+        let args = [
+            "dnsfilter",
+            "--list",
+            "/dev/null",
+            "--dns",
+            &upstream,
+            "--upstream-retries",
+            "0",
+        ];
+        let args = Args::parse_from(args.iter().chain(flags));
+        Server::new(&args).await.unwrap()
+    }
+
+    fn query(name: &str, id: u16) -> Vec<u8> {
+        let mut message = vec![0, 0, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        message[0..2].copy_from_slice(&id.to_be_bytes());
+        message.extend_from_slice(&encode_name(name));
+        message.extend_from_slice(&TYPE_A.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message
+    }
+
+    /// A query for `name` of type `qtype`.
+    fn typed_query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut message = query(name, 1);
+        let at = message.len() - 4;
+        message[at..at + 2].copy_from_slice(&qtype.to_be_bytes());
+        message
+    }
+
+    async fn ask(
+        service: &Service,
+        request: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        ask_from(service, request, Ipv4Addr::LOCALHOST.into()).await
+    }
+
+    async fn ask_from(
+        service: &Service,
+        request: &[u8],
+        client: IpAddr,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let client = SocketAddr::from((client, 5300));
+        handle_request(request, client, Transport::Udp, service).await
+    }
+
+    fn rcode(response: &[u8]) -> u8 {
+        response[3] & 0x0F
+    }
+
+    /// The 16-bit field at `i`, such as a record count in the header.
+    fn count(message: &[u8], i: usize) -> u16 {
+        u16::from_be_bytes([message[i], message[i + 1]])
+    }
+
+    /// Starts answering queries over TCP, returning the address to connect
+    /// to.
+    async fn listen_tcp(server: &Server) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_tcp(listener, Arc::clone(&server.service)));
+        addr
+    }
+
+    async fn ask_tcp(stream: &mut TcpStream, request: &[u8]) -> Vec<u8> {
+        write_tcp_message(stream, request).await.unwrap();
+        timeout(Duration::from_secs(2), read_tcp_message(stream))
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    /// Sends `requests` all at once and returns the responses in order.
+    async fn ask_together(
+        service: &Arc<Service>,
+        requests: Vec<Vec<u8>>,
+    ) -> Vec<Vec<u8>> {
+        let mut tasks = JoinSet::new();
+        for (i, request) in requests.into_iter().enumerate() {
+            let service = Arc::clone(service);
+            tasks.spawn(
+                async move { (i, ask(&service, &request).await.unwrap()) },
+            );
+        }
+        let mut responses = tasks.join_all().await;
+        responses.sort_by_key(|(i, _)| *i);
+        responses
+            .into_iter()
+            .map(|(_, response)| response)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn answers_queries_over_tcp() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let server = server(&upstream, &[]).await;
+        let addr = listen_tcp(&server).await;
+
+        // Clients that hang up partway, in the length prefix or in the
+        // message, cost only their own connection.
+        for partial in [&[0][..], &[0, 29, 0x12, 0x34]] {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(partial).await.unwrap();
+        }
+
+        // Several queries may follow one another on a connection.
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        for (id, name) in [(1, "a.example.com"), (2, "b.example.com")] {
+            let request = query(name, id);
+            let response = ask_tcp(&mut stream, &request).await;
+            assert_eq!(response, answer(&request, [10, 0, 0, 1]));
+        }
+        assert_eq!(upstream.queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn repeated_queries_are_answered_from_the_cache() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let server = server(&upstream, &[]).await;
+        let first = query("example.com", 1);
+        let second = query("example.com", 2);
+        let response = ask(&server.service, &first).await.unwrap();
+        assert_eq!(response, answer(&first, [10, 0, 0, 1]));
+        let response = ask(&server.service, &second).await.unwrap();
+        assert_eq!(response, answer(&second, [10, 0, 0, 1]));
+
+        assert_eq!(upstream.queries.load(Ordering::SeqCst), 1);
+        let metrics = &server.service.metrics;
+        assert_eq!(metrics.cache_hits.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn blocks_and_forwards_over_tcp_as_over_udp() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let list = TempFile::new("ads.example\n");
+        let server = server(&upstream, &["--list", list.path()]).await;
+        let addr = listen_tcp(&server).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        for over_tcp in [true, false] {
+            for (name, expected) in
+                [("ads.example", RCODE_NXDOMAIN), ("example.com", 0)]
+            {
+                let request = query(name, 1);
+                let response = if over_tcp {
+                    ask_tcp(&mut stream, &request).await
+                } else {
+                    ask(&server.service, &request).await.unwrap()
+                };
+                assert!(dns::is_reply_to(&response, &request));
+                assert_eq!(
+                    rcode(&response),
+                    expected,
+                    "{name}, tcp {over_tcp}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_upstream() {
+        let silent = MockUpstream::answering(Duration::ZERO, |_| None).await;
+        let working = MockUpstream::start(Duration::ZERO).await;
+        let working_addr = working.addr.to_string();
+        let flags = ["--dns", &working_addr, "--upstream-timeout", "100ms"];
+        let server = server(&silent, &flags).await;
+
+        let request = query("example.com", 1);
+        let response = ask(&server.service, &request).await.unwrap();
+        assert_eq!(response, answer(&request, [10, 0, 0, 1]));
+        assert_eq!(silent.queries.load(Ordering::SeqCst), 1);
+        assert_eq!(working.queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_queries_without_exactly_one_question() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let server = server(&upstream, &[]).await;
+        for questions in [0u16, 2] {
+            let mut request = query("example.com", 1);
+            request[4..6].copy_from_slice(&questions.to_be_bytes());
+            let response = ask(&server.service, &request).await.unwrap();
+            assert_eq!(rcode(&response), RCODE_FORMERR);
+        }
+        assert_eq!(upstream.queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn queries_of_another_class_or_do_bit_miss_the_cache() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let server = server(&upstream, &[]).await;
+        let plain = query("example.com", 1);
+        let mut chaos = plain.clone();
+        let class = chaos.len() - 2;
+        chaos[class..].copy_from_slice(&3u16.to_be_bytes());
+        let mut dnssec = plain.clone();
+        dnssec[11] = 1;
+        dnssec.extend_from_slice(&[0, 0, 41, 0x04, 0xD0, 0, 0, 0x80, 0, 0, 0]);
+
+        for request in [&plain, &chaos, &dnssec] {
+            let response = ask(&server.service, request).await.unwrap();
+            assert!(dns::is_reply_to(&response, request));
+        }
+        assert_eq!(upstream.queries.load(Ordering::SeqCst), 3);
+        let metrics = &server.service.metrics;
+        assert_eq!(metrics.cache_hits.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn dry_run_forwards_what_it_would_block() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let list = TempFile::new("ads.example\n");
+        let flags = ["--list", list.path(), "--dry-run"];
+        let server = server(&upstream, &flags).await;
+        let request = query("ads.example", 1);
+        let response = ask(&server.service, &request).await.unwrap();
+        assert_eq!(response, answer(&request, [10, 0, 0, 1]));
+        assert_eq!(upstream.queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn answers_only_standard_queries() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let server = server(&upstream, &[]).await;
+        let service = &server.service;
+
+        // A STATUS query.
+        let mut request = query("example.com", 1);
+        request[2] |= 2 << 3;
+        let response = ask(service, &request).await.unwrap();
+        assert_eq!(rcode(&response), RCODE_NOTIMP);
+
+        // Responses and messages too short for a header get no answer.
+        let mut response_as_query = query("example.com", 1);
+        response_as_query[2] |= 0x80;
+        assert!(ask(service, &response_as_query).await.is_err());
+        assert!(ask(service, &[0; 5]).await.is_err());
+
+        assert_eq!(upstream.queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn answers_blocked_queries_as_the_block_mode_says() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let list = TempFile::new("ads.example\n");
+        let a = typed_query("ads.example", TYPE_A);
+        let aaaa = typed_query("ads.example", TYPE_AAAA);
+        let soa = || [negative_soa(CLASS_IN, 300)];
+        let record = |rtype, data: &[u8]| Record {
+            owner: None,
+            rtype,
+            class: CLASS_IN,
+            ttl: 300,
+            data: data.to_vec(),
+        };
+        let expect = |request: &[u8], rcode, answers: &[Record]| {
+            let authority = if answers.is_empty() && rcode != RCODE_REFUSED {
+                &soa()[..]
+            } else {
+                &[]
+            };
+            create_answer_response(request, rcode, answers, authority).unwrap()
+        };
+
+        for (mode, answer_a, answer_aaaa) in [
+            (
+                "nxdomain",
+                expect(&a, RCODE_NXDOMAIN, &[]),
+                expect(&aaaa, RCODE_NXDOMAIN, &[]),
+            ),
+            ("nodata", expect(&a, 0, &[]), expect(&aaaa, 0, &[])),
+            (
+                "null-ip",
+                expect(&a, 0, &[record(TYPE_A, &[0; 4])]),
+                expect(&aaaa, 0, &[record(TYPE_AAAA, &[0; 16])]),
+            ),
+            (
+                "refused",
+                expect(&a, RCODE_REFUSED, &[]),
+                expect(&aaaa, RCODE_REFUSED, &[]),
+            ),
+        ] {
+            let flags = ["--list", list.path(), "--block-mode", mode];
+            let server = server(&upstream, &flags).await;
+            let response = ask(&server.service, &a).await.unwrap();
+            assert_eq!(response, answer_a, "{mode}");
+            let response = ask(&server.service, &aaaa).await.unwrap();
+            assert_eq!(response, answer_aaaa, "{mode}");
+        }
+        assert_eq!(upstream.queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn answers_servfail_when_no_upstream_does() {
+        let dead = MockUpstream::answering(Duration::ZERO, |_| None).await;
+        let server = server(&dead, &["--upstream-timeout", "100ms"]).await;
+        let request = query("example.com", 0xBEEF);
+        let response = ask(&server.service, &request).await.unwrap();
+        assert!(dns::is_reply_to(&response, &request));
+        assert_eq!(rcode(&response), RCODE_SERVFAIL);
+        assert_eq!(dead.queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn record_type_policies_apply_to_every_name() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let flags = ["--block-qtype", "AAAA,HTTPS"];
+        let blocking = server(&upstream, &flags).await;
+        for qtype in [TYPE_AAAA, 65] {
+            let request = typed_query("example.com", qtype);
+            let response = ask(&blocking.service, &request).await.unwrap();
+            assert_eq!(rcode(&response), 0);
+            assert_eq!(count(&response, 6), 0);
+            assert_eq!(count(&response, 8), 1);
+        }
+        let request = typed_query("example.com", TYPE_ANY);
+        let response = ask(&blocking.service, &request).await.unwrap();
+        let hinfo = Record {
+            owner: None,
+            rtype: TYPE_HINFO,
+            class: CLASS_IN,
+            ttl: ANY_HINFO_TTL,
+            data: b"\x07RFC8482\x00".to_vec(),
+        };
+        let expected = create_answer_response(&request, 0, &[hinfo], &[]);
+        assert_eq!(response, expected.unwrap());
+        assert_eq!(upstream.queries.load(Ordering::SeqCst), 0);
+
+        let request = typed_query("example.com", TYPE_A);
+        let response = ask(&blocking.service, &request).await.unwrap();
+        assert_eq!(response, answer(&request, [10, 0, 0, 1]));
+
+        let notimp = server(&upstream, &["--any-answer", "notimp"]).await;
+        let request = typed_query("example.com", TYPE_ANY);
+        let response = ask(&notimp.service, &request).await.unwrap();
+        assert_eq!(rcode(&response), RCODE_NOTIMP);
+        assert_eq!(upstream.queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn answers_with_the_addresses_a_list_entry_gives() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let list = TempFile::new("nas.example 10.0.0.5 fd00::5\n");
+        let server = server(&upstream, &["--list", list.path()]).await;
+        let record = |rtype, data: Vec<u8>| Record {
+            owner: None,
+            rtype,
+            class: CLASS_IN,
+            ttl: 300,
+            data,
+        };
+        let v4 = Ipv4Addr::new(10, 0, 0, 5).octets().to_vec();
+        let v6 = "fd00::5".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        for (qtype, data) in [(TYPE_A, v4), (TYPE_AAAA, v6)] {
+            let request = typed_query("nas.example", qtype);
+            let response = ask(&server.service, &request).await.unwrap();
+            let expected = create_answer_response(
+                &request,
+                0,
+                &[record(qtype, data)],
+                &[],
+            );
+            assert_eq!(response, expected.unwrap());
+        }
+        assert_eq!(upstream.queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn identical_queries_share_one_upstream_lookup() {
+        let upstream = MockUpstream::start(Duration::from_millis(100)).await;
+        let server = server(&upstream, &["--cache-size", "0"]).await;
+        let requests: Vec<_> =
+            (0..10).map(|id| query("example.com", id)).collect();
+        let responses = ask_together(&server.service, requests.clone()).await;
+
+        assert_eq!(upstream.queries.load(Ordering::SeqCst), 1);
+        let metrics = &server.service.metrics;
+        assert_eq!(metrics.deduplicated.load(Ordering::Relaxed), 9);
+        for (request, response) in requests.iter().zip(&responses) {
+            assert_eq!(response, &answer(request, [10, 0, 0, 1]));
+        }
+    }
+
+    /// The TTL of the first record after the question.
+    fn first_ttl(response: &[u8]) -> u32 {
+        let record = dns::skip_name(response, 12).unwrap() + 4;
+        let ttl = dns::skip_name(response, record).unwrap() + 4;
+        u32::from_be_bytes(response[ttl..ttl + 4].try_into().unwrap())
+    }
+
+    #[tokio::test]
+    async fn blocked_answers_carry_the_block_ttl() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let list = TempFile::new("ads.example\n");
+        for mode in ["nxdomain", "nodata", "null-ip"] {
+            let flags = [
+                "--list",
+                list.path(),
+                "--block-mode",
+                mode,
+                "--block-ttl",
+                "42",
+            ];
+            let server = server(&upstream, &flags).await;
+            let request = query("ads.example", 1);
+            let response = ask(&server.service, &request).await.unwrap();
+            // The address in null-ip mode, or else the SOA.
+            assert_eq!(count(&response, 6) + count(&response, 8), 1);
+            assert_eq!(first_ttl(&response), 42, "{mode}");
+        }
+    }
+
+    #[tokio::test]
+    async fn regex_denylist_blocks_what_no_list_entry_decides() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let regexes = TempFile::new("^ad[0-9]+\\.\n");
+        let allowlist = TempFile::new("ad2.example\n");
+        let flags = [
+            "--regex-denylist",
+            regexes.path(),
+            "--allowlist",
+            allowlist.path(),
+        ];
+        let server = server(&upstream, &flags).await;
+        for (name, expected) in [
+            ("ad1.example", RCODE_NXDOMAIN),
+            ("ad2.example", 0),
+            ("ads.example", 0),
+        ] {
+            let response = ask(&server.service, &query(name, 1)).await.unwrap();
+            assert_eq!(rcode(&response), expected, "{name}");
+        }
+        assert_eq!(upstream.queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn forwards_queries_for_the_root() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let list = TempFile::new("zip\n");
+        let server = server(&upstream, &["--list", list.path()]).await;
+        let request = query(".", 1);
+        let response = ask(&server.service, &request).await.unwrap();
+        assert_eq!(response, answer(&request, [10, 0, 0, 1]));
+    }
+
+    #[tokio::test]
+    async fn client_policies_give_clients_their_own_lists() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let kids = TempFile::new("games.example\n");
+        let everyone = TempFile::new("ads.example\n");
+        let policy = TempFile::new(&format!(
+            "[[policy]]\n\
+             name = \"kids\"\n\
+             clients = [\"192.168.1.20\", \"10.0.1.0/24\"]\n\
+             list = [{:?}]\n",
+            kids.path()
+        ));
+        let flags =
+            ["--list", everyone.path(), "--client-policy", policy.path()];
+        let server = server(&upstream, &flags).await;
+        let rcode_for = |client: &str, name: &str| {
+            let client = client.parse().unwrap();
+            let service = &server.service;
+            let request = query(name, 1);
+            async move {
+                let response = ask_from(service, &request, client).await;
+                rcode(&response.unwrap())
+            }
+        };
+        for kid in ["192.168.1.20", "10.0.1.7", "::ffff:10.0.1.7"] {
+            assert_eq!(rcode_for(kid, "games.example").await, RCODE_NXDOMAIN);
+            assert_eq!(rcode_for(kid, "ads.example").await, 0);
+        }
+        for adult in ["192.168.1.21", "10.0.2.7"] {
+            assert_eq!(rcode_for(adult, "games.example").await, 0);
+            assert_eq!(rcode_for(adult, "ads.example").await, RCODE_NXDOMAIN);
+        }
+    }
+
+    /// A DNS-over-HTTPS upstream over plain HTTP, answering every query
+    /// with 10.0.0.1, returning its URL.
+    async fn doh_upstream() -> String {
+        use http_body_util::{BodyExt, Full};
+        use hyper::{
+            body::{Bytes, Incoming},
+            header::CONTENT_TYPE,
+            server::conn::http1,
+            service::service_fn,
+            Method, Request, Response,
+        };
+        use hyper_util::rt::TokioIo;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url =
+            format!("http://{}/dns-query", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let handler = service_fn(|request: Request<Incoming>| async {
+                    assert_eq!(request.method(), Method::POST);
+                    assert_eq!(request.uri().path(), "/dns-query");
+                    let query = request.into_body().collect().await?;
+                    let response = answer(&query.to_bytes(), [10, 0, 0, 1]);
+                    let response = Response::builder()
+                        .header(CONTENT_TYPE, dns::DNS_MESSAGE)
+                        .body(Full::new(Bytes::from(response)))
+                        .unwrap();
+                    Ok::<_, hyper::Error>(response)
+                });
+                tokio::spawn(
+                    http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), handler),
+                );
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn forwards_over_dns_over_https() {
+        let url = doh_upstream().await;
+        let args = ["dnsfilter", "--list", "/dev/null", "--upstream-doh", &url];
+        let server = Server::new(&Args::parse_from(args)).await.unwrap();
+        for id in [1, 2] {
+            let request = query(&format!("host{id}.example.com"), id);
+            let response = ask(&server.service, &request).await.unwrap();
+            assert_eq!(response, answer(&request, [10, 0, 0, 1]));
+        }
+
+        let args = ["dnsfilter", "--upstream-doh", "dns.example/dns-query"];
+        assert!(Server::new(&Args::parse_from(args)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn no_forward_answers_unlisted_names_itself() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let list = TempFile::new("ads.example\n");
+        let flags = ["--list", list.path(), "--no-forward"];
+        let offline = server(&upstream, &flags).await;
+        for (name, expected) in [
+            ("example.com", RCODE_SERVFAIL),
+            ("ads.example", RCODE_NXDOMAIN),
+        ] {
+            let request = query(name, 1);
+            let response = ask(&offline.service, &request).await.unwrap();
+            assert!(dns::is_reply_to(&response, &request));
+            assert_eq!(rcode(&response), expected, "{name}");
+        }
+
+        let flags = ["--no-forward", "--no-forward-rcode", "refused"];
+        let refusing = server(&upstream, &flags).await;
+        let response = ask(&refusing.service, &query("example.com", 1))
+            .await
+            .unwrap();
+        assert_eq!(rcode(&response), RCODE_REFUSED);
+        assert_eq!(upstream.queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn arpa_entries_leave_reverse_lookups_alone() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let list = TempFile::new("arpa\n");
+        let server = server(&upstream, &["--list", list.path()]).await;
+        for name in ["4.3.2.1.in-addr.arpa", "1.0.0.0.ip6.arpa"] {
+            let request = typed_query(name, 12);
+            let response = ask(&server.service, &request).await.unwrap();
+            assert_eq!(rcode(&response), 0, "{name}");
+        }
+        assert_eq!(upstream.queries.load(Ordering::SeqCst), 2);
+        // The rest of arpa stays blocked.
+        let response =
+            ask(&server.service, &query("home.arpa", 1)).await.unwrap();
+        assert_eq!(rcode(&response), RCODE_NXDOMAIN);
+    }
+
+    #[tokio::test]
+    async fn blocks_answers_cloaked_behind_cnames() {
+        let upstream = MockUpstream::answering(Duration::ZERO, |request| {
+            let records = [
+                Record {
+                    owner: None,
+                    rtype: TYPE_CNAME,
+                    class: CLASS_IN,
+                    ttl: 60,
+                    data: encode_name("metrics.tracker.example"),
+                },
+                Record {
+                    owner: Some("metrics.tracker.example".to_owned()),
+                    rtype: TYPE_A,
+                    class: CLASS_IN,
+                    ttl: 60,
+                    data: vec![192, 0, 2, 1],
+                },
+            ];
+            create_answer_response(request, 0, &records, &[]).ok()
+        })
+        .await;
+        let list = TempFile::new("tracker.example\n");
+        let request = query("stats.shop.example", 1);
+
+        let trusting = server(&upstream, &["--list", list.path()]).await;
+        let response = ask(&trusting.service, &request).await.unwrap();
+        assert_eq!((rcode(&response), count(&response, 6)), (0, 2));
+
+        let flags = ["--list", list.path(), "--block-cnames"];
+        let cloak_aware = server(&upstream, &flags).await;
+        let response = ask(&cloak_aware.service, &request).await.unwrap();
+        assert_eq!(rcode(&response), RCODE_NXDOMAIN);
+    }
+
+    #[tokio::test]
+    async fn answers_the_health_domain_itself() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let healthy = server(&upstream, &[]).await;
+        let request = typed_query("health.dnsfilter.local", TYPE_TXT);
+        let response = ask(&healthy.service, &request).await.unwrap();
+        assert_eq!((rcode(&response), count(&response, 6)), (0, 1));
+        let text = &response[request.len()..];
+        assert!(text.windows(3).any(|bytes| bytes == b"\x02ok"));
+        assert!(text.windows(7).any(|bytes| bytes == b"uptime="));
+
+        let request = query("health.dnsfilter.local", 1);
+        let response = ask(&healthy.service, &request).await.unwrap();
+        assert_eq!((rcode(&response), count(&response, 6)), (0, 0));
+        assert_eq!(upstream.queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_names_longer_than_255_octets() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let strict = server(&upstream, &[]).await;
+        let label = "a".repeat(63);
+        let name = format!("{label}.{label}.{label}.{label}");
+        let response = ask(&strict.service, &query(&name, 1)).await.unwrap();
+        assert_eq!(rcode(&response), RCODE_FORMERR);
+        assert_eq!(upstream.queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn explains_blocks_in_extended_errors() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let list = TempFile::new("ads.example\n");
+        let flags = ["--list", list.path(), "--extended-errors"];
+        let explaining = server(&upstream, &flags).await;
+        let mut request = query("ads.example", 1);
+        request[11] = 1;
+        // An OPT record advertising 1232 bytes.
+        request.extend_from_slice(&[0, 0, 41, 0x04, 0xD0, 0, 0, 0, 0, 0, 0]);
+        let response = ask(&explaining.service, &request).await.unwrap();
+        assert_eq!(rcode(&response), RCODE_NXDOMAIN);
+        assert_eq!(count(&response, 10), 1);
+        // The OPT record comes last, and the EDE option last in it: code 15,
+        // its length, then the INFO-CODE and the reason.
+        let reason = b"blocked by denylist entry ads.example";
+        let ede = response.len() - reason.len() - 6;
+        assert_eq!(count(&response, ede), 15);
+        assert_eq!(usize::from(count(&response, ede + 2)), reason.len() + 2);
+        assert_eq!(count(&response, ede + 4), EDE_FILTERED);
+        assert_eq!(&response[ede + 6..], reason);
+    }
+
+    #[tokio::test]
+    async fn blocked_edns_queries_get_an_opt_record_back() {
+        let upstream = MockUpstream::start(Duration::ZERO).await;
+        let list = TempFile::new("ads.example\n");
+        let blocking = server(&upstream, &["--list", list.path()]).await;
+        // "dig ads.example +edns", with RD and AD set and a client cookie.
+        let dig: &[u8] = b"\x5b\x1f\x01\x20\x00\x01\x00\x00\x00\x00\x00\x01\
+            \x03ads\x07example\x00\x00\x01\x00\x01\
+            \x00\x00\x29\x04\xd0\x00\x00\x00\x00\x00\x0c\
+            \x00\x0a\x00\x08\x9d\x3e\x61\xc2\x05\x1a\x8b\x47";
+        let response = ask(&blocking.service, dig).await.unwrap();
+        assert_eq!(rcode(&response), RCODE_NXDOMAIN);
+        assert_eq!(count(&response, 10), 1);
+        let [high, low] = (MAX_UDP_PAYLOAD as u16).to_be_bytes();
+        assert!(response.ends_with(&[0, 0, 41, high, low, 0, 0, 0, 0, 0, 0]));
+
+        // The same with +dnssec, whose DO bit comes back.
+        let mut dnssec = dig.to_vec();
+        dnssec[dig.len() - 16] = 0x80;
+        let response = ask(&blocking.service, &dnssec).await.unwrap();
+        assert!(response.ends_with(&[41, high, low, 0, 0, 0x80, 0, 0, 0]));
+
+        // Without EDNS there is no OPT record to echo.
+        let response = ask(&blocking.service, &query("ads.example", 1))
+            .await
+            .unwrap();
+        assert_eq!(count(&response, 10), 0);
+    }
+
+    #[tokio::test]
+    async fn upstream_lookups_are_capped() {
+        let upstream = MockUpstream::start(Duration::from_millis(50)).await;
+        let flags = [
+            "--cache-size",
+            "0",
+            "--max-concurrent",
+            "2",
+            "--upstream-timeout",
+            "2s",
+        ];
+        let capped = server(&upstream, &flags).await;
+        let requests: Vec<_> = (0..6)
+            .map(|id| query(&format!("host{id}.example.com"), id))
+            .collect();
+        let responses = ask_together(&capped.service, requests).await;
+
+        assert_eq!(upstream.queries.load(Ordering::SeqCst), 6);
+        assert_eq!(upstream.peak.load(Ordering::SeqCst), 2);
+        assert!(responses.iter().all(|response| rcode(response) == 0));
+    }
+}
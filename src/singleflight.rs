@@ -0,0 +1,130 @@
+//! Collapses identical upstream lookups that are in progress at the same
+//! time into one, so a burst of queries for a popular name costs a single
+//! upstream exchange. Lookups are keyed like the cache, so queries only
+//! share an answer if they agree on EDNS and DNSSEC flags as well as on the
+//! question.
+
+use crate::cache::CacheKey;
+use std::{collections::HashMap, future::Future, sync::Mutex};
+use tokio::sync::watch::Sender;
+
+/// The response and the index of the upstream server that gave it.
+type Outcome = Result<(Vec<u8>, usize), &'static str>;
+
+#[derive(Default)]
+pub struct SingleFlight {
+    /// Lookups in progress, each publishing its outcome once it has one.
+    pending: Mutex<HashMap<CacheKey, Sender<Option<Outcome>>>>,
+}
+
+impl SingleFlight {
+    /// Runs `lookup` unless one for the same key is already running, in which
+    /// case that one's outcome is shared instead. Also returns whether the
+    /// outcome came from another query's lookup.
+    pub async fn run(
+        &self,
+        key: &CacheKey,
+        lookup: impl Future<Output = Outcome>,
+    ) -> (Outcome, bool) {
+        let waiting = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(key) {
+                Some(sender) => Some(sender.subscribe()),
+                None => {
+                    pending.insert(key.clone(), Sender::new(None));
+                    None
+                }
+            }
+        };
+        if let Some(mut receiver) = waiting {
+            if let Ok(outcome) = receiver.wait_for(Option::is_some).await {
+                return (outcome.clone().unwrap(), true);
+            }
+            // The query doing the lookup was dropped before finishing, so
+            // look it up here instead.
+            return (lookup.await, false);
+        }
+
+        let mut leader = Leader {
+            flight: self,
+            key: Some(key),
+        };
+        let outcome = lookup.await;
+        if let Some(sender) = leader.finish() {
+            sender.send_replace(Some(outcome.clone()));
+        }
+        (outcome, false)
+    }
+}
+
+/// Withdraws a lookup from the pending map when the query running it is
+/// dropped, which wakes anyone waiting on it to do their own.
+struct Leader<'a> {
+    flight: &'a SingleFlight,
+    key: Option<&'a CacheKey>,
+}
+
+impl Leader<'_> {
+    fn finish(&mut self) -> Option<Sender<Option<Outcome>>> {
+        let key = self.key.take()?;
+        self.flight.pending.lock().unwrap().remove(key)
+    }
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{EdnsFlags, TYPE_A};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::Notify;
+
+    fn key(flags: EdnsFlags) -> CacheKey {
+        ("example.com".to_owned(), TYPE_A, 1, flags)
+    }
+
+    #[tokio::test]
+    async fn lookups_differing_in_edns_flags_are_not_shared() {
+        let flight = SingleFlight::default();
+        let lookups = AtomicUsize::new(0);
+        let release = Notify::new();
+        let lookup = |answer: u8| {
+            let (lookups, release) = (&lookups, &release);
+            async move {
+                lookups.fetch_add(1, Ordering::SeqCst);
+                release.notified().await;
+                Ok((vec![answer], 0))
+            }
+        };
+        let plain = key(EdnsFlags::default());
+        let dnssec = key(EdnsFlags {
+            edns: true,
+            dnssec_ok: true,
+            checking_disabled: false,
+        });
+        let run = async {
+            tokio::join!(
+                flight.run(&plain, lookup(1)),
+                flight.run(&dnssec, lookup(2)),
+                flight.run(&dnssec, lookup(3)),
+            )
+        };
+        let release_all = async {
+            while lookups.load(Ordering::SeqCst) < 2 {
+                tokio::task::yield_now().await;
+            }
+            release.notify_waiters();
+        };
+        let ((plain, dnssec, shared), ()) = tokio::join!(run, release_all);
+
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+        assert_eq!(plain, (Ok((vec![1], 0)), false));
+        assert_eq!(dnssec, (Ok((vec![2], 0)), false));
+        assert_eq!(shared, (Ok((vec![2], 0)), true));
+    }
+}
@@ -0,0 +1,33 @@
+//! Certificates for the encrypted listeners.
+
+use std::sync::Arc;
+use tokio_rustls::rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig,
+};
+
+/// Loads a PEM certificate chain and private key, failing if either can't be
+/// read or the key doesn't belong to the certificate, so a bad pair is
+/// reported at startup rather than on the first connection.
+pub fn server_config(
+    cert_path: &str,
+    key_path: &str,
+) -> Result<ServerConfig, Box<dyn std::error::Error>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            format!("Failed to read TLS certificate {:?}: {}", cert_path, e)
+        })?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| {
+        format!("Failed to read TLS private key {:?}: {}", key_path, e)
+    })?;
+
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| format!("Invalid TLS certificate or key: {}", e))?;
+    Ok(config)
+}
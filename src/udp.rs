@@ -0,0 +1,586 @@
+//! Plain DNS upstream over a small pool of long-lived UDP sockets.
+//!
+//! Each query goes out on one of the sockets under a fresh random
+//! transaction ID, and a reader task per socket routes responses back to the
+//! waiting caller by that ID before the client's original ID is restored.
+//! Random IDs across several source ports keep replies hard to forge without
+//! binding a new socket for every query, and each socket is replaced by a
+//! freshly bound one after a while so the source ports keep changing. The
+//! sockets are connected to the upstream, so the system drops datagrams
+//! from anywhere else and reports an ICMP port unreachable as an error.
+
+use crate::{
+    dns::{is_reply_to, MAX_UDP_PAYLOAD},
+    metrics::Metrics,
+    upstream::{UPSTREAM_TIMED_OUT, UPSTREAM_UNREACHABLE},
+};
+use arc_swap::ArcSwap;
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{oneshot, Notify},
+    time::timeout,
+};
+use tracing::debug;
+
+/// Sockets per upstream, each with its own system-chosen source port.
+const POOL_SIZE: usize = 4;
+
+/// Queries a socket sends, and how long it is used for, before a freshly
+/// bound one takes its place.
+const MAX_SOCKET_QUERIES: usize = 10_000;
+const MAX_SOCKET_AGE: Duration = Duration::from_secs(300);
+
+/// First pause after a socket error, doubled for each one in a row so a
+/// socket that keeps failing doesn't spin.
+const MIN_ERROR_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+pub struct UdpClient {
+    addr: SocketAddr,
+    sockets: Vec<ArcSwap<PooledSocket>>,
+    /// The socket the next query goes out on.
+    next: AtomicUsize,
+    max_socket_queries: usize,
+    max_socket_age: Duration,
+}
+
+impl UdpClient {
+    /// Binds the pool's sockets and starts their readers, so this must run
+    /// inside the runtime.
+    pub fn new(addr: SocketAddr) -> std::io::Result<Self> {
+        let sockets = (0..POOL_SIZE)
+            .map(|_| PooledSocket::bind(addr).map(ArcSwap::new))
+            .collect::<std::io::Result<_>>()?;
+        Ok(Self {
+            addr,
+            sockets,
+            next: AtomicUsize::new(0),
+            max_socket_queries: MAX_SOCKET_QUERIES,
+            max_socket_age: MAX_SOCKET_AGE,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Sends the query up to `retries + 1` times, each send getting an equal
+    /// share of `upstream_timeout` so retries never make a query slower. The
+    /// resends reuse the transaction ID, so a late answer to an earlier one
+    /// is still accepted. Resends, and answers that took one, are counted in
+    /// `metrics`.
+    pub async fn query(
+        &self,
+        request: &[u8],
+        upstream_timeout: Duration,
+        retries: u32,
+        metrics: &Metrics,
+    ) -> Result<Vec<u8>, &'static str> {
+        if request.len() < 12 {
+            return Err("Invalid DNS request");
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        let slot = &self.sockets[next % self.sockets.len()];
+        let mut message = request.to_vec();
+        // A socket replaced since it was picked takes no more queries.
+        let (socket, id, mut rx) = loop {
+            let socket = self.socket(slot);
+            let (tx, rx) = oneshot::channel();
+            if let Some(id) = socket.add_pending(&mut message, tx) {
+                break (socket, id, rx);
+            }
+        };
+        let _guard = PendingGuard {
+            socket: &socket,
+            id,
+        };
+
+        let attempts = retries.saturating_add(1);
+        for attempt in 0..attempts {
+            if attempt > 0 {
+                metrics.upstream_retries.fetch_add(1, Ordering::Relaxed);
+            }
+            socket
+                .socket
+                .send(&message)
+                .await
+                .map_err(|_| "Failed to forward")?;
+            match timeout(upstream_timeout / attempts, &mut rx).await {
+                Ok(Ok(mut response)) => {
+                    if attempt > 0 {
+                        metrics
+                            .upstream_retry_successes
+                            .fetch_add(1, Ordering::Relaxed);
+                    }
+                    response[0..2].copy_from_slice(&request[0..2]);
+                    return Ok(response);
+                }
+                Ok(Err(_)) => return Err(UPSTREAM_UNREACHABLE),
+                Err(_) => {}
+            }
+        }
+        Err(UPSTREAM_TIMED_OUT)
+    }
+
+    /// The socket in `slot` to send a query on, first replacing it with a
+    /// freshly bound one if it has sent its share of queries or been in use
+    /// too long. If binding fails, the old socket carries on.
+    fn socket(&self, slot: &ArcSwap<PooledSocket>) -> Arc<PooledSocket> {
+        let socket = slot.load_full();
+        let sent = socket.sent.fetch_add(1, Ordering::Relaxed);
+        if sent < self.max_socket_queries
+            && socket.bound.elapsed() < self.max_socket_age
+        {
+            return socket;
+        }
+        let fresh = match PooledSocket::bind(self.addr) {
+            Ok(fresh) => fresh,
+            Err(e) => {
+                debug!(error = %e, "failed to replace upstream socket");
+                return socket;
+            }
+        };
+        let previous = slot.compare_and_swap(&socket, Arc::clone(&fresh));
+        if Arc::ptr_eq(&previous, &socket) {
+            socket.retire();
+            fresh
+        } else {
+            // Another query replaced it first.
+            fresh.retire();
+            Arc::clone(&previous)
+        }
+    }
+}
+
+impl Drop for UdpClient {
+    /// Lets the readers stop, which they would otherwise never do.
+    fn drop(&mut self) {
+        for slot in &self.sockets {
+            slot.load().retire();
+        }
+    }
+}
+
+struct PooledSocket {
+    socket: UdpSocket,
+    pending: Mutex<HashMap<u16, Pending>>,
+    /// Queries sent on the socket, counting toward replacing it.
+    sent: AtomicUsize,
+    bound: Instant,
+    /// Set, with `pending` locked, once the socket is replaced. It then
+    /// takes no new queries, and its reader stops once the ones waiting on
+    /// it are done.
+    retired: AtomicBool,
+    stop: Notify,
+}
+
+/// A query waiting for its reply, with the message as sent so the reply can
+/// be checked against it.
+struct Pending {
+    request: Vec<u8>,
+    reply: oneshot::Sender<Vec<u8>>,
+}
+
+impl PooledSocket {
+    /// Binds a socket connected to `addr` and starts its reader.
+    fn bind(addr: SocketAddr) -> std::io::Result<Arc<Self>> {
+        let local: SocketAddr = if addr.is_ipv6() {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = std::net::UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+        let socket = Arc::new(Self {
+            socket: UdpSocket::from_std(socket)?,
+            pending: Mutex::new(HashMap::new()),
+            sent: AtomicUsize::new(0),
+            bound: Instant::now(),
+            retired: AtomicBool::new(false),
+            stop: Notify::new(),
+        });
+        tokio::spawn(Arc::clone(&socket).read_responses());
+        Ok(socket)
+    }
+
+    /// Waits for a reply to `message` under a fresh transaction ID, which is
+    /// written into it and returned. Returns nothing if the socket has been
+    /// retired.
+    fn add_pending(
+        &self,
+        message: &mut [u8],
+        reply: oneshot::Sender<Vec<u8>>,
+    ) -> Option<u16> {
+        let mut pending = self.pending.lock().unwrap();
+        if self.retired.load(Ordering::Relaxed) {
+            return None;
+        }
+        let id = loop {
+            let id = rand::random::<u16>();
+            if !pending.contains_key(&id) {
+                break id;
+            }
+        };
+        message[0..2].copy_from_slice(&id.to_be_bytes());
+        let request = message.to_vec();
+        pending.insert(id, Pending { request, reply });
+        Some(id)
+    }
+
+    /// Takes the socket out of use, stopping its reader now if no queries
+    /// are waiting on it, or else once the last one is done.
+    fn retire(&self) {
+        let pending = self.pending.lock().unwrap();
+        self.retired.store(true, Ordering::Relaxed);
+        if pending.is_empty() {
+            self.stop.notify_one();
+        }
+    }
+
+    /// Hands each reply to the query it answers. Anything that isn't a
+    /// response to a pending query is a stray, duplicate or forged reply, so
+    /// it is skipped.
+    async fn read_responses(self: Arc<Self>) {
+        let mut buf = [0u8; MAX_UDP_PAYLOAD];
+        let mut backoff = None;
+        loop {
+            let received = tokio::select! {
+                received = self.socket.recv(&mut buf) => received,
+                () = self.stop.notified() => return,
+            };
+            let size = match received {
+                Ok(size) => size,
+                Err(e) => {
+                    // An error such as an ICMP port unreachable means the
+                    // upstream won't answer, so the queries waiting here fail
+                    // now and move on to the next upstream rather than time
+                    // out.
+                    debug!(error = %e, "upstream socket error");
+                    self.pending.lock().unwrap().clear();
+                    let pause = backoff.map_or(MIN_ERROR_BACKOFF, |pause| {
+                        MAX_ERROR_BACKOFF.min(pause * 2)
+                    });
+                    backoff = Some(pause);
+                    tokio::time::sleep(pause).await;
+                    continue;
+                }
+            };
+            backoff = None;
+            let response = &buf[..size];
+            if size < 2 {
+                debug!("ignoring unexpected upstream datagram");
+                continue;
+            }
+            let id = u16::from_be_bytes([response[0], response[1]]);
+            let waiter = {
+                let mut pending = self.pending.lock().unwrap();
+                match pending.get(&id) {
+                    Some(query) if is_reply_to(response, &query.request) => {
+                        pending.remove(&id)
+                    }
+                    _ => None,
+                }
+            };
+            let Some(waiter) = waiter else {
+                debug!("ignoring unexpected upstream datagram");
+                continue;
+            };
+            // The query may have timed out just now, which is fine.
+            let _ = waiter.reply.send(response.to_vec());
+        }
+    }
+}
+
+/// Forgets a pending query when its caller stops waiting, e.g. on timeout,
+/// so a late response is discarded instead of leaking the map entry.
+struct PendingGuard<'a> {
+    socket: &'a PooledSocket,
+    id: u16,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        let mut pending = self.socket.pending.lock().unwrap();
+        pending.remove(&self.id);
+        if pending.is_empty() && self.socket.retired.load(Ordering::Relaxed) {
+            self.socket.stop.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{
+        create_answer_response, encode_name, Record, CLASS_IN, TYPE_A,
+    };
+    use std::time::Instant;
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    fn query(name: &str) -> Vec<u8> {
+        let mut message = vec![0xAB, 0xCD, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(&encode_name(name));
+        message.extend_from_slice(&TYPE_A.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message
+    }
+
+    fn answer(request: &[u8], address: [u8; 4]) -> Vec<u8> {
+        let record = Record {
+            owner: None,
+            rtype: TYPE_A,
+            class: CLASS_IN,
+            ttl: 60,
+            data: address.to_vec(),
+        };
+        create_answer_response(request, 0, &[record], &[]).unwrap()
+    }
+
+    async fn mock_upstream() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    /// Receives the next query sent to `upstream`, with where it came from.
+    async fn receive(upstream: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+        let mut buf = [0; MAX_UDP_PAYLOAD];
+        let (size, from) = timeout(TIMEOUT, upstream.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        (buf[..size].to_vec(), from)
+    }
+
+    #[tokio::test]
+    async fn resends_within_the_timeout() {
+        let upstream = mock_upstream().await;
+        let client = UdpClient::new(upstream.local_addr().unwrap()).unwrap();
+        let metrics = Metrics::default();
+        let request = query("example.com");
+        let reply = async {
+            // The first query is lost on the way.
+            let (first, _) = receive(&upstream).await;
+            let (resent, from) = receive(&upstream).await;
+            assert_eq!(first, resent);
+            upstream
+                .send_to(&answer(&resent, [10, 0, 0, 1]), from)
+                .await
+        };
+        let start = Instant::now();
+        let (response, sent) = tokio::join!(
+            client.query(&request, Duration::from_millis(400), 1, &metrics),
+            reply
+        );
+        sent.unwrap();
+        assert_eq!(response.unwrap(), answer(&request, [10, 0, 0, 1]));
+        assert!(start.elapsed() < Duration::from_millis(400));
+        assert_eq!(metrics.upstream_retries.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.upstream_retry_successes.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn answers_under_the_client_id() {
+        let upstream = mock_upstream().await;
+        let client = UdpClient::new(upstream.local_addr().unwrap()).unwrap();
+        let metrics = Metrics::default();
+        let request = query("example.com");
+        let reply = async {
+            let (sent, from) = receive(&upstream).await;
+            // The query went out under a fresh ID, with the question intact.
+            assert_eq!(sent[2..], request[2..]);
+            upstream.send_to(&answer(&sent, [10, 0, 0, 1]), from).await
+        };
+        let (response, sent) =
+            tokio::join!(client.query(&request, TIMEOUT, 0, &metrics), reply);
+        sent.unwrap();
+        assert_eq!(response.unwrap(), answer(&request, [10, 0, 0, 1]));
+    }
+
+    #[tokio::test]
+    async fn ignores_replies_that_dont_match_the_query() {
+        let upstream = mock_upstream().await;
+        let spoofer = mock_upstream().await;
+        let client = UdpClient::new(upstream.local_addr().unwrap()).unwrap();
+        let metrics = Metrics::default();
+        let request = query("example.com");
+        let reply = async {
+            let (sent, from) = receive(&upstream).await;
+            let forged = answer(&sent, [6, 6, 6, 6]);
+            // The right ID and question, but from another address.
+            spoofer.send_to(&forged, from).await?;
+            let mut wrong_id = forged.clone();
+            wrong_id[0] ^= 0xFF;
+            upstream.send_to(&wrong_id, from).await?;
+            let mut other_question = sent.clone();
+            other_question[13] = b'x';
+            let other_question = answer(&other_question, [6, 6, 6, 6]);
+            upstream.send_to(&other_question, from).await?;
+            upstream.send_to(&sent, from).await?;
+            upstream.send_to(&[0], from).await?;
+            upstream.send_to(&answer(&sent, [10, 0, 0, 1]), from).await
+        };
+        let (response, sent) =
+            tokio::join!(client.query(&request, TIMEOUT, 0, &metrics), reply);
+        sent.unwrap();
+        assert_eq!(response.unwrap(), answer(&request, [10, 0, 0, 1]));
+    }
+
+    #[tokio::test]
+    async fn times_out_without_a_reply() {
+        let upstream = mock_upstream().await;
+        let client = UdpClient::new(upstream.local_addr().unwrap()).unwrap();
+        let response = client
+            .query(
+                &query("example.com"),
+                Duration::from_millis(50),
+                0,
+                &Metrics::default(),
+            )
+            .await;
+        assert_eq!(response, Err(UPSTREAM_TIMED_OUT));
+        // The pending query was forgotten.
+        let pending = client
+            .sockets
+            .iter()
+            .map(|slot| slot.load().pending.lock().unwrap().len());
+        assert_eq!(pending.sum::<usize>(), 0);
+    }
+
+    /// Whether `socket`'s reader has stopped, leaving `socket` the only
+    /// reference, within a second.
+    async fn reader_stops(socket: &Arc<PooledSocket>) -> bool {
+        for _ in 0..100 {
+            if Arc::strong_count(socket) == 1 {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn replaces_sockets_after_their_share_of_queries() {
+        let upstream = mock_upstream().await;
+        let mut client =
+            UdpClient::new(upstream.local_addr().unwrap()).unwrap();
+        client.sockets.truncate(1);
+        client.max_socket_queries = 2;
+        let first = client.sockets[0].load_full();
+        let metrics = Metrics::default();
+        let mut ports = Vec::new();
+        for _ in 0..3 {
+            let request = query("example.com");
+            let reply = async {
+                let (sent, from) = receive(&upstream).await;
+                ports.push(from.port());
+                upstream.send_to(&answer(&sent, [10, 0, 0, 1]), from).await
+            };
+            let (response, sent) = tokio::join!(
+                client.query(&request, TIMEOUT, 0, &metrics),
+                reply
+            );
+            sent.unwrap();
+            assert_eq!(response.unwrap(), answer(&request, [10, 0, 0, 1]));
+        }
+        // The third query went out on a new socket, and the old one's
+        // reader stopped.
+        assert_eq!(ports[0], ports[1]);
+        assert_ne!(ports[1], ports[2]);
+        assert!(!Arc::ptr_eq(&first, &client.sockets[0].load()));
+        assert!(reader_stops(&first).await);
+    }
+
+    #[tokio::test]
+    async fn replaced_sockets_still_take_late_replies() {
+        let upstream = mock_upstream().await;
+        let mut client =
+            UdpClient::new(upstream.local_addr().unwrap()).unwrap();
+        client.sockets.truncate(1);
+        let first = client.sockets[0].load_full();
+        let metrics = Metrics::default();
+        let request = query("example.com");
+        let reply = async {
+            let (sent, from) = receive(&upstream).await;
+            // The socket is replaced while the query waits on it.
+            let fresh = PooledSocket::bind(client.addr).unwrap();
+            client.sockets[0].store(fresh);
+            first.retire();
+            upstream.send_to(&answer(&sent, [10, 0, 0, 1]), from).await
+        };
+        let (response, sent) =
+            tokio::join!(client.query(&request, TIMEOUT, 0, &metrics), reply);
+        sent.unwrap();
+        assert_eq!(response.unwrap(), answer(&request, [10, 0, 0, 1]));
+        assert!(reader_stops(&first).await);
+    }
+
+    #[tokio::test]
+    async fn dropping_the_client_stops_its_readers() {
+        let upstream = mock_upstream().await;
+        let client = UdpClient::new(upstream.local_addr().unwrap()).unwrap();
+        let sockets: Vec<_> =
+            client.sockets.iter().map(|slot| slot.load_full()).collect();
+        drop(client);
+        for socket in &sockets {
+            assert!(reader_stops(socket).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_fast_when_nothing_listens() {
+        let closed = mock_upstream().await.local_addr().unwrap();
+        let client = UdpClient::new(closed).unwrap();
+        let start = Instant::now();
+        let response = client
+            .query(&query("example.com"), TIMEOUT, 0, &Metrics::default())
+            .await;
+        assert_eq!(response, Err(UPSTREAM_UNREACHABLE));
+        assert!(start.elapsed() < TIMEOUT / 2);
+    }
+
+    #[tokio::test]
+    async fn answers_many_queries_at_once_through_the_pool() {
+        let upstream = mock_upstream().await;
+        let mut client =
+            UdpClient::new(upstream.local_addr().unwrap()).unwrap();
+        // Small enough that sockets are replaced with queries in flight.
+        client.max_socket_queries = 20;
+        let client = Arc::new(client);
+        tokio::spawn(async move {
+            let mut buf = [0; MAX_UDP_PAYLOAD];
+            while let Ok((size, from)) = upstream.recv_from(&mut buf).await {
+                let reply = answer(&buf[..size], [10, 0, 0, 1]);
+                let _ = upstream.send_to(&reply, from).await;
+            }
+        });
+        // In waves, since a burst of thousands of datagrams would overflow
+        // the upstream's receive buffer.
+        for wave in 0..20 {
+            let mut queries = tokio::task::JoinSet::new();
+            for i in 0..100 {
+                let client = Arc::clone(&client);
+                queries.spawn(async move {
+                    let request = query(&format!("host{wave}-{i}.example"));
+                    let response = client
+                        .query(&request, TIMEOUT, 0, &Metrics::default())
+                        .await;
+                    (response, answer(&request, [10, 0, 0, 1]))
+                });
+            }
+            while let Some(result) = queries.join_next().await {
+                let (response, expected) = result.unwrap();
+                assert_eq!(response.unwrap(), expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,487 @@
+//! The upstream servers queries are forwarded to, and how to pick one.
+
+use crate::{
+    args::UpstreamStrategy,
+    dns::{is_reply_to, is_truncated, DNS_MESSAGE},
+    dnstap::{Dnstap, Transport},
+    doq::DoqClient,
+    dot::{self, DotClient},
+    metrics::{Histogram, Metrics},
+    server::{read_tcp_message, write_tcp_message},
+    udp::UdpClient,
+};
+use reqwest::{
+    header::{ACCEPT, CONTENT_TYPE},
+    StatusCode,
+};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{net::TcpStream, sync::Semaphore, time::timeout};
+use tracing::{info, warn};
+
+/// Error returned whenever an upstream exchange runs out of time.
+pub(crate) const UPSTREAM_TIMED_OUT: &str = "Upstream DNS server timeout";
+
+/// Error returned when the system reports an upstream can't be reached,
+/// such as by an ICMP port unreachable.
+pub(crate) const UPSTREAM_UNREACHABLE: &str = "Upstream DNS server unreachable";
+
+/// Error returned for a response that doesn't answer the query sent.
+const UPSTREAM_MISMATCH: &str = "Upstream response does not match the query";
+
+/// Error returned when no lookup slot frees up in time.
+const UPSTREAMS_BUSY: &str = "Too many upstream lookups in flight";
+
+/// Consecutive failures after which an upstream is skipped.
+const UPSTREAM_DOWN_AFTER: u32 = 3;
+
+/// How long a failing upstream is skipped before it is tried again.
+const UPSTREAM_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Weight of the newest exchange in an upstream's latency average.
+const LATENCY_WEIGHT: f64 = 0.2;
+
+/// With the fastest strategy, one query in this many goes to the servers in
+/// turn instead, so a slower server that speeds up is noticed.
+const FASTEST_PROBE_EVERY: usize = 20;
+
+/// With the race strategy, how long the quickest server gets to answer
+/// alone before the next one is asked too.
+const RACE_HEAD_START: Duration = Duration::from_millis(30);
+
+/// The upstream servers, tried one after another until one answers.
+pub(crate) struct Upstreams {
+    pub(crate) servers: Vec<Server>,
+    pub(crate) strategy: UpstreamStrategy,
+    /// Where the next round-robin query starts.
+    pub(crate) next: AtomicUsize,
+    /// How long a UDP or QUIC upstream gets to answer.
+    pub(crate) timeout: Duration,
+    /// Resends of an unanswered UDP query within `timeout`.
+    pub(crate) retries: u32,
+    /// How long a TCP, TLS or HTTPS exchange may take.
+    pub(crate) stream_timeout: Duration,
+    /// A permit per lookup allowed in flight at once.
+    pub(crate) slots: Semaphore,
+}
+
+impl Upstreams {
+    /// Returns the response along with the index of the server that gave it.
+    pub(crate) async fn forward(
+        &self,
+        request: &[u8],
+        metrics: &Metrics,
+        dnstap: Option<&Dnstap>,
+    ) -> Result<(Vec<u8>, usize), &'static str> {
+        // Waiting for a slot takes from the time the query gets, like a slow
+        // upstream would.
+        let Ok(Ok(_slot)) = timeout(self.timeout, self.slots.acquire()).await
+        else {
+            metrics.upstreams_busy.fetch_add(1, Ordering::Relaxed);
+            return Err(UPSTREAMS_BUSY);
+        };
+        let mut order: Vec<(usize, &Server)> =
+            self.servers.iter().enumerate().collect();
+        match self.strategy {
+            UpstreamStrategy::Failover => {}
+            UpstreamStrategy::RoundRobin => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                order.rotate_left(next % self.servers.len());
+            }
+            UpstreamStrategy::Fastest => {
+                // Servers without a measurement yet sort first.
+                order.sort_by_key(|(_, server)| server.latency());
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                if next.is_multiple_of(FASTEST_PROBE_EVERY) {
+                    let probe = next / FASTEST_PROBE_EVERY % order.len();
+                    let server = order.remove(probe);
+                    order.insert(0, server);
+                }
+            }
+            // Racing measures the runner-up whenever it wins, so it needs no
+            // probes.
+            UpstreamStrategy::Race => {
+                order.sort_by_key(|(_, server)| server.latency());
+            }
+        }
+        // Servers that are down go last, so they are only tried when every
+        // healthy one fails, and rejoin the order once their cooldown ends.
+        let now = Instant::now();
+        let (up, down): (Vec<_>, Vec<_>) = order
+            .into_iter()
+            .partition(|(_, server)| !server.is_down(now));
+        let mut order: Vec<_> = up.into_iter().chain(down).collect();
+        let mut error = "No upstream DNS servers";
+        if self.strategy == UpstreamStrategy::Race && order.len() > 1 {
+            let rest = order.split_off(2);
+            match self
+                .race(request, order[0], order[1], metrics, dnstap)
+                .await
+            {
+                Ok((response, index)) => {
+                    let wins = &self.servers[index].race_wins;
+                    wins.fetch_add(1, Ordering::Relaxed);
+                    return Ok((response, index));
+                }
+                Err(e) => error = e,
+            }
+            order = rest;
+        }
+        for (index, server) in order {
+            match self.exchange(request, server, metrics, dnstap).await {
+                Ok(response) => return Ok((response, index)),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    /// Gives `first` a head start of `RACE_HEAD_START`, then asks `second`
+    /// too and takes whichever answers first. The other exchange is dropped,
+    /// which cancels it, unless the first to finish failed.
+    async fn race<'a>(
+        &self,
+        request: &[u8],
+        first: (usize, &'a Server),
+        second: (usize, &'a Server),
+        metrics: &Metrics,
+        dnstap: Option<&Dnstap>,
+    ) -> Result<(Vec<u8>, usize), &'static str> {
+        let ask = |(index, server): (usize, &'a Server)| async move {
+            let response =
+                self.exchange(request, server, metrics, dnstap).await?;
+            Ok((response, index))
+        };
+        let start = Instant::now();
+        let leader = ask(first);
+        tokio::pin!(leader);
+        match timeout(RACE_HEAD_START, &mut leader).await {
+            Ok(Ok(answer)) => return Ok(answer),
+            Ok(Err(_)) => return ask(second).await,
+            Err(_) => {}
+        }
+        let challenger = ask(second);
+        tokio::pin!(challenger);
+        let (result, rest, overtaken) = tokio::select! {
+            result = &mut leader => (result, challenger, false),
+            result = &mut challenger => (result, leader, true),
+        };
+        match result {
+            Ok(answer) => {
+                // The leader's exchange is cancelled and never measured, so
+                // count it as taking as long as it was given. Otherwise a
+                // slow server would keep its place ahead of the winner.
+                if overtaken {
+                    first.1.health.lock().unwrap().observe(start.elapsed());
+                }
+                Ok(answer)
+            }
+            Err(_) => rest.await,
+        }
+    }
+
+    /// Sends the query to one server, keeping its health and the counters up
+    /// to date.
+    async fn exchange(
+        &self,
+        request: &[u8],
+        server: &Server,
+        metrics: &Metrics,
+        dnstap: Option<&Dnstap>,
+    ) -> Result<Vec<u8>, &'static str> {
+        let (transport, addr) = server.upstream.peer();
+        let sent = SystemTime::now();
+        if let Some(dnstap) = dnstap {
+            dnstap.resolver_query(addr, transport, sent, request);
+        }
+        let start = Instant::now();
+        // Every transport hands back the client's ID, so one check covers
+        // them all: an answer to some other question, or not an answer at
+        // all, counts as a failure and can't win a race or be cached.
+        let result =
+            forward_to_upstream(request, &server.upstream, self, metrics)
+                .await
+                .and_then(|response| {
+                    if is_reply_to(&response, request) {
+                        Ok(response)
+                    } else {
+                        Err(UPSTREAM_MISMATCH)
+                    }
+                });
+        server.record(&result, start.elapsed());
+        match &result {
+            Ok(response) => {
+                if let Some(dnstap) = dnstap {
+                    dnstap.resolver_response(addr, transport, sent, response);
+                }
+            }
+            Err(e) => {
+                if *e == UPSTREAM_TIMED_OUT {
+                    metrics.upstream_timeouts.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// An upstream along with how it has been doing lately.
+pub(crate) struct Server {
+    /// The server as given on the command line, for logs.
+    pub(crate) name: String,
+    upstream: Upstream,
+    health: Mutex<Health>,
+    /// How long exchanges take, failed ones included.
+    pub(crate) durations: Histogram,
+    /// Exchanges that failed, timeouts included.
+    pub(crate) failures: AtomicU64,
+    /// Queries it answered first with the race strategy.
+    pub(crate) race_wins: AtomicU64,
+}
+
+#[derive(Default)]
+struct Health {
+    /// Exchanges that have failed since the last success.
+    failures: u32,
+    /// While set, the server counts as down until this time.
+    down_until: Option<Instant>,
+    /// Moving average of the time exchanges take, failed ones included.
+    latency: Option<Duration>,
+}
+
+impl Health {
+    fn observe(&mut self, latency: Duration) {
+        self.latency = Some(match self.latency {
+            Some(average) => {
+                average.mul_f64(1.0 - LATENCY_WEIGHT)
+                    + latency.mul_f64(LATENCY_WEIGHT)
+            }
+            None => latency,
+        });
+    }
+}
+
+impl Server {
+    pub(crate) fn new(
+        s: &str,
+        tls_insecure: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            name: s.to_owned(),
+            upstream: Upstream::new(s, tls_insecure)?,
+            health: Mutex::default(),
+            durations: Histogram::default(),
+            failures: AtomicU64::new(0),
+            race_wins: AtomicU64::new(0),
+        })
+    }
+
+    pub(crate) fn is_down(&self, now: Instant) -> bool {
+        let health = self.health.lock().unwrap();
+        health.down_until.is_some_and(|until| until > now)
+    }
+
+    pub(crate) fn latency(&self) -> Option<Duration> {
+        self.health.lock().unwrap().latency
+    }
+
+    /// Marks the server down after `UPSTREAM_DOWN_AFTER` failures in a row,
+    /// and up again as soon as an exchange succeeds.
+    fn record<T>(&self, result: &Result<T, &'static str>, latency: Duration) {
+        self.durations.observe(latency);
+        if result.is_err() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut health = self.health.lock().unwrap();
+        health.observe(latency);
+        match result {
+            Ok(_) => {
+                if health.down_until.take().is_some() {
+                    info!(upstream = %self.name, "upstream is back up");
+                }
+                health.failures = 0;
+            }
+            Err(e) => {
+                health.failures += 1;
+                if health.failures < UPSTREAM_DOWN_AFTER {
+                    return;
+                }
+                if health.down_until.is_none() {
+                    warn!(
+                        upstream = %self.name,
+                        error = %e,
+                        cooldown = ?UPSTREAM_COOLDOWN,
+                        "upstream is down"
+                    );
+                }
+                // A failed retry after the cooldown keeps it down longer.
+                health.down_until = Some(Instant::now() + UPSTREAM_COOLDOWN);
+            }
+        }
+    }
+}
+
+/// Where queries that pass the filter are sent.
+pub(crate) enum Upstream {
+    /// Plain DNS over UDP, retried over TCP when the answer is truncated.
+    Udp(UdpClient),
+    /// DNS-over-HTTPS (RFC 8484), or over plain HTTP for an http:// URL.
+    /// The client keeps connections alive, so queries share TLS sessions
+    /// instead of handshaking each time.
+    Https {
+        url: String,
+        client: reqwest::Client,
+    },
+    /// DNS-over-TLS (RFC 7858) over one persistent, pipelined connection.
+    Tls(DotClient),
+    /// DNS-over-QUIC (RFC 9250), one stream per query on a kept-alive
+    /// connection.
+    Quic(DoqClient),
+}
+
+impl Upstream {
+    fn new(
+        s: &str,
+        tls_insecure: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if let Some(spec) = s.strip_prefix("tls://") {
+            Ok(Upstream::Tls(DotClient::new(spec, tls_insecure)?))
+        } else if let Some(spec) = s.strip_prefix("quic://") {
+            Ok(Upstream::Quic(DoqClient::new(spec, tls_insecure)?))
+        } else if s.starts_with("https://") || s.starts_with("http://") {
+            // The same TLS settings as DoT and DoQ, --tls-insecure included.
+            let mut tls = dot::client_config(tls_insecure)?;
+            tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+            let client = reqwest::Client::builder()
+                .use_preconfigured_tls(tls)
+                .build()?;
+            Ok(Upstream::Https {
+                url: s.to_owned(),
+                client,
+            })
+        } else {
+            Ok(Upstream::Udp(UdpClient::new(s.parse()?)?))
+        }
+    }
+
+    /// How the server is reached and at which address, for dnstap.
+    fn peer(&self) -> (Transport, Option<SocketAddr>) {
+        match self {
+            Upstream::Udp(client) => (Transport::Udp, Some(client.addr())),
+            Upstream::Https { .. } => (Transport::Https, None),
+            Upstream::Tls(client) => (Transport::Tls, Some(client.addr())),
+            Upstream::Quic(client) => (Transport::Quic, Some(client.addr())),
+        }
+    }
+}
+
+async fn forward_to_upstream(
+    request: &[u8],
+    upstream: &Upstream,
+    upstreams: &Upstreams,
+    metrics: &Metrics,
+) -> Result<Vec<u8>, &'static str> {
+    match upstream {
+        Upstream::Udp(client) => {
+            forward_over_udp(request, client, upstreams, metrics).await
+        }
+        Upstream::Https { url, client } => {
+            forward_over_https(request, url, client, upstreams.stream_timeout)
+                .await
+        }
+        Upstream::Tls(client) => {
+            timeout(upstreams.stream_timeout, client.query(request))
+                .await
+                .map_err(|_| UPSTREAM_TIMED_OUT)?
+        }
+        Upstream::Quic(client) => {
+            client.query(request, upstreams.timeout).await
+        }
+    }
+}
+
+/// Forwards over UDP, retrying over TCP when the answer is truncated.
+async fn forward_over_udp(
+    request: &[u8],
+    client: &UdpClient,
+    upstreams: &Upstreams,
+    metrics: &Metrics,
+) -> Result<Vec<u8>, &'static str> {
+    let response = client
+        .query(request, upstreams.timeout, upstreams.retries, metrics)
+        .await?;
+    if is_truncated(&response) {
+        // Fall back to the truncated answer if the TCP retry fails, so the
+        // client at least learns it should retry on its own.
+        let addr = client.addr();
+        let tcp = forward_over_tcp(request, &addr, upstreams.stream_timeout);
+        if let Ok(full) = tcp.await {
+            return Ok(full);
+        }
+    }
+    Ok(response)
+}
+
+async fn forward_over_tcp(
+    request: &[u8],
+    upstream_dns: &SocketAddr,
+    stream_timeout: Duration,
+) -> Result<Vec<u8>, &'static str> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(upstream_dns).await?;
+        write_tcp_message(&mut stream, request).await?;
+        read_tcp_message(&mut stream).await
+    };
+    timeout(stream_timeout, exchange)
+        .await
+        .map_err(|_| UPSTREAM_TIMED_OUT)?
+        .map_err(|_| "Failed to receive TCP response")
+}
+
+async fn forward_over_https(
+    request: &[u8],
+    url: &str,
+    client: &reqwest::Client,
+    stream_timeout: Duration,
+) -> Result<Vec<u8>, &'static str> {
+    let response = client
+        .post(url)
+        .timeout(stream_timeout)
+        .header(CONTENT_TYPE, DNS_MESSAGE)
+        .header(ACCEPT, DNS_MESSAGE)
+        .body(request.to_vec())
+        .send()
+        .await
+        .map_err(|e| {
+            if e.is_timeout() {
+                UPSTREAM_TIMED_OUT
+            } else {
+                "Failed to forward over HTTPS"
+            }
+        })?;
+
+    if response.status() != StatusCode::OK {
+        return Err("DoH server returned an error status");
+    }
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(str::trim);
+    if content_type != Some(DNS_MESSAGE) {
+        return Err("DoH server returned an unexpected content type");
+    }
+
+    let body = response
+        .bytes()
+        .await
+        .map_err(|_| "Failed to receive HTTPS response")?;
+    Ok(body.to_vec())
+}
@@ -0,0 +1,88 @@
+//! Reloads the blocklists when their files change on disk.
+//!
+//! The directories holding the lists are watched rather than the files
+//! themselves, so a list that is replaced by renaming a new file over it
+//! keeps being followed without re-adding the watch.
+
+use crate::{
+    filter::ListConfig,
+    remote,
+    server::{reload_lists, Service},
+};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::mpsc, time::timeout};
+use tracing::warn;
+
+/// How long the files must stay untouched before they are reread, so a
+/// writer that takes a while isn't caught halfway through.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Starts watching the list files, failing if a watch can't be set up.
+pub fn watch_lists(
+    config: Arc<ListConfig>,
+    service: Arc<Service>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let sources = config.sources();
+    let lists = sources
+        .iter()
+        .filter(|source| !remote::is_url(source))
+        .map(Path::new);
+    let files = lists
+        .chain(config.regex_denylist.as_deref())
+        .chain(config.local_records.as_deref())
+        .map(absolute)
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let watched = files.clone();
+    let mut watcher = RecommendedWatcher::new(
+        move |event: notify::Result<notify::Event>| match event {
+            Ok(event)
+                if !matches!(event.kind, EventKind::Access(_))
+                    && event.paths.iter().any(|p| watched.contains(p)) =>
+            {
+                let _ = tx.send(());
+            }
+            Ok(_) => {}
+            Err(e) => warn!(error = %e, "error watching blocklists"),
+        },
+        notify::Config::default(),
+    )?;
+    for file in &files {
+        let dir = file.parent().unwrap_or(Path::new("/"));
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .map_err(|e| {
+                format!("Failed to watch {:?}: {}", dir.display(), e)
+            })?;
+    }
+
+    tokio::spawn(async move {
+        // Dropping the watcher would end the watch, so the task owns it.
+        let _watcher = watcher;
+        while rx.recv().await.is_some() {
+            while let Ok(Some(())) = timeout(DEBOUNCE, rx.recv()).await {}
+            reload_lists(&config, &service).await;
+        }
+    });
+    Ok(())
+}
+
+/// Resolves `path` against the current directory and any symlinked parent
+/// directories, which is how the watcher reports the paths of its events.
+fn absolute(path: &Path) -> io::Result<PathBuf> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Not a file path")
+    })?;
+    Ok(dir.canonicalize()?.join(name))
+}